@@ -0,0 +1,13 @@
+/* Foreign functions for 15_extern_clamp.snek. Arguments and results are
+ * tagged Snek values; tagged integers compare like the integers they encode. */
+#include <stdint.h>
+
+int64_t clamp(int64_t v, int64_t lo, int64_t hi) {
+    if (v < lo) {
+        return lo;
+    }
+    if (v > hi) {
+        return hi;
+    }
+    return v;
+}
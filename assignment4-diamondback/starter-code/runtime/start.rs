@@ -1,43 +1,481 @@
 // runtime/start.rs
 // This file provides the entry point for compiled programs
 
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
 #[link(name = "our_code")]
 extern "C" {
-    // The \x01 here is an undocumented feature of LLVM that ensures
-    // it does not add an underscore in front of the name on macOS
-    #[link_name = "\x01our_code_starts_here"]
+    // Plain C names: rustc adds the platform's prefix (`_` on macOS), and the
+    // compiler's `--target` spells the symbols it emits the same way.
     fn our_code_starts_here() -> i64;
+
+    // Backtrace tables emitted by the compiler (see README.md).
+    #[link_name = "snek_function_table"]
+    static FUNCTION_TABLE: FunctionEntry;
+    #[link_name = "snek_function_count"]
+    static FUNCTION_COUNT: u64;
+    #[link_name = "snek_call_site_table"]
+    static CALL_SITE_TABLE: CallSite;
+    #[link_name = "snek_call_site_count"]
+    static CALL_SITE_COUNT: u64;
+    #[link_name = "snek_error_site_table"]
+    static ERROR_SITE_TABLE: ErrorSite;
+    #[link_name = "snek_error_site_count"]
+    static ERROR_SITE_COUNT: u64;
+    #[link_name = "snek_source_name"]
+    static SOURCE_NAME: u8;
+
+    // Per-function counters from `--profile`, parallel to the function table.
+    #[link_name = "snek_profile_enabled"]
+    static PROFILE_ENABLED: u64;
+    #[link_name = "snek_profile_counters"]
+    static PROFILE_COUNTERS: ProfileCounter;
 }
 
-#[no_mangle]
-pub extern "C" fn snek_error(errcode: i64) {
-    if errcode == 1 {
-        eprintln!("invalid argument");
-    } else if errcode == 2 {
-        eprintln!("overflow");
+#[repr(C)]
+struct FunctionEntry {
+    start: u64,
+    end: u64,
+    name: *const u8,
+}
+
+#[repr(C)]
+struct CallSite {
+    ret: u64,
+    line: u64,
+    col: u64,
+}
+
+#[repr(C)]
+struct ErrorSite {
+    here: u64,
+    op: *const u8,
+    line: u64,
+    col: u64,
+    expected: *const u8,
+}
+
+#[repr(C)]
+struct ProfileCounter {
+    calls: u64,
+    cycles: u64,
+    active: u64,
+}
+
+fn c_str(p: *const u8) -> String {
+    let mut bytes = Vec::new();
+    let mut p = p;
+    unsafe {
+        while *p != 0 {
+            bytes.push(*p);
+            p = p.add(1);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn function_at(pc: u64) -> Option<&'static FunctionEntry> {
+    let funs = unsafe { std::slice::from_raw_parts(&FUNCTION_TABLE, FUNCTION_COUNT as usize) };
+    funs.iter().find(|f| f.start <= pc && pc < f.end)
+}
+
+fn call_site_at(ret: u64) -> Option<&'static CallSite> {
+    let sites = unsafe { std::slice::from_raw_parts(&CALL_SITE_TABLE, CALL_SITE_COUNT as usize) };
+    sites.iter().find(|c| c.ret == ret)
+}
+
+fn error_site(site: i64) -> Option<&'static ErrorSite> {
+    let sites = unsafe { std::slice::from_raw_parts(&ERROR_SITE_TABLE, ERROR_SITE_COUNT as usize) };
+    if site < 0 {
+        None
     } else {
-        eprintln!("an error occurred ({errcode})");
+        sites.get(site as usize)
+    }
+}
+
+/// (name, calls, inclusive cycles) for every function that was called, most
+/// expensive first. Empty unless the program was compiled with `--profile`.
+fn profile_rows() -> Vec<(String, u64, u64)> {
+    if unsafe { PROFILE_ENABLED } == 0 {
+        return Vec::new();
+    }
+    let n = unsafe { FUNCTION_COUNT } as usize;
+    let funs = unsafe { std::slice::from_raw_parts(&FUNCTION_TABLE, n) };
+    let counters = unsafe { std::slice::from_raw_parts(&PROFILE_COUNTERS, n) };
+    let mut rows: Vec<(String, u64, u64)> = funs
+        .iter()
+        .zip(counters)
+        .filter(|(_, c)| c.calls > 0)
+        .map(|(f, c)| (c_str(f.name), c.calls, c.cycles))
+        .collect();
+    rows.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)).then(a.0.cmp(&b.0)));
+    rows
+}
+
+/// Writes the profile table to stderr.
+fn print_profile() {
+    let rows = profile_rows();
+    if rows.is_empty() {
+        return;
+    }
+    let width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0).max(8);
+    eprintln!("{:<width$}  {:>12}  {:>16}", "function", "calls", "cycles", width = width);
+    for (name, calls, cycles) in &rows {
+        eprintln!("{:<width$}  {:>12}  {:>16}", name, calls, cycles, width = width);
+    }
+}
+
+fn json_profile() -> String {
+    let rows: Vec<String> = profile_rows()
+        .iter()
+        .map(|(name, calls, cycles)| {
+            format!(
+                "{{\"function\": {}, \"calls\": {}, \"cycles\": {}}}",
+                json_string(name),
+                calls,
+                cycles
+            )
+        })
+        .collect();
+    format!("[{}]", rows.join(", "))
+}
+
+// Longest backtrace printed before the rest is summarised.
+const MAX_BACKTRACE_LINES: usize = 32;
+
+/// Walks the `rbp` chain starting at frame pointer `fp`, whose function
+/// contains `pc` and is executing source position `at`, when known. Stops at
+/// the first return address outside compiled code. Returns one line per run
+/// of identical frames, most recent first.
+fn backtrace(fp: u64, pc: u64, at: Option<(u64, u64)>) -> Vec<String> {
+    let source = c_str(unsafe { &SOURCE_NAME });
+    let mut frames: Vec<String> = Vec::new();
+    let (mut fp, mut pc) = (fp, pc);
+    let mut at = at.or_else(|| call_site_at(pc).map(|c| (c.line, c.col)));
+    let known = |at: Option<(u64, u64)>| at.filter(|&(line, _)| line != 0);
+    while fp != 0 {
+        let fun = match function_at(pc) {
+            Some(f) => f,
+            None => break,
+        };
+        let name = c_str(fun.name);
+        frames.push(match known(at) {
+            Some((line, col)) => format!("{} ({}:{}:{})", name, source, line, col),
+            None => name,
+        });
+        let ret = unsafe { *((fp + 8) as *const u64) };
+        fp = unsafe { *(fp as *const u64) };
+        pc = ret;
+        at = call_site_at(ret).map(|c| (c.line, c.col));
+    }
+    let mut lines = Vec::new();
+    let mut i = 0;
+    while i < frames.len() {
+        if lines.len() == MAX_BACKTRACE_LINES {
+            lines.push(format!("... {} more frames", frames.len() - i));
+            break;
+        }
+        let mut j = i + 1;
+        while j < frames.len() && frames[j] == frames[i] {
+            j += 1;
+        }
+        if j - i > 1 {
+            lines.push(format!("at {} (repeated {} times)", frames[i], j - i));
+        } else {
+            lines.push(format!("at {}", frames[i]));
+        }
+        i = j;
+    }
+    lines
+}
+
+fn error_kind(errcode: i64) -> String {
+    match errcode {
+        1 => "invalid argument".to_string(),
+        2 => "overflow".to_string(),
+        3 => "division by zero".to_string(),
+        4 => "argument index out of range".to_string(),
+        5 => "stack overflow".to_string(),
+        6 => "end of input".to_string(),
+        7 => "invalid input".to_string(),
+        _ => format!("an error occurred ({errcode})"),
+    }
+}
+
+fn has_kind(v: i64, expected: &str) -> bool {
+    match expected {
+        "num" => v & 1 == 0 || is_float(v),
+        "int" => v & 1 == 0,
+        _ => true,
+    }
+}
+
+fn is_binary(op: &str) -> bool {
+    matches!(op, "+" | "-" | "*" | "/" | "<" | ">" | "<=" | ">=" | "=")
+}
+
+/// "expected num, got true" for the first operand of the wrong kind, or both
+/// operands when neither is singly at fault (as with `=`).
+fn describe_operands(expected: &str, operands: &[i64]) -> String {
+    match operands.iter().find(|v| !has_kind(**v, expected)) {
+        Some(v) => format!("expected {}, got {}", expected, render_tagged(*v)),
+        None => {
+            let got: Vec<String> = operands.iter().map(|v| render_tagged(*v)).collect();
+            format!("expected {}, got {}", expected, got.join(" and "))
+        }
     }
+}
+
+/// Reports a runtime error and exits. `site` indexes the error site table, or
+/// is -1 when the error has no source location. `fp` is the frame pointer of
+/// the failing Snek function, or 0 when unknown. For a stack overflow it is
+/// the frame that failed the check, so the backtrace starts at its caller.
+/// `a` and `b` are the operands: for invalid arguments, unary operators pass
+/// one and binary operators two.
+#[no_mangle]
+pub extern "C" fn snek_error(errcode: i64, site: i64, fp: u64, a: i64, b: i64) {
+    let detail = match error_site(site) {
+        Some(s) if errcode == 1 => {
+            let op = c_str(s.op);
+            let operands: &[i64] = if is_binary(&op) { &[a, b] } else { &[a] };
+            Some(describe_operands(&c_str(s.expected), operands))
+        }
+        _ => None,
+    };
+    fail(errcode, site, fp, detail)
+}
+
+/// Reports "<kind> to <op> at <file:line:col>: <detail>" (or "<kind> in ..."
+/// without a detail) and a backtrace, then exits.
+fn fail(errcode: i64, site: i64, fp: u64, detail: Option<String>) -> ! {
+    let source = c_str(unsafe { &SOURCE_NAME });
+    let kind = error_kind(errcode);
+    let (mut fp, mut pc, mut at) = (fp, 0, None);
+    let message = match error_site(site) {
+        Some(s) => {
+            // Line 0 marks code without a source position, such as the prelude.
+            let place = if s.line == 0 {
+                c_str(s.op)
+            } else {
+                format!("{} at {}:{}:{}", c_str(s.op), source, s.line, s.col)
+            };
+            pc = s.here;
+            at = Some((s.line, s.col));
+            match detail {
+                Some(d) => format!("{} to {}: {}", kind, place, d),
+                None => format!("{} in {}", kind, place),
+            }
+        }
+        None => {
+            if errcode == 5 && fp != 0 {
+                pc = unsafe { *((fp + 8) as *const u64) };
+                fp = unsafe { *(fp as *const u64) };
+            } else {
+                fp = 0;
+            }
+            kind.clone()
+        }
+    };
+    let trace = backtrace(fp, pc, at);
+    if json_mode() {
+        let error = format!(
+            "{{\"code\": {}, \"kind\": {}, \"message\": {}, \"backtrace\": [{}]}}",
+            errcode,
+            json_string(&kind),
+            json_string(&message),
+            trace.iter().map(|l| json_string(l)).collect::<Vec<_>>().join(", ")
+        );
+        emit_json_record("null", &error);
+        std::process::exit(EXIT_ERROR_BASE + errcode as i32);
+    }
+    eprintln!("{}", message);
+    if !trace.is_empty() {
+        eprintln!("backtrace (most recent call first):");
+        for line in &trace {
+            eprintln!("  {}", line);
+        }
+    }
+    print_profile();
     std::process::exit(1);
 }
 
+// JSON output mode (SNEK_OUTPUT=json or a leading --json argument): stdout
+// gets a single record instead of text, and the exit status names the error.
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
+static PRINTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Exit statuses in JSON mode: 0 on success, EXIT_BAD_ARGS for an unparsable
+// command-line argument, and EXIT_ERROR_BASE + code for `snek_error(code)`.
+const EXIT_BAD_ARGS: i32 = 2;
+const EXIT_ERROR_BASE: i32 = 10;
+
+fn json_mode() -> bool {
+    JSON_MODE.load(Ordering::Relaxed)
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `{"type": ..., "value": ...}` for a tagged value. Non-finite floats, which
+/// JSON numbers cannot express, are given as strings.
+fn json_value(v: i64) -> String {
+    let (ty, value) = if v & 1 == 0 {
+        ("int", format!("{}", v >> 1))
+    } else if v == 3 || v == 1 {
+        ("bool", render_tagged(v))
+    } else if is_float(v) && unbox_float(v).is_finite() {
+        ("float", render_tagged(v))
+    } else if is_float(v) {
+        ("float", json_string(&render_tagged(v)))
+    } else {
+        ("unknown", format!("{v}"))
+    };
+    format!("{{\"type\": \"{}\", \"value\": {}}}", ty, value)
+}
+
+/// Writes `{"value": ..., "output": [...], "error": ...}` to stdout, plus a
+/// `"profile"` list when the program was compiled with `--profile`.
+fn emit_json_record(value: &str, error: &str) {
+    let printed = PRINTED.lock().map(|p| p.join(", ")).unwrap_or_default();
+    let profile = if unsafe { PROFILE_ENABLED } != 0 {
+        format!(", \"profile\": {}", json_profile())
+    } else {
+        String::new()
+    };
+    println!(
+        "{{\"value\": {}, \"output\": [{}], \"error\": {}{}}}",
+        value, printed, error, profile
+    );
+}
+
 #[no_mangle]
 pub extern "C" fn snek_print(val: i64) -> i64 {
-    if val & 1 == 0 {
-        println!("{}", val >> 1);
-    } else if val == 3 {
-        println!("true");
-    } else if val == 1 {
-        println!("false");
+    if json_mode() {
+        PRINTED.lock().unwrap().push(json_value(val));
     } else {
-        println!("{val}");
+        println!("{}", render_tagged(val));
     }
     val
 }
 
+// Floats are boxed: a tagged float is the address of an f64 with 0b101 in the low bits.
+const FLOAT_TAG: i64 = 5;
+
+fn is_float(v: i64) -> bool {
+    v & 7 == FLOAT_TAG
+}
+
+fn unbox_float(v: i64) -> f64 {
+    unsafe { *((v - FLOAT_TAG) as *const f64) }
+}
+
+fn box_float(f: f64) -> i64 {
+    Box::into_raw(Box::new(f)) as i64 | FLOAT_TAG
+}
+
+fn tag_bool(b: bool) -> i64 {
+    if b {
+        3
+    } else {
+        1
+    }
+}
+
+/// Numeric view of a tagged value, promoting integers when the other operand is a float.
+fn as_f64(v: i64) -> f64 {
+    if is_float(v) {
+        unbox_float(v)
+    } else if v & 1 == 0 {
+        (v >> 1) as f64
+    } else {
+        snek_error(1, -1, 0, v, 0);
+        unreachable!()
+    }
+}
+
+// Must match the ARITH_* codes in src/main.rs.
+const ARITH_PLUS: i64 = 0;
+const ARITH_MINUS: i64 = 1;
+const ARITH_TIMES: i64 = 2;
+const ARITH_DIVIDE: i64 = 3;
+const ARITH_LESS: i64 = 4;
+const ARITH_GREATER: i64 = 5;
+const ARITH_LESS_EQ: i64 = 6;
+const ARITH_GREATER_EQ: i64 = 7;
+const ARITH_EQUAL: i64 = 8;
+
+/// Mixed int/float arithmetic and comparisons. Compiled code only calls this
+/// after ruling out booleans and the all-integer fast path.
+#[no_mangle]
+pub extern "C" fn snek_arith(op: i64, a: i64, b: i64) -> i64 {
+    let (x, y) = (as_f64(a), as_f64(b));
+    match op {
+        ARITH_PLUS => box_float(x + y),
+        ARITH_MINUS => box_float(x - y),
+        ARITH_TIMES => box_float(x * y),
+        ARITH_DIVIDE => box_float(x / y),
+        ARITH_LESS => tag_bool(x < y),
+        ARITH_GREATER => tag_bool(x > y),
+        ARITH_LESS_EQ => tag_bool(x <= y),
+        ARITH_GREATER_EQ => tag_bool(x >= y),
+        ARITH_EQUAL => tag_bool(x == y),
+        _ => {
+            snek_error(op, -1, 0, a, b);
+            unreachable!()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn snek_to_float(v: i64) -> i64 {
+    if is_float(v) {
+        v
+    } else {
+        box_float(as_f64(v))
+    }
+}
+
+/// Rounds toward zero; values outside the tagged integer range overflow.
+#[no_mangle]
+pub extern "C" fn snek_truncate(v: i64, site: i64, fp: u64) -> i64 {
+    if !is_float(v) {
+        return v;
+    }
+    let t = unbox_float(v).trunc();
+    if t.is_nan() || t < (i64::MIN >> 1) as f64 || t >= -((i64::MIN >> 1) as f64) {
+        snek_error(2, site, fp, v, 0);
+    }
+    (t as i64) << 1
+}
+
 #[no_mangle]
 pub static mut INPUT_VAL: i64 = 0;
 
+/// Lowest `rsp` a Snek function may run at; checked in every `fun_` prologue.
+/// Zero disables the check until `main` computes the real limit.
+#[no_mangle]
+pub static mut STACK_LIMIT: u64 = 0;
+
+// Snek code runs on its own thread so the stack size is known up front.
+// Override with SNEK_STACK_SIZE (bytes).
+const DEFAULT_STACK_BYTES: usize = 8 << 20;
+// Room left below the limit for snek_error and the other runtime calls.
+const STACK_HEADROOM: usize = 256 << 10;
+
 fn render_tagged(v: i64) -> String {
     if v & 1 == 0 {
         format!("{}", v >> 1)
@@ -45,19 +483,184 @@ fn render_tagged(v: i64) -> String {
         "true".to_string()
     } else if v == 1 {
         "false".to_string()
+    } else if is_float(v) {
+        format!("{:?}", unbox_float(v))
     } else {
         format!("{v}")
     }
 }
 
+/// Parses one command-line argument into a tagged value: `true`, `false`,
+/// an integer in the tagged range, or a float.
+fn parse_input(s: &str) -> Result<i64, String> {
+    match s {
+        "true" => return Ok(3),
+        "false" => return Ok(1),
+        _ => {}
+    }
+    if let Ok(n) = s.parse::<i64>() {
+        if n < (i64::MIN >> 1) || n > (i64::MAX >> 1) {
+            return Err(format!("input out of range: {s}"));
+        }
+        return Ok(n << 1);
+    }
+    match s.parse::<f64>() {
+        Ok(f) if f.is_finite() => Ok(box_float(f)),
+        _ => Err(format!("invalid input: {s}")),
+    }
+}
+
+static ARGS: OnceLock<Vec<i64>> = OnceLock::new();
+
+#[no_mangle]
+pub extern "C" fn snek_argc() -> i64 {
+    (ARGS.get().map_or(0, |a| a.len()) as i64) << 1
+}
+
+/// `(arg i)`: the i-th command-line argument, counting from 0. The compiled
+/// code has already checked that `i` is a number.
+#[no_mangle]
+pub extern "C" fn snek_arg(i: i64, site: i64, fp: u64) -> i64 {
+    let args = ARGS.get().map_or(&[][..], |a| &a[..]);
+    match args.get((i >> 1) as usize) {
+        Some(v) if i >= 0 => *v,
+        _ => {
+            snek_error(4, site, fp, i, 0);
+            unreachable!()
+        }
+    }
+}
+
+// Unread rest of the current stdin line, shared by the read builtins.
+static STDIN_PENDING: Mutex<String> = Mutex::new(String::new());
+
+/// Next line of stdin without its line terminator, or `None` at end of input.
+fn next_stdin_line() -> Option<String> {
+    let mut line = String::new();
+    match std::io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+    }
+}
+
+/// Next whitespace-separated token on stdin; raises end of input at `site`.
+fn read_token(site: i64, fp: u64) -> String {
+    let mut pending = STDIN_PENDING.lock().unwrap();
+    loop {
+        let rest = pending.trim_start();
+        if !rest.is_empty() {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (token, remaining) = (rest[..end].to_string(), rest[end..].to_string());
+            *pending = remaining;
+            return token;
+        }
+        match next_stdin_line() {
+            Some(line) => *pending = line,
+            None => {
+                drop(pending);
+                fail(6, site, fp, None)
+            }
+        }
+    }
+}
+
+fn bad_input(site: i64, fp: u64, expected: &str, text: &str) -> ! {
+    fail(7, site, fp, Some(format!("expected {}, got {:?}", expected, text)))
+}
+
+/// `(read-num)`: the next token on stdin as an integer or float.
+#[no_mangle]
+pub extern "C" fn snek_read_num(site: i64, fp: u64) -> i64 {
+    let token = read_token(site, fp);
+    match parse_input(&token) {
+        Ok(v) if v & 1 == 0 || is_float(v) => v,
+        _ => bad_input(site, fp, "num", &token),
+    }
+}
+
+/// `(read-bool)`: the next token on stdin, which must be `true` or `false`.
+#[no_mangle]
+pub extern "C" fn snek_read_bool(site: i64, fp: u64) -> i64 {
+    let token = read_token(site, fp);
+    match token.as_str() {
+        "true" => 3,
+        "false" => 1,
+        _ => bad_input(site, fp, "bool", &token),
+    }
+}
+
+/// `(read-line)`: the rest of the current line if a token read left anything
+/// on it, otherwise the next line, parsed like a command-line argument.
+#[no_mangle]
+pub extern "C" fn snek_read_line(site: i64, fp: u64) -> i64 {
+    let pending = std::mem::take(&mut *STDIN_PENDING.lock().unwrap());
+    let line = if pending.trim().is_empty() {
+        match next_stdin_line() {
+            Some(line) => line,
+            None => fail(6, site, fp, None),
+        }
+    } else {
+        pending
+    };
+    match parse_input(line.trim()) {
+        Ok(v) => v,
+        Err(_) => bad_input(site, fp, "num or bool", line.trim()),
+    }
+}
+
 fn main() {
-    let cli_input: i32 = std::env::args()
-        .nth(1)
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(0);
+    let mut argv: Vec<String> = std::env::args().skip(1).collect();
+    if argv.first().map(String::as_str) == Some("--json") {
+        argv.remove(0);
+        JSON_MODE.store(true, Ordering::Relaxed);
+    }
+    if std::env::var("SNEK_OUTPUT").map_or(false, |v| v == "json") {
+        JSON_MODE.store(true, Ordering::Relaxed);
+    }
+    let mut args = Vec::new();
+    for s in &argv {
+        match parse_input(s) {
+            Ok(v) => args.push(v),
+            Err(msg) if json_mode() => {
+                let error = format!(
+                    "{{\"code\": null, \"kind\": \"bad argument\", \"message\": {}, \"backtrace\": []}}",
+                    json_string(&msg)
+                );
+                emit_json_record("null", &error);
+                std::process::exit(EXIT_BAD_ARGS);
+            }
+            Err(msg) => {
+                eprintln!("{msg}");
+                std::process::exit(1);
+            }
+        }
+    }
     unsafe {
-        INPUT_VAL = (cli_input as i64) << 1;
+        INPUT_VAL = args.first().copied().unwrap_or(0);
+    }
+    ARGS.set(args).unwrap();
+
+    let stack_bytes = std::env::var("SNEK_STACK_SIZE")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_STACK_BYTES)
+        .max(4 * STACK_HEADROOM);
+    let runner = std::thread::Builder::new()
+        .stack_size(stack_bytes)
+        .spawn(move || {
+            let marker = 0u8;
+            let top = &marker as *const u8 as usize;
+            unsafe {
+                STACK_LIMIT = (top - stack_bytes + STACK_HEADROOM) as u64;
+                our_code_starts_here()
+            }
+        })
+        .expect("failed to start program thread");
+    let i: i64 = runner.join().unwrap();
+    if json_mode() {
+        emit_json_record(&json_value(i), "null");
+    } else {
+        println!("{}", render_tagged(i));
+        print_profile();
     }
-    let i: i64 = unsafe { our_code_starts_here() };
-    println!("{}", render_tagged(i));
 }
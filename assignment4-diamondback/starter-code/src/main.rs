@@ -2,7 +2,8 @@
 
 use sexp::Atom::*;
 use sexp::*;
-use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
@@ -10,20 +11,33 @@ use std::io::prelude::*;
 #[derive(Debug, Clone)]
 enum Expr {
     Num(i32),
+    Float(f64),
     Bool(bool),
     Input,
+    Argc,
+    Read(ReadKind, Pos),
     Var(String),
     Let(Vec<(String, Expr)>, Box<Expr>),
-    UnOp(UnOp, Box<Expr>),
-    BinOp(BinOp, Box<Expr>, Box<Expr>),
+    UnOp(UnOp, Box<Expr>, Pos),
+    BinOp(BinOp, Box<Expr>, Box<Expr>, Pos),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
     Block(Vec<Expr>),
     Loop(Box<Expr>),
     Break(Box<Expr>),
     Set(String, Box<Expr>),
-    Call(String, Vec<Expr>),
+    Call(String, Vec<Expr>, Pos),
 }
 
+/// 1-based source position of a list form; line 0 means unknown.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Pos {
+    line: u32,
+    col: u32,
+}
+
+/// Source positions of the list nodes of a parsed `Sexp`, keyed by node address.
+type Spans = HashMap<*const Sexp, Pos>;
+
 #[derive(Debug, Clone)]
 struct Definition {
     name: String,
@@ -31,27 +45,56 @@ struct Definition {
     body: Expr,
 }
 
+/// `(extern (name param ...))`: a C-ABI function supplied by the runtime or a
+/// linked library, callable like a Snek function.
+#[derive(Debug, Clone)]
+struct Extern {
+    name: String,
+    params: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Global {
+    Define(String, Expr),
+    Const(String, Expr),
+}
+
 #[derive(Debug, Clone)]
 struct Program {
     defns: Vec<Definition>,
+    externs: Vec<Extern>,
+    globals: Vec<Global>,
     main: Expr,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum UnOp {
     Add1,
     Sub1,
     Negate,
     IsNum,
     IsBool,
+    IsFloat,
+    ToFloat,
+    Truncate,
+    Arg,
     Print,
 }
 
-#[derive(Debug, Clone)]
+/// Stdin builtins: `(read-num)`, `(read-bool)` and `(read-line)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReadKind {
+    Num,
+    Bool,
+    Line,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 enum BinOp {
     Plus,
     Minus,
     Times,
+    Divide,
     Less,
     Greater,
     LessEq,
@@ -69,6 +112,7 @@ fn reserved_word(sym: &str) -> bool {
             | "+"
             | "-"
             | "*"
+            | "/"
             | "<"
             | ">"
             | "<="
@@ -76,6 +120,9 @@ fn reserved_word(sym: &str) -> bool {
             | "="
             | "isnum"
             | "isbool"
+            | "isfloat"
+            | "float"
+            | "truncate"
             | "if"
             | "block"
             | "loop"
@@ -84,13 +131,81 @@ fn reserved_word(sym: &str) -> bool {
             | "true"
             | "false"
             | "input"
+            | "arg"
+            | "argc"
+            | "read-num"
+            | "read-bool"
+            | "read-line"
             | "fun"
+            | "extern"
+            | "define"
+            | "const"
     )
 }
 
-fn parse_expr(s: &Sexp) -> Expr {
+/// Positions of every `(` in `src`, in order, skipping comments and quoted atoms
+/// the same way the `sexp` lexer does.
+fn list_positions(src: &str) -> Vec<Pos> {
+    let mut out = Vec::new();
+    let (mut line, mut col) = (1, 0);
+    let mut chars = src.chars();
+    let mut in_comment = false;
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            line += 1;
+            col = 0;
+            in_comment = false;
+            continue;
+        }
+        col += 1;
+        if in_comment {
+            continue;
+        }
+        if in_string {
+            if c == '\\' {
+                chars.next();
+                col += 1;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            ';' => in_comment = true,
+            '"' => in_string = true,
+            '(' => out.push(Pos { line, col }),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Pairs the lists of `sexp` (in pre-order) with their positions in `src`.
+fn source_spans(src: &str, sexp: &Sexp) -> Spans {
+    fn walk(s: &Sexp, positions: &mut std::vec::IntoIter<Pos>, out: &mut Spans) {
+        if let Sexp::List(items) = s {
+            if let Some(p) = positions.next() {
+                out.insert(s as *const Sexp, p);
+            }
+            for item in items {
+                walk(item, positions, out);
+            }
+        }
+    }
+    let mut spans = Spans::new();
+    walk(sexp, &mut list_positions(src).into_iter(), &mut spans);
+    spans
+}
+
+fn span_of(s: &Sexp, spans: &Spans) -> Pos {
+    spans.get(&(s as *const Sexp)).copied().unwrap_or_default()
+}
+
+fn parse_expr(s: &Sexp, spans: &Spans) -> Expr {
     match s {
         Sexp::Atom(I(n)) => Expr::Num(i32::try_from(*n).unwrap()),
+        Sexp::Atom(F(f)) => Expr::Float(*f),
 
         Sexp::Atom(S(name)) => match name.as_str() {
             "true" => Expr::Bool(true),
@@ -121,99 +236,155 @@ fn parse_expr(s: &Sexp) -> Expr {
                                 if reserved_word(nm) {
                                     panic!("Cannot use keyword as binding name: {}", nm);
                                 }
-                                pairs.push((nm.to_string(), parse_expr(rhs)));
+                                pairs.push((nm.to_string(), parse_expr(rhs, spans)));
                             }
                             _ => panic!("Invalid binding: {:?}", pair),
                         },
                         _ => panic!("Invalid binding: {:?}", b),
                     }
                 }
-                Expr::Let(pairs, Box::new(parse_expr(body)))
+                Expr::Let(pairs, Box::new(parse_expr(body, spans)))
             }
 
             [Sexp::Atom(S(op)), e] if op == "add1" => {
-                Expr::UnOp(UnOp::Add1, Box::new(parse_expr(e)))
+                Expr::UnOp(UnOp::Add1, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
             [Sexp::Atom(S(op)), e] if op == "sub1" => {
-                Expr::UnOp(UnOp::Sub1, Box::new(parse_expr(e)))
+                Expr::UnOp(UnOp::Sub1, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
             [Sexp::Atom(S(op)), e] if op == "negate" => {
-                Expr::UnOp(UnOp::Negate, Box::new(parse_expr(e)))
+                Expr::UnOp(UnOp::Negate, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
             [Sexp::Atom(S(op)), e] if op == "isnum" => {
-                Expr::UnOp(UnOp::IsNum, Box::new(parse_expr(e)))
+                Expr::UnOp(UnOp::IsNum, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
             [Sexp::Atom(S(op)), e] if op == "isbool" => {
-                Expr::UnOp(UnOp::IsBool, Box::new(parse_expr(e)))
-            }
-            [Sexp::Atom(S(op)), e] if op == "print" => {
-                Expr::UnOp(UnOp::Print, Box::new(parse_expr(e)))
+                Expr::UnOp(UnOp::IsBool, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
-
-            [Sexp::Atom(S(op)), e1, e2] if op == "+" => {
-                Expr::BinOp(BinOp::Plus, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)))
+            [Sexp::Atom(S(op)), e] if op == "isfloat" => {
+                Expr::UnOp(UnOp::IsFloat, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
-            [Sexp::Atom(S(op)), e1, e2] if op == "-" => {
-                Expr::BinOp(BinOp::Minus, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)))
+            [Sexp::Atom(S(op)), e] if op == "float" => {
+                Expr::UnOp(UnOp::ToFloat, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
-            [Sexp::Atom(S(op)), e1, e2] if op == "*" => {
-                Expr::BinOp(BinOp::Times, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)))
+            [Sexp::Atom(S(op)), e] if op == "truncate" => {
+                Expr::UnOp(UnOp::Truncate, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
-            [Sexp::Atom(S(op)), e1, e2] if op == "<" => {
-                Expr::BinOp(BinOp::Less, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)))
+            [Sexp::Atom(S(op)), e] if op == "arg" => {
+                Expr::UnOp(UnOp::Arg, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
-            [Sexp::Atom(S(op)), e1, e2] if op == ">" => {
-                Expr::BinOp(BinOp::Greater, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)))
+            [Sexp::Atom(S(op))] if op == "argc" => Expr::Argc,
+            [Sexp::Atom(S(op))] if op == "read-num" => {
+                Expr::Read(ReadKind::Num, span_of(s, spans))
             }
-            [Sexp::Atom(S(op)), e1, e2] if op == "<=" => {
-                Expr::BinOp(BinOp::LessEq, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)))
+            [Sexp::Atom(S(op))] if op == "read-bool" => {
+                Expr::Read(ReadKind::Bool, span_of(s, spans))
             }
-            [Sexp::Atom(S(op)), e1, e2] if op == ">=" => {
-                Expr::BinOp(BinOp::GreaterEq, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)))
+            [Sexp::Atom(S(op))] if op == "read-line" => {
+                Expr::Read(ReadKind::Line, span_of(s, spans))
             }
-            [Sexp::Atom(S(op)), e1, e2] if op == "=" => {
-                Expr::BinOp(BinOp::Equal, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)))
+            [Sexp::Atom(S(op)), e] if op == "print" => {
+                Expr::UnOp(UnOp::Print, Box::new(parse_expr(e, spans)), span_of(s, spans))
             }
 
+            [Sexp::Atom(S(op)), e1, e2] if op == "+" => Expr::BinOp(
+                BinOp::Plus,
+                Box::new(parse_expr(e1, spans)),
+                Box::new(parse_expr(e2, spans)),
+                span_of(s, spans),
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == "-" => Expr::BinOp(
+                BinOp::Minus,
+                Box::new(parse_expr(e1, spans)),
+                Box::new(parse_expr(e2, spans)),
+                span_of(s, spans),
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == "*" => Expr::BinOp(
+                BinOp::Times,
+                Box::new(parse_expr(e1, spans)),
+                Box::new(parse_expr(e2, spans)),
+                span_of(s, spans),
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == "/" => Expr::BinOp(
+                BinOp::Divide,
+                Box::new(parse_expr(e1, spans)),
+                Box::new(parse_expr(e2, spans)),
+                span_of(s, spans),
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == "<" => Expr::BinOp(
+                BinOp::Less,
+                Box::new(parse_expr(e1, spans)),
+                Box::new(parse_expr(e2, spans)),
+                span_of(s, spans),
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == ">" => Expr::BinOp(
+                BinOp::Greater,
+                Box::new(parse_expr(e1, spans)),
+                Box::new(parse_expr(e2, spans)),
+                span_of(s, spans),
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == "<=" => Expr::BinOp(
+                BinOp::LessEq,
+                Box::new(parse_expr(e1, spans)),
+                Box::new(parse_expr(e2, spans)),
+                span_of(s, spans),
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == ">=" => Expr::BinOp(
+                BinOp::GreaterEq,
+                Box::new(parse_expr(e1, spans)),
+                Box::new(parse_expr(e2, spans)),
+                span_of(s, spans),
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == "=" => Expr::BinOp(
+                BinOp::Equal,
+                Box::new(parse_expr(e1, spans)),
+                Box::new(parse_expr(e2, spans)),
+                span_of(s, spans),
+            ),
+
             [Sexp::Atom(S(kw)), c, t, f] if kw == "if" => Expr::If(
-                Box::new(parse_expr(c)),
-                Box::new(parse_expr(t)),
-                Box::new(parse_expr(f)),
+                Box::new(parse_expr(c, spans)),
+                Box::new(parse_expr(t, spans)),
+                Box::new(parse_expr(f, spans)),
             ),
 
             [Sexp::Atom(S(kw)), rest @ ..] if kw == "block" => {
                 if rest.is_empty() {
                     panic!("block needs at least one expression");
                 }
-                Expr::Block(rest.iter().map(parse_expr).collect())
+                Expr::Block(rest.iter().map(|e| parse_expr(e, spans)).collect())
             }
 
-            [Sexp::Atom(S(kw)), body] if kw == "loop" => Expr::Loop(Box::new(parse_expr(body))),
+            [Sexp::Atom(S(kw)), body] if kw == "loop" => {
+                Expr::Loop(Box::new(parse_expr(body, spans)))
+            }
 
-            [Sexp::Atom(S(kw)), e] if kw == "break" => Expr::Break(Box::new(parse_expr(e))),
+            [Sexp::Atom(S(kw)), e] if kw == "break" => Expr::Break(Box::new(parse_expr(e, spans))),
 
             [Sexp::Atom(S(kw)), Sexp::Atom(S(name)), rhs] if kw == "set!" => {
                 if reserved_word(name) {
                     panic!("set! target cannot be keyword: {}", name);
                 }
-                Expr::Set(name.to_string(), Box::new(parse_expr(rhs)))
+                Expr::Set(name.to_string(), Box::new(parse_expr(rhs, spans)))
             }
 
             [Sexp::Atom(S(name)), args @ ..] => {
                 if reserved_word(name) {
                     panic!("Invalid expression: {:?}", vec);
                 }
-                Expr::Call(name.to_string(), args.iter().map(parse_expr).collect())
+                Expr::Call(
+                    name.to_string(),
+                    args.iter().map(|e| parse_expr(e, spans)).collect(),
+                    span_of(s, spans),
+                )
             }
 
             _ => panic!("Invalid expression: {:?}", vec),
         },
-
-        _ => panic!("Invalid expression: {:?}", s),
     }
 }
 
-fn parse_definition(s: &Sexp) -> Definition {
+fn parse_definition(s: &Sexp, spans: &Spans) -> Definition {
     match s {
         Sexp::List(items) => match &items[..] {
             [Sexp::Atom(S(fun_kw)), Sexp::List(signature), body] if fun_kw == "fun" => {
@@ -241,7 +412,7 @@ fn parse_definition(s: &Sexp) -> Definition {
                         Definition {
                             name: name.clone(),
                             params: out_params,
-                            body: parse_expr(body),
+                            body: parse_expr(body, spans),
                         }
                     }
                     _ => panic!("Invalid function signature"),
@@ -253,6 +424,70 @@ fn parse_definition(s: &Sexp) -> Definition {
     }
 }
 
+fn parse_global(s: &Sexp, spans: &Spans) -> Global {
+    match s {
+        Sexp::List(items) => match &items[..] {
+            [Sexp::Atom(S(kw)), Sexp::Atom(S(name)), rhs] if kw == "define" || kw == "const" => {
+                if reserved_word(name) {
+                    panic!("Global name cannot be keyword: {}", name);
+                }
+                let value = parse_expr(rhs, spans);
+                if kw == "define" {
+                    return Global::Define(name.clone(), value);
+                }
+                match value {
+                    Expr::Num(_) | Expr::Float(_) | Expr::Bool(_) => {
+                        Global::Const(name.clone(), value)
+                    }
+                    _ => panic!("Constant {} must be a literal", name),
+                }
+            }
+            _ => panic!("Invalid global declaration"),
+        },
+        _ => panic!("Invalid global declaration"),
+    }
+}
+
+fn parse_extern(s: &Sexp) -> Extern {
+    match s {
+        Sexp::List(items) => match &items[..] {
+            [Sexp::Atom(S(kw)), Sexp::List(signature)] if kw == "extern" => match &signature[..] {
+                [Sexp::Atom(S(name)), params @ ..] => {
+                    if reserved_word(name) {
+                        panic!("Function name cannot be keyword: {}", name);
+                    }
+                    // The name is used as the assembly symbol, so it must be a C identifier.
+                    let symbol_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+                    let leading_digit = name.starts_with(|c: char| c.is_ascii_digit());
+                    if leading_digit || !name.chars().all(symbol_char) {
+                        panic!("Invalid extern name: {}", name);
+                    }
+                    let mut seen = HashSet::new();
+                    let mut out_params = Vec::new();
+                    for p in params {
+                        match p {
+                            Sexp::Atom(S(param)) if !reserved_word(param) => {
+                                if !seen.insert(param.clone()) {
+                                    panic!("Duplicate parameter: {}", param);
+                                }
+                                out_params.push(param.clone());
+                            }
+                            _ => panic!("Invalid parameter in extern {}", name),
+                        }
+                    }
+                    Extern {
+                        name: name.clone(),
+                        params: out_params,
+                    }
+                }
+                _ => panic!("Invalid extern signature"),
+            },
+            _ => panic!("Invalid extern declaration"),
+        },
+        _ => panic!("Invalid extern declaration"),
+    }
+}
+
 fn is_definition_form(s: &Sexp) -> bool {
     match s {
         Sexp::List(items) => match &items[..] {
@@ -263,896 +498,6478 @@ fn is_definition_form(s: &Sexp) -> bool {
     }
 }
 
-fn parse_program(s: &Sexp) -> Program {
+fn is_global_form(s: &Sexp) -> bool {
+    match s {
+        Sexp::List(items) => match &items[..] {
+            [Sexp::Atom(S(kw)), Sexp::Atom(S(_)), _] => kw == "define" || kw == "const",
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn is_extern_form(s: &Sexp) -> bool {
+    match s {
+        Sexp::List(items) => match &items[..] {
+            [Sexp::Atom(S(kw)), Sexp::List(_)] => kw == "extern",
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn is_top_level_form(s: &Sexp) -> bool {
+    is_definition_form(s) || is_global_form(s) || is_extern_form(s)
+}
+
+fn parse_program(s: &Sexp, spans: &Spans) -> Program {
     match s {
-        Sexp::List(items) if items.iter().any(is_definition_form) => {
+        Sexp::List(items) if items.iter().any(is_top_level_form) => {
             if items.is_empty() {
                 panic!("Program cannot be empty");
             }
             let mut defns = Vec::new();
+            let mut externs = Vec::new();
+            let mut globals = Vec::new();
             for item in &items[..items.len() - 1] {
-                if !is_definition_form(item) {
+                if is_definition_form(item) {
+                    defns.push(parse_definition(item, spans));
+                } else if is_extern_form(item) {
+                    externs.push(parse_extern(item));
+                } else if is_global_form(item) {
+                    globals.push(parse_global(item, spans));
+                } else {
                     panic!("Function definitions must come before main expression");
                 }
-                defns.push(parse_definition(item));
             }
-            if is_definition_form(&items[items.len() - 1]) {
+            if is_top_level_form(&items[items.len() - 1]) {
                 panic!("Program must end with a main expression");
             }
             Program {
                 defns,
-                main: parse_expr(&items[items.len() - 1]),
+                externs,
+                globals,
+                main: parse_expr(&items[items.len() - 1], spans),
             }
         }
         _ => Program {
             defns: vec![],
-            main: parse_expr(s),
+            externs: vec![],
+            globals: vec![],
+            main: parse_expr(s, spans),
         },
     }
 }
 
-fn mk_label(seq: &mut i32, stem: &str) -> String {
-    *seq += 1;
-    format!("{}_{}", stem, *seq)
+/// Registers used by generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R11,
+    Rsp,
+    Rbp,
+    Rbx,
+    R12,
+    R13,
+    R14,
+    R15,
 }
 
-fn append_snek_invalid_at(lines: &mut Vec<String>, lab: &str) {
-    lines.push(format!("{}:", lab));
-    lines.push("mov rdi, 1".to_string());
-    lines.push("call snek_error".to_string());
+/// Instruction operands.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Val {
+    Reg(Reg),
+    /// The low 32 bits of a register, e.g. `eax`.
+    Reg32(Reg),
+    /// The low 8 bits of a register, e.g. `al`.
+    Reg8(Reg),
+    Imm(i64),
+    /// `[reg + offset]`.
+    RegOffset(Reg, i32),
+    /// `[rel label + offset]`.
+    Rel(String, i32),
 }
 
-fn append_snek_overflow_at(lines: &mut Vec<String>, lab: &str) {
-    lines.push(format!("{}:", lab));
-    lines.push("mov rdi, 2".to_string());
-    lines.push("call snek_error".to_string());
+/// Condition codes of conditional jumps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cond {
+    E,
+    Ne,
+    L,
+    Le,
+    G,
+    Ge,
+    O,
+    B,
 }
 
-fn append_two_num_checks(depth: i32, lines: &mut Vec<String>, seq: &mut i32) -> String {
-    let bad = mk_label(seq, "badarg");
-    lines.push("mov r11, rax".to_string());
-    lines.push("and r11, 1".to_string());
-    lines.push("cmp r11, 0".to_string());
-    lines.push(format!("jne {}", bad));
-    lines.push(format!("mov rcx, [rbp - {}]", depth));
-    lines.push("test rcx, 1".to_string());
-    lines.push(format!("jne {}", bad));
-    lines.push("mov rdi, rcx".to_string());
-    lines.push("sar rdi, 1".to_string());
-    lines.push("mov rsi, rax".to_string());
-    lines.push("sar rsi, 1".to_string());
-    bad
+impl Cond {
+    /// The condition that holds exactly when a comparison's `self` fails.
+    fn negate(self) -> Cond {
+        match self {
+            Cond::E => Cond::Ne,
+            Cond::Ne => Cond::E,
+            Cond::L => Cond::Ge,
+            Cond::Ge => Cond::L,
+            Cond::G => Cond::Le,
+            Cond::Le => Cond::G,
+            Cond::O | Cond::B => panic!("no negated condition for {:?}", self),
+        }
+    }
 }
 
-fn load_slot(off: i32) -> String {
-    format!("mov rax, [rbp - {}]", off)
+/// The assembly the compiler generates. Code is built as a list of these and
+/// only turned into text by `instr_to_str` once a function is complete.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+enum Instr {
+    ILabel(String),
+    IMov(Val, Val),
+    ILea(Val, Val),
+    IAdd(Val, Val),
+    ISub(Val, Val),
+    IMul(Val, Val),
+    IAnd(Val, Val),
+    IOr(Val, Val),
+    ITest(Val, Val),
+    ICmp(Val, Val),
+    ISar(Val, Val),
+    IShl(Val, Val),
+    INeg(Val),
+    ICqo,
+    IDiv(Val),
+    IPush(Val),
+    IPop(Val),
+    IRdtsc,
+    ICall(String),
+    IRet,
+    IJmp(String),
+    IJcc(Cond, String),
+    ICmov(Cond, Val, Val),
 }
 
-fn store_slot(off: i32) -> String {
-    format!("mov [rbp - {}], rax", off)
+fn reg(r: Reg) -> Val {
+    Val::Reg(r)
 }
 
-fn emit_expr(
-    e: &Expr,
-    env: &HashMap<String, i32>,
-    arities: &HashMap<String, usize>,
-    param_names: &HashSet<String>,
-    depth: i32,
-    seq: &mut i32,
-    exit_loop: Option<&String>,
-) -> String {
-    match e {
-        Expr::Num(n) => {
-            let enc = (*n as i64).wrapping_mul(2);
-            format!("mov rax, {}", enc)
-        }
+/// The stack slot `[rbp - off]`; parameters have negative offsets and so
+/// live above `rbp`.
+fn slot(off: i32) -> Val {
+    Val::RegOffset(Reg::Rbp, -off)
+}
 
-        Expr::Bool(b) => {
-            if *b {
-                "mov rax, 3".to_string()
-            } else {
-                "mov rax, 1".to_string()
-            }
-        }
+fn rel(label: &str) -> Val {
+    Val::Rel(label.to_string(), 0)
+}
 
-        Expr::Input => "mov rax, [rel INPUT_VAL]".to_string(),
+fn reg_to_str(r: Reg) -> &'static str {
+    match r {
+        Reg::Rax => "rax",
+        Reg::Rcx => "rcx",
+        Reg::Rdx => "rdx",
+        Reg::Rsi => "rsi",
+        Reg::Rdi => "rdi",
+        Reg::R8 => "r8",
+        Reg::R9 => "r9",
+        Reg::R11 => "r11",
+        Reg::Rsp => "rsp",
+        Reg::Rbp => "rbp",
+        Reg::Rbx => "rbx",
+        Reg::R12 => "r12",
+        Reg::R13 => "r13",
+        Reg::R14 => "r14",
+        Reg::R15 => "r15",
+    }
+}
 
-        Expr::Var(name) => match env.get(name) {
-            Some(off) if *off > 0 => load_slot(*off),
-            Some(off) => format!("mov rax, [rbp + {}]", -off),
-            None => panic!("Unbound variable: {}", name),
-        },
+fn reg32_to_str(r: Reg) -> &'static str {
+    match r {
+        Reg::Rax => "eax",
+        Reg::Rcx => "ecx",
+        Reg::Rdx => "edx",
+        Reg::Rsi => "esi",
+        Reg::Rdi => "edi",
+        Reg::R8 => "r8d",
+        Reg::R9 => "r9d",
+        Reg::R11 => "r11d",
+        Reg::Rsp => "esp",
+        Reg::Rbp => "ebp",
+        Reg::Rbx => "ebx",
+        Reg::R12 => "r12d",
+        Reg::R13 => "r13d",
+        Reg::R14 => "r14d",
+        Reg::R15 => "r15d",
+    }
+}
 
-        Expr::Let(bindings, body) => {
-            let mut lines = Vec::new();
-            let mut next_env = env.clone();
-            let mut cursor = depth;
-            for (nm, rhs) in bindings {
-                if param_names.contains(nm) {
-                    panic!("Cannot shadow parameter with let: {}", nm);
-                }
-                lines.push(emit_expr(
-                    rhs,
-                    &next_env,
-                    arities,
-                    param_names,
-                    cursor,
-                    seq,
-                    exit_loop,
-                ));
-                lines.push(store_slot(cursor));
-                next_env.insert(nm.clone(), cursor);
-                cursor += 8;
-            }
-            lines.push(emit_expr(
-                body,
-                &next_env,
-                arities,
-                param_names,
-                cursor,
-                seq,
-                exit_loop,
-            ));
-            lines.join("\n  ")
-        }
-
-        Expr::UnOp(op, sub) => {
-            let mut lines = vec![emit_expr(
-                sub,
-                env,
-                arities,
-                param_names,
-                depth,
-                seq,
-                exit_loop,
-            )];
-            match op {
-                UnOp::Add1 => {
-                    let bad = mk_label(seq, "badarg");
-                    let ov = mk_label(seq, "overflow");
-                    let done = mk_label(seq, "u_done");
-                    lines.push("mov r11, rax".to_string());
-                    lines.push("and r11, 1".to_string());
-                    lines.push("cmp r11, 0".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push("add rax, 2".to_string());
-                    lines.push(format!("jo {}", ov));
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    append_snek_overflow_at(&mut lines, &ov);
-                    lines.push(format!("{}:", done));
-                }
-                UnOp::Sub1 => {
-                    let bad = mk_label(seq, "badarg");
-                    let ov = mk_label(seq, "overflow");
-                    let done = mk_label(seq, "u_done");
-                    lines.push("mov r11, rax".to_string());
-                    lines.push("and r11, 1".to_string());
-                    lines.push("cmp r11, 0".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push("sub rax, 2".to_string());
-                    lines.push(format!("jo {}", ov));
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    append_snek_overflow_at(&mut lines, &ov);
-                    lines.push(format!("{}:", done));
-                }
-                UnOp::Negate => {
-                    let bad = mk_label(seq, "badarg");
-                    let ov = mk_label(seq, "overflow");
-                    let done = mk_label(seq, "u_done");
-                    lines.push("mov r11, rax".to_string());
-                    lines.push("and r11, 1".to_string());
-                    lines.push("cmp r11, 0".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push("sar rax, 1".to_string());
-                    lines.push("neg eax".to_string());
-                    lines.push(format!("jo {}", ov));
-                    lines.push("movsxd rax, eax".to_string());
-                    lines.push("sal rax, 1".to_string());
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    append_snek_overflow_at(&mut lines, &ov);
-                    lines.push(format!("{}:", done));
-                }
-                UnOp::IsNum => {
-                    let t = mk_label(seq, "inum_t");
-                    let d = mk_label(seq, "inum_d");
-                    lines.push("mov r11, rax".to_string());
-                    lines.push("and r11, 1".to_string());
-                    lines.push("cmp r11, 0".to_string());
-                    lines.push(format!("je {}", t));
-                    lines.push("mov rax, 1".to_string());
-                    lines.push(format!("jmp {}", d));
-                    lines.push(format!("{}:", t));
-                    lines.push("mov rax, 3".to_string());
-                    lines.push(format!("{}:", d));
-                }
-                UnOp::IsBool => {
-                    let t = mk_label(seq, "ib_t");
-                    let d = mk_label(seq, "ib_d");
-                    lines.push("mov r11, rax".to_string());
-                    lines.push("and r11, 1".to_string());
-                    lines.push("cmp r11, 0".to_string());
-                    lines.push(format!("jne {}", t));
-                    lines.push("mov rax, 1".to_string());
-                    lines.push(format!("jmp {}", d));
-                    lines.push(format!("{}:", t));
-                    lines.push("mov rax, 3".to_string());
-                    lines.push(format!("{}:", d));
-                }
-                UnOp::Print => {
-                    lines.push("mov rdi, rax".to_string());
-                    lines.push("call snek_print".to_string());
-                }
-            }
-            lines.join("\n  ")
-        }
-
-        Expr::BinOp(op, e1, e2) => {
-            let mut lines = Vec::new();
-            lines.push(emit_expr(
-                e1,
-                env,
-                arities,
-                param_names,
-                depth,
-                seq,
-                exit_loop,
-            ));
-            lines.push(store_slot(depth));
-            lines.push(emit_expr(
-                e2,
-                env,
-                arities,
-                param_names,
-                depth + 8,
-                seq,
-                exit_loop,
-            ));
-            match op {
-                BinOp::Plus => {
-                    let bad = mk_label(seq, "badarg");
-                    let ov = mk_label(seq, "overflow");
-                    let done = mk_label(seq, "bin_done");
-                    lines.push("mov r11, rax".to_string());
-                    lines.push("and r11, 1".to_string());
-                    lines.push("cmp r11, 0".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push(format!("mov r11, [rbp - {}]", depth));
-                    lines.push("test r11, 1".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push(format!("add rax, [rbp - {}]", depth));
-                    lines.push(format!("jo {}", ov));
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    append_snek_overflow_at(&mut lines, &ov);
-                    lines.push(format!("{}:", done));
-                }
-                BinOp::Minus => {
-                    let bad = mk_label(seq, "badarg");
-                    let ov = mk_label(seq, "overflow");
-                    let done = mk_label(seq, "bin_done");
-                    lines.push("mov r11, rax".to_string());
-                    lines.push("and r11, 1".to_string());
-                    lines.push("cmp r11, 0".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push(format!("mov r11, [rbp - {}]", depth));
-                    lines.push("test r11, 1".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push(format!("mov rcx, [rbp - {}]", depth));
-                    lines.push("sub rcx, rax".to_string());
-                    lines.push(format!("jo {}", ov));
-                    lines.push("mov rax, rcx".to_string());
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    append_snek_overflow_at(&mut lines, &ov);
-                    lines.push(format!("{}:", done));
-                }
-                BinOp::Times => {
-                    let bad = mk_label(seq, "badarg");
-                    let ov = mk_label(seq, "overflow");
-                    let done = mk_label(seq, "bin_done");
-                    lines.push("mov r11, rax".to_string());
-                    lines.push("and r11, 1".to_string());
-                    lines.push("cmp r11, 0".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push(format!("mov rcx, [rbp - {}]", depth));
-                    lines.push("test rcx, 1".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push("mov edi, ecx".to_string());
-                    lines.push("sar edi, 1".to_string());
-                    lines.push("mov esi, eax".to_string());
-                    lines.push("sar esi, 1".to_string());
-                    lines.push("mov eax, edi".to_string());
-                    lines.push("imul eax, esi".to_string());
-                    lines.push(format!("jo {}", ov));
-                    lines.push("movsxd rax, eax".to_string());
-                    lines.push("sal rax, 1".to_string());
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    append_snek_overflow_at(&mut lines, &ov);
-                    lines.push(format!("{}:", done));
-                }
-                BinOp::Less => {
-                    let bad = append_two_num_checks(depth, &mut lines, seq);
-                    let done = mk_label(seq, "bin_done");
-                    lines.push("cmp rdi, rsi".to_string());
-                    let tr = mk_label(seq, "lt1");
-                    let fin = mk_label(seq, "lt2");
-                    lines.push(format!("jl {}", tr));
-                    lines.push("mov rax, 1".to_string());
-                    lines.push(format!("jmp {}", fin));
-                    lines.push(format!("{}:", tr));
-                    lines.push("mov rax, 3".to_string());
-                    lines.push(format!("{}:", fin));
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    lines.push(format!("{}:", done));
-                }
-                BinOp::Greater => {
-                    let bad = append_two_num_checks(depth, &mut lines, seq);
-                    let done = mk_label(seq, "bin_done");
-                    lines.push("cmp rdi, rsi".to_string());
-                    let tr = mk_label(seq, "gt1");
-                    let fin = mk_label(seq, "gt2");
-                    lines.push(format!("jg {}", tr));
-                    lines.push("mov rax, 1".to_string());
-                    lines.push(format!("jmp {}", fin));
-                    lines.push(format!("{}:", tr));
-                    lines.push("mov rax, 3".to_string());
-                    lines.push(format!("{}:", fin));
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    lines.push(format!("{}:", done));
-                }
-                BinOp::LessEq => {
-                    let bad = append_two_num_checks(depth, &mut lines, seq);
-                    let done = mk_label(seq, "bin_done");
-                    lines.push("cmp rdi, rsi".to_string());
-                    let tr = mk_label(seq, "le1");
-                    let fin = mk_label(seq, "le2");
-                    lines.push(format!("jle {}", tr));
-                    lines.push("mov rax, 1".to_string());
-                    lines.push(format!("jmp {}", fin));
-                    lines.push(format!("{}:", tr));
-                    lines.push("mov rax, 3".to_string());
-                    lines.push(format!("{}:", fin));
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    lines.push(format!("{}:", done));
-                }
-                BinOp::GreaterEq => {
-                    let bad = append_two_num_checks(depth, &mut lines, seq);
-                    let done = mk_label(seq, "bin_done");
-                    lines.push("cmp rdi, rsi".to_string());
-                    let tr = mk_label(seq, "ge1");
-                    let fin = mk_label(seq, "ge2");
-                    lines.push(format!("jge {}", tr));
-                    lines.push("mov rax, 1".to_string());
-                    lines.push(format!("jmp {}", fin));
-                    lines.push(format!("{}:", tr));
-                    lines.push("mov rax, 3".to_string());
-                    lines.push(format!("{}:", fin));
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    lines.push(format!("{}:", done));
-                }
-                BinOp::Equal => {
-                    let bad = mk_label(seq, "badarg");
-                    let done = mk_label(seq, "bin_done");
-                    lines.push(format!("mov r11, [rbp - {}]", depth));
-                    lines.push("mov rcx, rax".to_string());
-                    lines.push("mov rdx, rcx".to_string());
-                    lines.push("and rdx, 1".to_string());
-                    lines.push("mov rdi, r11".to_string());
-                    lines.push("and rdi, 1".to_string());
-                    lines.push("cmp rdx, rdi".to_string());
-                    lines.push(format!("jne {}", bad));
-                    lines.push("cmp rax, r11".to_string());
-                    let tr = mk_label(seq, "eqt");
-                    let fin = mk_label(seq, "eqf");
-                    lines.push(format!("je {}", tr));
-                    lines.push("mov rax, 1".to_string());
-                    lines.push(format!("jmp {}", fin));
-                    lines.push(format!("{}:", tr));
-                    lines.push("mov rax, 3".to_string());
-                    lines.push(format!("{}:", fin));
-                    lines.push(format!("jmp {}", done));
-                    append_snek_invalid_at(&mut lines, &bad);
-                    lines.push(format!("{}:", done));
-                }
-            }
-            lines.join("\n  ")
-        }
-
-        Expr::If(cond, th, el) => {
-            let alt = mk_label(seq, "if_alt");
-            let done = mk_label(seq, "if_done");
-            let lines = vec![
-                emit_expr(cond, env, arities, param_names, depth, seq, exit_loop),
-                "cmp rax, 1".to_string(),
-                format!("je {}", alt),
-                emit_expr(th, env, arities, param_names, depth, seq, exit_loop),
-                format!("jmp {}", done),
-                format!("{}:", alt),
-                emit_expr(el, env, arities, param_names, depth, seq, exit_loop),
-                format!("{}:", done),
-            ];
-            lines.join("\n  ")
-        }
+fn reg8_to_str(r: Reg) -> &'static str {
+    match r {
+        Reg::Rax => "al",
+        Reg::Rcx => "cl",
+        Reg::Rdx => "dl",
+        Reg::Rsi => "sil",
+        Reg::Rdi => "dil",
+        Reg::R8 => "r8b",
+        Reg::R9 => "r9b",
+        Reg::R11 => "r11b",
+        Reg::Rsp => "spl",
+        Reg::Rbp => "bpl",
+        Reg::Rbx => "bl",
+        Reg::R12 => "r12b",
+        Reg::R13 => "r13b",
+        Reg::R14 => "r14b",
+        Reg::R15 => "r15b",
+    }
+}
 
-        Expr::Block(items) => {
-            if items.is_empty() {
-                panic!("empty block");
-            }
-            let mut lines = Vec::new();
-            for piece in items {
-                lines.push(emit_expr(
-                    piece,
-                    env,
-                    arities,
-                    param_names,
-                    depth,
-                    seq,
-                    exit_loop,
-                ));
-            }
-            lines.join("\n  ")
-        }
-
-        Expr::Loop(body) => {
-            let head = mk_label(seq, "lp_h");
-            let tail = mk_label(seq, "lp_t");
-            let lines = vec![
-                format!("{}:", head),
-                emit_expr(body, env, arities, param_names, depth, seq, Some(&tail)),
-                format!("jmp {}", head),
-                format!("{}:", tail),
-            ];
-            lines.join("\n  ")
-        }
-
-        Expr::Break(inner) => match exit_loop {
-            Some(lab) => {
-                let lines = vec![
-                    emit_expr(inner, env, arities, param_names, depth, seq, exit_loop),
-                    format!("jmp {}", lab),
-                ];
-                lines.join("\n  ")
-            }
-            None => panic!("break outside of loop"),
-        },
+fn offset_to_str(offset: i32) -> String {
+    match offset {
+        0 => String::new(),
+        o if o < 0 => format!(" - {}", -(o as i64)),
+        o => format!(" + {}", o),
+    }
+}
 
-        Expr::Set(name, rhs) => {
-            let off = match env.get(name) {
-                Some(o) => *o,
-                None => panic!("set! on unknown binding: {}", name),
-            };
-            let mut lines = vec![emit_expr(
-                rhs,
-                env,
-                arities,
-                param_names,
-                depth,
-                seq,
-                exit_loop,
-            )];
-            if off > 0 {
-                lines.push(store_slot(off));
-            } else {
-                lines.push(format!("mov [rbp + {}], rax", -off));
-            }
-            lines.join("\n  ")
-        }
+fn val_to_str(v: &Val) -> String {
+    match v {
+        Val::Reg(r) => reg_to_str(*r).to_string(),
+        Val::Reg32(r) => reg32_to_str(*r).to_string(),
+        Val::Reg8(r) => reg8_to_str(*r).to_string(),
+        Val::Imm(n) => n.to_string(),
+        Val::RegOffset(r, offset) => format!("[{}{}]", reg_to_str(*r), offset_to_str(*offset)),
+        Val::Rel(label, offset) => format!("[rel {}{}]", label, offset_to_str(*offset)),
+    }
+}
 
-        Expr::Call(name, args) => {
-            let expected = match arities.get(name) {
-                Some(arity) => *arity,
-                None => panic!("Undefined function: {}", name),
-            };
-            if expected != args.len() {
-                panic!(
-                    "Wrong number of arguments in call to {}: expected {}, got {}",
-                    name,
-                    expected,
-                    args.len()
-                );
-            }
-            let mut lines = Vec::new();
-            let n = args.len() as i32;
-            let eval_depth = depth + n * 8;
-            for (i, arg) in args.iter().enumerate() {
-                lines.push(emit_expr(
-                    arg,
-                    env,
-                    arities,
-                    param_names,
-                    eval_depth,
-                    seq,
-                    exit_loop,
-                ));
-                lines.push(format!("mov [rbp - {}], rax", depth + (i as i32) * 8));
-            }
+fn is_memory(v: &Val) -> bool {
+    matches!(v, Val::RegOffset(..) | Val::Rel(..))
+}
 
-            let needs_pad = args.len() % 2 == 1;
-            if needs_pad {
-                lines.push("sub rsp, 8".to_string());
-            }
-            for i in (0..args.len()).rev() {
-                lines.push(format!("mov rax, [rbp - {}]", depth + (i as i32) * 8));
-                lines.push("push rax".to_string());
-            }
-            lines.push(format!("call fun_{}", name));
-            let cleanup = (args.len() * 8) + if needs_pad { 8 } else { 0 };
-            if cleanup > 0 {
-                lines.push(format!("add rsp, {}", cleanup));
-            }
-            lines.join("\n  ")
-        }
-    }
+/// `op dst, src`, sized explicitly when neither operand is a register.
+fn binary_to_str(op: &str, dst: &Val, src: &Val) -> String {
+    let size = if is_memory(dst) && matches!(src, Val::Imm(_)) { "qword " } else { "" };
+    format!("{} {}{}, {}", op, size, val_to_str(dst), val_to_str(src))
 }
 
-fn max_stack_depth(e: &Expr, depth: i32) -> i32 {
-    match e {
-        Expr::Num(_) | Expr::Bool(_) | Expr::Input | Expr::Var(_) => 0,
-        Expr::UnOp(_, sub) => max_stack_depth(sub, depth),
-        Expr::BinOp(_, e1, e2) => {
-            let left = max_stack_depth(e1, depth);
-            let right = max_stack_depth(e2, depth + 8);
-            left.max(right).max(depth)
-        }
-        Expr::If(c, t, f) => max_stack_depth(c, depth)
-            .max(max_stack_depth(t, depth))
-            .max(max_stack_depth(f, depth)),
-        Expr::Block(items) => items
-            .iter()
-            .map(|it| max_stack_depth(it, depth))
-            .max()
-            .unwrap_or(0),
-        Expr::Loop(body) => max_stack_depth(body, depth),
-        Expr::Break(inner) => max_stack_depth(inner, depth),
-        Expr::Set(_, rhs) => max_stack_depth(rhs, depth),
-        Expr::Let(bindings, body) => {
-            let mut cursor = depth;
-            let mut best = 0;
-            for (_, rhs) in bindings {
-                best = best.max(max_stack_depth(rhs, cursor)).max(cursor);
-                cursor += 8;
-            }
-            best.max(max_stack_depth(body, cursor))
-        }
-        Expr::Call(_, args) => {
-            let n = args.len() as i32;
-            let mut best = if n == 0 { 0 } else { depth + (n - 1) * 8 };
-            let eval_depth = depth + n * 8;
-            for arg in args {
-                best = best.max(max_stack_depth(arg, eval_depth));
-            }
-            best
-        }
+fn cond_to_str(c: Cond) -> &'static str {
+    match c {
+        Cond::E => "e",
+        Cond::Ne => "ne",
+        Cond::L => "l",
+        Cond::Le => "le",
+        Cond::G => "g",
+        Cond::Ge => "ge",
+        Cond::O => "o",
+        Cond::B => "b",
     }
 }
 
-fn align_to_16(bytes: i32) -> i32 {
-    if bytes == 0 {
-        0
-    } else {
-        ((bytes + 15) / 16) * 16
+fn instr_to_str(i: &Instr) -> String {
+    match i {
+        Instr::ILabel(name) => format!("{}:", name),
+        Instr::IMov(dst, src) => binary_to_str("mov", dst, src),
+        Instr::ILea(dst, src) => binary_to_str("lea", dst, src),
+        Instr::IAdd(dst, src) => binary_to_str("add", dst, src),
+        Instr::ISub(dst, src) => binary_to_str("sub", dst, src),
+        Instr::IMul(dst, src) => binary_to_str("imul", dst, src),
+        Instr::IAnd(dst, src) => binary_to_str("and", dst, src),
+        Instr::IOr(dst, src) => binary_to_str("or", dst, src),
+        Instr::ITest(dst, src) => binary_to_str("test", dst, src),
+        Instr::ICmp(dst, src) => binary_to_str("cmp", dst, src),
+        Instr::ISar(dst, src) => binary_to_str("sar", dst, src),
+        Instr::IShl(dst, src) => binary_to_str("shl", dst, src),
+        Instr::INeg(v) => format!("neg {}", val_to_str(v)),
+        Instr::ICqo => "cqo".to_string(),
+        Instr::IDiv(v) => format!("idiv {}", val_to_str(v)),
+        Instr::IPush(v) => format!("push {}", val_to_str(v)),
+        Instr::IPop(v) => format!("pop {}", val_to_str(v)),
+        Instr::IRdtsc => "rdtsc".to_string(),
+        Instr::ICall(target) => format!("call {}", target),
+        Instr::IRet => "ret".to_string(),
+        Instr::IJmp(target) => format!("jmp {}", target),
+        Instr::IJcc(c, target) => format!("j{} {}", cond_to_str(*c), target),
+        Instr::ICmov(c, dst, src) => binary_to_str(&format!("cmov{}", cond_to_str(*c)), dst, src),
     }
 }
 
-fn compile_definition(defn: &Definition, arities: &HashMap<String, usize>, seq: &mut i32) -> String {
-    let mut env = HashMap::new();
-    for (i, param) in defn.params.iter().enumerate() {
-        env.insert(param.clone(), -(16 + (i as i32) * 8));
-    }
-    let param_names: HashSet<String> = defn.params.iter().cloned().collect();
-    let frame_bytes = align_to_16(max_stack_depth(&defn.body, 8));
-    let mut lines = vec![
-        format!("fun_{}:", defn.name),
-        "push rbp".to_string(),
-        "mov rbp, rsp".to_string(),
-    ];
-    if frame_bytes > 0 {
-        lines.push(format!("sub rsp, {}", frame_bytes));
-    }
-    lines.push(emit_expr(
-        &defn.body,
-        &env,
-        arities,
-        &param_names,
-        8,
-        seq,
-        None,
-    ));
-    lines.push("mov rsp, rbp".to_string());
-    lines.push("pop rbp".to_string());
-    lines.push("ret".to_string());
-    lines.join("\n")
+fn mk_label(seq: &mut i32, stem: &str) -> String {
+    *seq += 1;
+    format!("{}_{}", stem, *seq)
 }
 
-fn compile_program(prog: &Program) -> String {
-    let mut arities = HashMap::new();
-    for defn in &prog.defns {
-        if arities.insert(defn.name.clone(), defn.params.len()).is_some() {
-            panic!("Duplicate function definition: {}", defn.name);
-        }
-    }
+/// What a runtime error stub knows about the failing operation: an address in
+/// the enclosing function, the operator, its source position, and the operand
+/// type it expected. `snek_error` receives the index into this table.
+#[derive(Debug, Clone)]
+struct ErrorSite {
+    here: String,
+    op: &'static str,
+    pos: Pos,
+    expected: &'static str,
+}
 
-    let mut seq = 0i32;
-    let mut lines = vec![
-        "section .text".to_string(),
-        "default rel".to_string(),
-        "extern snek_error".to_string(),
-        "extern snek_print".to_string(),
-        "extern INPUT_VAL".to_string(),
-        "global our_code_starts_here".to_string(),
-    ];
-    for defn in &prog.defns {
-        lines.push(compile_definition(defn, &arities, &mut seq));
-    }
-
-    let main_env = HashMap::new();
-    let main_params = HashSet::new();
-    let main_frame = align_to_16(max_stack_depth(&prog.main, 8));
-    lines.push("our_code_starts_here:".to_string());
-    lines.push("push rbp".to_string());
-    lines.push("mov rbp, rsp".to_string());
-    if main_frame > 0 {
-        lines.push(format!("sub rsp, {}", main_frame));
-    }
-    lines.push(emit_expr(
-        &prog.main,
-        &main_env,
-        &arities,
-        &main_params,
-        8,
-        &mut seq,
-        None,
-    ));
-    lines.push("mov rsp, rbp".to_string());
-    lines.push("pop rbp".to_string());
-    lines.push("ret".to_string());
-    format!("{}\n", lines.join("\n"))
+/// Where the operands of a failing operation are when its error stub runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operands {
+    None,
+    /// The operand in `rax`.
+    Unary,
+    /// Left operand in `rcx`, right operand in `rax`.
+    Binary,
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+/// Shared tails of the error stubs, one per error code and operand layout,
+/// emitted once after all functions. Each calls `snek_error` with the site
+/// the stub left in `rsi` and never returns.
+const ERROR_TAILS: [(&str, i64, Operands); 4] = [
+    ("fail_invalid_unary", 1, Operands::Unary),
+    ("fail_invalid_binary", 1, Operands::Binary),
+    ("fail_overflow", 2, Operands::None),
+    ("fail_div_zero", 3, Operands::None),
+];
 
-    if args.len() != 3 {
-        eprintln!("Usage: {} <input.snek> <output.s>", args[0]);
-        std::process::exit(1);
-    }
+fn is_error_tail(label: &str) -> bool {
+    ERROR_TAILS.iter().any(|(tail, _, _)| *tail == label)
+}
 
-    let in_name = &args[1];
-    let out_name = &args[2];
+fn error_site(ctx: &Ctx, op: &'static str, pos: Pos, expected: &'static str, here: &str) -> i64 {
+    let mut sites = ctx.error_sites.borrow_mut();
+    sites.push(ErrorSite {
+        here: here.to_string(),
+        op,
+        pos,
+        expected,
+    });
+    (sites.len() - 1) as i64
+}
 
-    let mut in_file = File::open(in_name)?;
-    let mut in_contents = String::new();
-    in_file.read_to_string(&mut in_contents)?;
+/// Trailing arguments of fallible runtime helpers: the error site and the
+/// current frame pointer, so errors raised in the runtime are reported too.
+fn append_site_args(code: &mut Vec<Instr>, site: i64) {
+    code.push(Instr::IMov(reg(Reg::Rsi), Val::Imm(site)));
+    code.push(Instr::IMov(reg(Reg::Rdx), reg(Reg::Rbp)));
+}
 
-    let sexp = parse(&in_contents).unwrap_or_else(|e| panic!("Parse error: {}", e));
-    let prog = parse_program(&sexp);
-    let asm = compile_program(&prog);
+/// Adds the stub at `lab` for error site `site` to the function's
+/// out-of-line stubs: it loads the site into `rsi` and jumps to the shared
+/// tail for `errcode` and `operands`.
+fn append_snek_error_at(ctx: &Ctx, lab: &str, errcode: i64, site: i64, operands: Operands) {
+    let (tail, _, _) = ERROR_TAILS
+        .iter()
+        .find(|(_, code, layout)| *code == errcode && *layout == operands)
+        .unwrap();
+    let mut stubs = ctx.stubs.borrow_mut();
+    stubs.push(Instr::ILabel(lab.to_string()));
+    stubs.push(Instr::IMov(Val::Reg32(Reg::Rsi), Val::Imm(site)));
+    stubs.push(Instr::IJmp(tail.to_string()));
+}
 
-    let mut out_file = File::create(out_name)?;
-    out_file.write_all(asm.as_bytes())?;
+fn append_snek_invalid_at(ctx: &Ctx, lab: &str, site: i64, operands: Operands) {
+    append_snek_error_at(ctx, lab, 1, site, operands);
+}
 
-    Ok(())
+fn append_snek_overflow_at(ctx: &Ctx, lab: &str, site: i64) {
+    append_snek_error_at(ctx, lab, 2, site, Operands::None);
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn append_snek_div_zero_at(ctx: &Ctx, lab: &str, site: i64) {
+    append_snek_error_at(ctx, lab, 3, site, Operands::None);
+}
 
-    fn parse_prog(src: &str) -> Program {
-        parse_program(&parse(src).unwrap())
+/// The shared tails in `ERROR_TAILS` that stubs in `code` jump to: each
+/// passes the error code, the frame pointer and the operands to `snek_error`.
+fn append_error_tails(code: &mut Vec<Instr>) {
+    let used: HashSet<String> = code
+        .iter()
+        .filter_map(|i| match i {
+            Instr::IJmp(target) if is_error_tail(target) => Some(target.clone()),
+            _ => None,
+        })
+        .collect();
+    for (tail, errcode, operands) in ERROR_TAILS {
+        if !used.contains(tail) {
+            continue;
+        }
+        code.push(Instr::ILabel(tail.to_string()));
+        code.push(Instr::IMov(reg(Reg::Rdi), Val::Imm(errcode)));
+        code.push(Instr::IMov(reg(Reg::Rdx), reg(Reg::Rbp)));
+        match operands {
+            Operands::None => {}
+            Operands::Unary => code.push(Instr::IMov(reg(Reg::Rcx), reg(Reg::Rax))),
+            Operands::Binary => code.push(Instr::IMov(reg(Reg::R8), reg(Reg::Rax))),
+        }
+        code.push(Instr::ICall("snek_error".to_string()));
     }
+}
 
-    fn compile_src(src: &str) -> String {
-        compile_program(&parse_prog(src))
-    }
+// Operation codes understood by `snek_arith` in runtime/start.rs.
+const ARITH_PLUS: i64 = 0;
+const ARITH_MINUS: i64 = 1;
+const ARITH_TIMES: i64 = 2;
+const ARITH_DIVIDE: i64 = 3;
+const ARITH_LESS: i64 = 4;
+const ARITH_GREATER: i64 = 5;
+const ARITH_LESS_EQ: i64 = 6;
+const ARITH_GREATER_EQ: i64 = 7;
+const ARITH_EQUAL: i64 = 8;
 
-    #[test]
-    fn parse_single_def_and_main() {
-        let p = parse_prog("((fun (id x) x) (id 5))");
-        assert_eq!(p.defns.len(), 1);
-    }
+/// Compares the low bits of `r`, masked with `mask`, with `bits`, so that
+/// `E` holds when they match. Clobbers `r11`.
+fn append_tag_test(code: &mut Vec<Instr>, r: Reg, mask: i64, bits: i64) {
+    code.push(Instr::IMov(reg(Reg::R11), reg(r)));
+    code.push(Instr::IAnd(reg(Reg::R11), Val::Imm(mask)));
+    code.push(Instr::ICmp(reg(Reg::R11), Val::Imm(bits)));
+}
 
-    #[test]
-    fn parse_call_with_zero_args() {
-        let p = parse_prog("((fun (forty_two) 42) (forty_two))");
-        assert!(matches!(p.main, Expr::Call(_, _)));
-    }
+/// Jumps to `target` when the low bits of `r`, masked with `mask`, equal
+/// `bits` (`jcc` is `E`) or differ from them (`Ne`). Clobbers `r11`.
+fn append_tag_check(code: &mut Vec<Instr>, r: Reg, mask: i64, bits: i64, jcc: Cond, target: &str) {
+    append_tag_test(code, r, mask, bits);
+    code.push(Instr::IJcc(jcc, target.to_string()));
+}
 
-    #[test]
-    fn parse_call_with_five_args() {
-        let p = parse_prog("((fun (sum5 a b c d e) (+ a (+ b (+ c (+ d e))))) (sum5 1 2 3 4 5))");
-        assert!(matches!(p.main, Expr::Call(_, _)));
-    }
+fn append_bool_guard(code: &mut Vec<Instr>, r: Reg, bad: &str) {
+    append_tag_check(code, r, 5, 1, Cond::E, bad);
+}
 
-    #[test]
-    fn simple_function_call_emits_call_label() {
-        let asm = compile_src("((fun (inc x) (add1 x)) (inc 4))");
-        assert!(asm.contains("call fun_inc"));
+/// Slow path taken when an operand of a binary operator is not an integer.
+/// Booleans are rejected here, unless the operand is known to be a number;
+/// any int/float mix is handed to `snek_arith` with the left operand in
+/// `left` and the right operand in `rax`. The result falls through in `rax`
+/// to the code after the operation.
+fn append_binary_slow_path(
+    code: &mut Vec<Instr>,
+    (slow, bad): (&str, &str),
+    arith: i64,
+    left: &Val,
+    (lt, rt): (Ty, Ty),
+) {
+    code.push(Instr::ILabel(slow.to_string()));
+    // The error stub at `bad` expects the left operand in `rcx`.
+    code.push(Instr::IMov(reg(Reg::Rcx), left.clone()));
+    if !rt.is_num() {
+        append_bool_guard(code, Reg::Rax, bad);
+    }
+    if !lt.is_num() {
+        append_bool_guard(code, Reg::Rcx, bad);
     }
+    code.push(Instr::IMov(reg(Reg::Rsi), reg(Reg::Rcx)));
+    code.push(Instr::IMov(reg(Reg::Rdi), Val::Imm(arith)));
+    code.push(Instr::IMov(reg(Reg::Rdx), reg(Reg::Rax)));
+    code.push(Instr::ICall("snek_arith".to_string()));
+}
 
-    #[test]
-    fn zero_arg_call_emits_no_cleanup() {
-        let asm = compile_src("((fun (f) 7) (f))");
-        assert!(!asm.contains("add rsp, 0"));
+/// Slow path for `add1`/`sub1`/`negate`: combines `rax` with a tagged constant,
+/// falling through with the result like `append_binary_slow_path`.
+fn append_unary_slow_path(
+    code: &mut Vec<Instr>,
+    (slow, bad): (&str, &str),
+    arith: i64,
+    operand: i64,
+    ty: Ty,
+) {
+    code.push(Instr::ILabel(slow.to_string()));
+    if !ty.is_num() {
+        append_bool_guard(code, Reg::Rax, bad);
     }
+    code.push(Instr::IMov(reg(Reg::Rdi), Val::Imm(arith)));
+    code.push(Instr::IMov(reg(Reg::Rsi), reg(Reg::Rax)));
+    code.push(Instr::IMov(reg(Reg::Rdx), Val::Imm(operand)));
+    code.push(Instr::ICall("snek_arith".to_string()));
+}
 
-    #[test]
-    fn one_arg_call_cleans_up_and_aligns() {
+fn float_label(f: f64) -> String {
+    format!("flt_{:016x}", f.to_bits())
+}
+
+/// Untags both operands of a comparison into `rdi` and `rsi`, branching to
+/// the returned slow-path label when one may not be an integer.
+fn append_two_num_checks(
+    left: &Val,
+    code: &mut Vec<Instr>,
+    seq: &mut i32,
+    (lt, rt): (Ty, Ty),
+) -> String {
+    let slow = mk_label(seq, "slow");
+    if rt != Ty::Int {
+        append_tag_check(code, Reg::Rax, 1, 0, Cond::Ne, &slow);
+    }
+    code.push(Instr::IMov(reg(Reg::Rcx), left.clone()));
+    if lt != Ty::Int {
+        code.push(Instr::ITest(reg(Reg::Rcx), Val::Imm(1)));
+        code.push(Instr::IJcc(Cond::Ne, slow.clone()));
+    }
+    code.push(Instr::IMov(reg(Reg::Rdi), reg(Reg::Rcx)));
+    code.push(Instr::ISar(reg(Reg::Rdi), Val::Imm(1)));
+    code.push(Instr::IMov(reg(Reg::Rsi), reg(Reg::Rax)));
+    code.push(Instr::ISar(reg(Reg::Rsi), Val::Imm(1)));
+    slow
+}
+
+/// Where a comparison or tag test sends its outcome.
+#[derive(Debug, Clone, Copy)]
+enum Dest<'a> {
+    /// Into `rax`, as a Snek boolean.
+    Value,
+    /// To `then` when the condition holds and to `els` when it fails.
+    /// `next` is whichever of them directly follows, if either does, so the
+    /// code going on to it in line falls through.
+    Branch { then: &'a str, els: &'a str, next: Option<&'a str> },
+}
+
+/// Sends condition `c` of the last comparison to `dest`: a `cmov` of the
+/// Snek boolean into `rax`, which clobbers `r11`, or a conditional jump.
+fn append_condition(code: &mut Vec<Instr>, c: Cond, dest: Dest) {
+    match dest {
+        Dest::Value => {
+            code.push(Instr::IMov(reg(Reg::Rax), Val::Imm(1)));
+            code.push(Instr::IMov(reg(Reg::R11), Val::Imm(3)));
+            code.push(Instr::ICmov(c, reg(Reg::Rax), reg(Reg::R11)));
+        }
+        Dest::Branch { then, els, next } => append_branch(code, c, (then, els), next),
+    }
+}
+
+/// Jumps to `then` if condition `c` holds and to `els` otherwise, leaving
+/// out the jump to `next`.
+fn append_branch(code: &mut Vec<Instr>, c: Cond, (then, els): (&str, &str), next: Option<&str>) {
+    if next == Some(els) {
+        code.push(Instr::IJcc(c, then.to_string()));
+    } else {
+        code.push(Instr::IJcc(c.negate(), els.to_string()));
+        if next != Some(then) {
+            code.push(Instr::IJmp(then.to_string()));
+        }
+    }
+}
+
+/// Places `slow`, the slow path of a condition, which ends with the Snek
+/// boolean in `rax`. For a value it follows the fast path, which jumps over
+/// it to `done`. For a branch it goes out of line with the error stubs and
+/// branches on `rax` itself.
+fn append_slow_condition(
+    code: &mut Vec<Instr>,
+    mut slow: Vec<Instr>,
+    dest: Dest,
+    ctx: &Ctx,
+    done: &str,
+) {
+    match dest {
+        Dest::Value => {
+            code.push(Instr::IJmp(done.to_string()));
+            code.extend(slow);
+            code.push(Instr::ILabel(done.to_string()));
+        }
+        Dest::Branch { then, els, .. } => {
+            slow.push(Instr::ICmp(reg(Reg::Rax), Val::Imm(1)));
+            slow.push(Instr::IJcc(Cond::E, els.to_string()));
+            slow.push(Instr::IJmp(then.to_string()));
+            ctx.stubs.borrow_mut().extend(slow);
+        }
+    }
+}
+
+fn store_slot(off: i32) -> Instr {
+    Instr::IMov(slot(off), reg(Reg::Rax))
+}
+
+/// What is known about the tag of a value. `Num` is an integer or a float.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ty {
+    Int,
+    Float,
+    Num,
+    Bool,
+    Any,
+}
+
+impl Ty {
+    fn is_num(self) -> bool {
+        matches!(self, Ty::Int | Ty::Float | Ty::Num)
+    }
+
+    /// What is known about a value that has one of two types.
+    fn join(self, other: Ty) -> Ty {
+        if self == other {
+            self
+        } else if self.is_num() && other.is_num() {
+            Ty::Num
+        } else {
+            Ty::Any
+        }
+    }
+
+    /// Result of `+`, `-`, `*` or `/` when it does not raise an error.
+    fn arith(self, other: Ty) -> Ty {
+        if self == Ty::Int && other == Ty::Int {
+            Ty::Int
+        } else {
+            Ty::Num
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum GlobalBinding {
+    Mutable,
+    Const(Expr),
+}
+
+#[derive(Clone, Copy)]
+struct Ctx<'a> {
+    arities: &'a HashMap<String, usize>,
+    /// Functions declared with `extern`, called with the System V convention.
+    externs: &'a HashSet<String>,
+    globals: &'a HashMap<String, GlobalBinding>,
+    /// Parameters of the function being compiled; none for main.
+    params: &'a [String],
+    /// Deepest stack slot the code emitted so far uses, in bytes below `rbp`.
+    frame: &'a Cell<i32>,
+    /// Return-address label and source position of every call emitted so far.
+    call_sites: &'a RefCell<Vec<(String, Pos)>>,
+    /// Every fallible operation emitted so far; error stubs pass their index.
+    error_sites: &'a RefCell<Vec<ErrorSite>>,
+    /// Error stubs of the function being compiled, placed after its epilogue
+    /// so the code that does not fail runs straight through.
+    stubs: &'a RefCell<Vec<Instr>>,
+    /// Omit tag checks that known types make redundant (`-O1` and above).
+    use_types: bool,
+    /// Remove unreachable blocks, hoist loop invariants, and share repeated
+    /// and drop dead computations (`-O1` and above).
+    prune: bool,
+    /// Functions whose repeated calls with the same arguments may be merged;
+    /// empty when profiling, which counts every call.
+    pure: &'a HashSet<String>,
+    /// Pass arguments to Snek functions in registers, as for `extern`
+    /// functions, instead of all on the stack (`--sysv-calls`).
+    sysv_calls: bool,
+}
+
+impl Ctx<'_> {
+    /// The stack slot `[rbp - depth]`, counted in the frame size.
+    fn claim_slot(&self, depth: i32) -> Val {
+        self.frame.set(self.frame.get().max(depth));
+        slot(depth)
+    }
+
+    /// `ty`, if code may rely on it.
+    fn known(&self, ty: Ty) -> Ty {
+        if self.use_types {
+            ty
+        } else {
+            Ty::Any
+        }
+    }
+}
+
+fn global_label(name: &str) -> String {
+    format!("glob_{}", name)
+}
+
+/// A variable after lowering to A-normal form. `let` bindings are numbered in
+/// the order they are lowered, so shadowing and hoisting never confuse two of
+/// them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Var {
+    Local(usize),
+    /// The i-th parameter of the enclosing function.
+    Param(usize),
+    /// A mutable global; constants are replaced by their literal.
+    Global(String),
+}
+
+/// An operand in A-normal form.
+#[derive(Debug, Clone, PartialEq)]
+enum Imm {
+    Num(i32),
+    Bool(bool),
+    Float(f64),
+    Var(Var),
+}
+
+/// A computation whose operands are all immediates. The condition of an `if`
+/// is itself a computation, so code generation can see what it tests.
+#[derive(Debug, Clone, PartialEq)]
+enum CExpr {
+    Imm(Imm),
+    Input,
+    Argc,
+    Read(ReadKind, Pos),
+    UnOp(UnOp, Imm, Pos),
+    BinOp(BinOp, Imm, Imm, Pos),
+    Call(String, Vec<Imm>, Pos),
+    If(Box<CExpr>, Box<AExpr>, Box<AExpr>),
+    Loop(Box<AExpr>),
+    Break(Imm),
+    Set(Var, Imm),
+}
+
+/// An expression in A-normal form: bindings evaluated in order, then a
+/// computation giving the value.
+#[derive(Debug, Clone, PartialEq)]
+enum AExpr {
+    Let(usize, CExpr, Box<AExpr>),
+    /// Evaluates the first expression for its effects; its bindings end with it.
+    Seq(Box<AExpr>, Box<AExpr>),
+    Ret(CExpr),
+}
+
+/// What lowering an operand puts before the computation that uses it.
+enum Binding {
+    Let(usize, CExpr),
+    Do(AExpr),
+}
+
+fn wrap_bindings(bindings: Vec<Binding>, last: CExpr) -> AExpr {
+    bindings.into_iter().rev().fold(AExpr::Ret(last), |body, b| match b {
+        Binding::Let(id, c) => AExpr::Let(id, c, Box::new(body)),
+        Binding::Do(a) => AExpr::Seq(Box::new(a), Box::new(body)),
+    })
+}
+
+/// Calls `f` on every computation in `a`, including those nested in `if`
+/// and `loop`.
+fn visit_cexprs(a: &AExpr, f: &mut impl FnMut(&CExpr)) {
+    match a {
+        AExpr::Let(_, c, body) => {
+            visit_cexpr(c, f);
+            visit_cexprs(body, f);
+        }
+        AExpr::Seq(first, rest) => {
+            visit_cexprs(first, f);
+            visit_cexprs(rest, f);
+        }
+        AExpr::Ret(c) => visit_cexpr(c, f),
+    }
+}
+
+fn visit_cexpr(c: &CExpr, f: &mut impl FnMut(&CExpr)) {
+    f(c);
+    match c {
+        CExpr::If(cond, t, e) => {
+            visit_cexpr(cond, f);
+            visit_cexprs(t, f);
+            visit_cexprs(e, f);
+        }
+        CExpr::Loop(body) => visit_cexprs(body, f),
+        _ => {}
+    }
+}
+
+/// Whether running `bindings` may change `v`: a `set!` of it, or for a global
+/// any call, since functions may set globals.
+fn bindings_may_write(bindings: &[Binding], v: &Var) -> bool {
+    let mut writes = false;
+    let mut check = |c: &CExpr| match c {
+        CExpr::Set(target, _) if target == v => writes = true,
+        CExpr::Call(..) if matches!(v, Var::Global(_)) => writes = true,
+        _ => {}
+    };
+    for b in bindings {
+        match b {
+            Binding::Let(_, c) => visit_cexpr(c, &mut check),
+            Binding::Do(a) => visit_cexprs(a, &mut check),
+        }
+    }
+    writes
+}
+
+/// Lowers one function body, or main and the global initializers, to
+/// A-normal form, resolving names. `validate_program` has already reported
+/// unbound variables, bad `set!` targets, misplaced `break`s and bad calls.
+struct Lowering<'a> {
+    ctx: Ctx<'a>,
+    next_local: usize,
+}
+
+/// `let`-bound names in scope and their locals.
+type Scope = HashMap<String, usize>;
+
+impl Lowering<'_> {
+    fn fresh(&mut self) -> usize {
+        self.next_local += 1;
+        self.next_local - 1
+    }
+
+    /// The operand `name` stands for; constants become their literal.
+    fn resolve(&self, name: &str, scope: &Scope) -> Imm {
+        if let Some(v) = self.resolve_target(name, scope) {
+            return Imm::Var(v);
+        }
+        match self.ctx.globals.get(name) {
+            Some(GlobalBinding::Const(Expr::Num(n))) => Imm::Num(*n),
+            Some(GlobalBinding::Const(Expr::Bool(b))) => Imm::Bool(*b),
+            Some(GlobalBinding::Const(Expr::Float(f))) => Imm::Float(*f),
+            _ => panic!("Unbound variable: {}", name),
+        }
+    }
+
+    fn lower(&mut self, e: &Expr, scope: &Scope, in_loop: bool) -> AExpr {
+        let mut bindings = Vec::new();
+        let last = self.lower_cexpr(e, scope, in_loop, &mut bindings);
+        wrap_bindings(bindings, last)
+    }
+
+    /// Lowers `e` to a computation, appending the bindings it needs first.
+    fn lower_cexpr(
+        &mut self,
+        e: &Expr,
+        scope: &Scope,
+        in_loop: bool,
+        out: &mut Vec<Binding>,
+    ) -> CExpr {
+        match e {
+            Expr::Num(n) => CExpr::Imm(Imm::Num(*n)),
+            Expr::Bool(b) => CExpr::Imm(Imm::Bool(*b)),
+            Expr::Float(f) => CExpr::Imm(Imm::Float(*f)),
+            Expr::Input => CExpr::Input,
+            Expr::Argc => CExpr::Argc,
+            Expr::Read(kind, pos) => CExpr::Read(*kind, *pos),
+            Expr::Var(name) => CExpr::Imm(self.resolve(name, scope)),
+            Expr::Let(bindings, body) => {
+                let mut inner = scope.clone();
+                for (name, rhs) in bindings {
+                    if self.ctx.params.contains(name) {
+                        panic!("Cannot shadow parameter with let: {}", name);
+                    }
+                    let value = self.lower_cexpr(rhs, &inner, in_loop, out);
+                    let id = self.fresh();
+                    out.push(Binding::Let(id, value));
+                    inner.insert(name.clone(), id);
+                }
+                self.lower_cexpr(body, &inner, in_loop, out)
+            }
+            Expr::UnOp(op, sub, pos) => {
+                let operand = self.lower_imm(sub, scope, in_loop, out);
+                CExpr::UnOp(op.clone(), operand, *pos)
+            }
+            Expr::BinOp(op, e1, e2, pos) => {
+                let mut operands = self.lower_operands(&[e1, e2], scope, in_loop, out);
+                let right = operands.pop().unwrap();
+                let left = operands.pop().unwrap();
+                CExpr::BinOp(op.clone(), left, right, *pos)
+            }
+            Expr::If(cond, th, el) => {
+                let cond = self.lower_cexpr(cond, scope, in_loop, out);
+                let th = self.lower(th, scope, in_loop);
+                let el = self.lower(el, scope, in_loop);
+                CExpr::If(Box::new(cond), Box::new(th), Box::new(el))
+            }
+            Expr::Block(items) => {
+                let (last, init) = items.split_last().unwrap_or_else(|| panic!("empty block"));
+                for item in init {
+                    out.push(Binding::Do(self.lower(item, scope, in_loop)));
+                }
+                self.lower_cexpr(last, scope, in_loop, out)
+            }
+            Expr::Loop(body) => CExpr::Loop(Box::new(self.lower(body, scope, true))),
+            Expr::Break(inner) => {
+                if !in_loop {
+                    panic!("break outside of loop");
+                }
+                CExpr::Break(self.lower_imm(inner, scope, in_loop, out))
+            }
+            Expr::Set(name, rhs) => {
+                let target = match self.resolve_target(name, scope) {
+                    Some(v) => v,
+                    None => match self.ctx.globals.get(name) {
+                        Some(GlobalBinding::Const(_)) => panic!("Cannot set! constant: {}", name),
+                        _ => panic!("set! on unknown binding: {}", name),
+                    },
+                };
+                CExpr::Set(target, self.lower_imm(rhs, scope, in_loop, out))
+            }
+            Expr::Call(name, args, pos) => {
+                let expected = match self.ctx.arities.get(name) {
+                    Some(arity) => *arity,
+                    None => panic!("Undefined function: {}", name),
+                };
+                if expected != args.len() {
+                    panic!(
+                        "Wrong number of arguments in call to {}: expected {}, got {}",
+                        name,
+                        expected,
+                        args.len()
+                    );
+                }
+                let args: Vec<&Expr> = args.iter().collect();
+                let operands = self.lower_operands(&args, scope, in_loop, out);
+                CExpr::Call(name.clone(), operands, *pos)
+            }
+        }
+    }
+
+    /// The variable `set!` of `name` assigns, if it may be assigned.
+    fn resolve_target(&self, name: &str, scope: &Scope) -> Option<Var> {
+        if let Some(id) = scope.get(name) {
+            return Some(Var::Local(*id));
+        }
+        if let Some(i) = self.ctx.params.iter().position(|p| p == name) {
+            return Some(Var::Param(i));
+        }
+        match self.ctx.globals.get(name) {
+            Some(GlobalBinding::Mutable) => Some(Var::Global(name.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Lowers `e` to an operand, binding it to a new local unless it already
+    /// is one.
+    fn lower_imm(&mut self, e: &Expr, scope: &Scope, in_loop: bool, out: &mut Vec<Binding>) -> Imm {
+        match self.lower_cexpr(e, scope, in_loop, out) {
+            CExpr::Imm(imm) => imm,
+            other => {
+                let id = self.fresh();
+                out.push(Binding::Let(id, other));
+                Imm::Var(Var::Local(id))
+            }
+        }
+    }
+
+    /// Lowers operands evaluated left to right. A variable operand is copied
+    /// when a later operand may change it, so it keeps the value it had when
+    /// it was evaluated.
+    fn lower_operands(
+        &mut self,
+        es: &[&Expr],
+        scope: &Scope,
+        in_loop: bool,
+        out: &mut Vec<Binding>,
+    ) -> Vec<Imm> {
+        let mut lowered = Vec::new();
+        for e in es {
+            let mut bindings = Vec::new();
+            let imm = self.lower_imm(e, scope, in_loop, &mut bindings);
+            lowered.push((bindings, imm));
+        }
+        let mut operands = Vec::new();
+        for i in 0..lowered.len() {
+            let (head, tail) = lowered.split_at_mut(i + 1);
+            let (bindings, imm) = &mut head[i];
+            out.append(bindings);
+            let clobbered = match imm {
+                Imm::Var(v) => tail.iter().any(|(later, _)| bindings_may_write(later, v)),
+                _ => false,
+            };
+            if clobbered {
+                let id = self.fresh();
+                out.push(Binding::Let(id, CExpr::Imm(imm.clone())));
+                *imm = Imm::Var(Var::Local(id));
+            }
+            operands.push(imm.clone());
+        }
+        operands
+    }
+}
+
+/// `e`, the body of a function with `ctx.params` or main, in A-normal form.
+fn lower_expr(e: &Expr, ctx: &Ctx) -> AExpr {
+    let mut lowering = Lowering { ctx: *ctx, next_local: 0 };
+    lowering.lower(e, &Scope::new(), false)
+}
+
+/// Number of an SSA value: the result of one instruction or phi of a `Cfg`.
+type ValueId = usize;
+
+/// Index of a basic block in `Cfg::blocks`.
+type BlockId = usize;
+
+/// An operand in SSA form.
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Num(i32),
+    Bool(bool),
+    Float(f64),
+    Value(ValueId),
+    /// The i-th parameter as it was passed; a `set!` of it defines a new value.
+    Param(usize),
+    /// A mutable global, read when the instruction using it runs.
+    Global(String),
+}
+
+/// A computation in a basic block.
+#[derive(Debug, Clone, PartialEq)]
+enum Operation {
+    /// The operand's current value; a global is read at this point.
+    Copy(Operand),
+    Input,
+    Argc,
+    Read(ReadKind, Pos),
+    UnOp(UnOp, Operand, Pos),
+    BinOp(BinOp, Operand, Operand, Pos),
+    Call(String, Vec<Operand>, Pos),
+    /// The i-th parameter as passed in the i-th System V argument register;
+    /// defined at the start of the entry block, before any call clobbers it.
+    RegParam(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Inst {
+    /// Defines a value as the result of an operation.
+    Def(ValueId, Operation),
+    SetGlobal(String, Operand),
+}
+
+/// A value that depends on which predecessor control came from.
+#[derive(Debug, Clone, PartialEq)]
+struct Phi {
+    dest: ValueId,
+    incoming: Vec<(BlockId, Operand)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Terminator {
+    Jump(BlockId),
+    /// To the first block unless the operand is `false`.
+    Branch(Operand, BlockId, BlockId),
+    Return(Operand),
+    /// The block is still being built.
+    Pending,
+}
+
+/// A tag test, `(isnum x)`, `(isbool x)` or `(isfloat x)`, that holds or
+/// fails whenever a block runs.
+#[derive(Debug, Clone, PartialEq)]
+struct Guard {
+    operand: Operand,
+    test: UnOp,
+    holds: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Block {
+    /// Stem of the block's label.
+    stem: &'static str,
+    guards: Vec<Guard>,
+    phis: Vec<Phi>,
+    insts: Vec<Inst>,
+    term: Terminator,
+}
+
+/// A function body, or main or a global initializer, as a control-flow graph
+/// in SSA form. Block 0 is the entry, and blocks are in code layout order.
+#[derive(Debug, Clone, PartialEq)]
+struct Cfg {
+    blocks: Vec<Block>,
+    /// Number of values defined, each numbered below it.
+    values: usize,
+}
+
+fn successors(term: &Terminator) -> Vec<BlockId> {
+    match term {
+        Terminator::Jump(target) => vec![*target],
+        Terminator::Branch(_, then_block, else_block) => vec![*then_block, *else_block],
+        Terminator::Return(_) | Terminator::Pending => vec![],
+    }
+}
+
+fn inst_operands(inst: &Inst) -> Vec<&Operand> {
+    match inst {
+        Inst::Def(_, op) => match op {
+            Operation::Copy(o) | Operation::UnOp(_, o, _) => vec![o],
+            Operation::BinOp(_, l, r, _) => vec![l, r],
+            Operation::Call(_, args, _) => args.iter().collect(),
+            Operation::Input | Operation::Argc | Operation::Read(..) | Operation::RegParam(_) => {
+                vec![]
+            }
+        },
+        Inst::SetGlobal(_, o) => vec![o],
+    }
+}
+
+fn term_operand(term: &Terminator) -> Option<&Operand> {
+    match term {
+        Terminator::Branch(o, _, _) | Terminator::Return(o) => Some(o),
+        Terminator::Jump(_) | Terminator::Pending => None,
+    }
+}
+
+fn for_each_op_operand_mut(op: &mut Operation, f: &mut impl FnMut(&mut Operand)) {
+    match op {
+        Operation::Copy(o) | Operation::UnOp(_, o, _) => f(o),
+        Operation::BinOp(_, l, r, _) => {
+            f(l);
+            f(r);
+        }
+        Operation::Call(_, args, _) => args.iter_mut().for_each(f),
+        Operation::Input | Operation::Argc | Operation::Read(..) | Operation::RegParam(_) => {}
+    }
+}
+
+/// Calls `f` on every operand in `cfg`, guards included.
+fn for_each_operand_mut(cfg: &mut Cfg, f: &mut impl FnMut(&mut Operand)) {
+    for block in &mut cfg.blocks {
+        for guard in &mut block.guards {
+            f(&mut guard.operand);
+        }
+        for phi in &mut block.phis {
+            for (_, o) in &mut phi.incoming {
+                f(o);
+            }
+        }
+        for inst in &mut block.insts {
+            match inst {
+                Inst::Def(_, op) => for_each_op_operand_mut(op, f),
+                Inst::SetGlobal(_, o) => f(o),
+            }
+        }
+        if let Terminator::Branch(o, _, _) | Terminator::Return(o) = &mut block.term {
+            f(o);
+        }
+    }
+}
+
+/// Variables in scope and the operand each holds at the current point.
+type Env = BTreeMap<Var, Operand>;
+
+/// Where control leaves for a join: the block, the variables, and the value.
+type Arrival = (BlockId, Env, Operand);
+
+/// Builds a `Cfg` from A-normal form. Blocks are numbered as they are
+/// created, which may be before the code that jumps to them is built, and
+/// laid out in the order they are started.
+struct CfgBuilder {
+    blocks: Vec<Block>,
+    order: Vec<BlockId>,
+    current: BlockId,
+    values: usize,
+    env: Env,
+    guards: Vec<Guard>,
+    /// Exit block of each enclosing loop and the `break`s that reach it.
+    loops: Vec<(BlockId, Vec<Arrival>)>,
+}
+
+impl CfgBuilder {
+    fn block(&mut self, stem: &'static str) -> BlockId {
+        self.blocks.push(Block {
+            stem,
+            guards: Vec::new(),
+            phis: Vec::new(),
+            insts: Vec::new(),
+            term: Terminator::Pending,
+        });
+        self.blocks.len() - 1
+    }
+
+    fn start(&mut self, id: BlockId) {
+        self.current = id;
+        self.order.push(id);
+        self.blocks[id].guards = self.guards.clone();
+    }
+
+    fn finish(&mut self, term: Terminator) {
+        self.blocks[self.current].term = term;
+    }
+
+    fn fresh(&mut self) -> ValueId {
+        self.values += 1;
+        self.values - 1
+    }
+
+    fn def(&mut self, op: Operation) -> Operand {
+        let v = self.fresh();
+        self.blocks[self.current].insts.push(Inst::Def(v, op));
+        Operand::Value(v)
+    }
+
+    fn operand(&self, imm: &Imm) -> Operand {
+        match imm {
+            Imm::Num(n) => Operand::Num(*n),
+            Imm::Bool(b) => Operand::Bool(*b),
+            Imm::Float(f) => Operand::Float(*f),
+            Imm::Var(Var::Global(name)) => Operand::Global(name.clone()),
+            Imm::Var(v) => self.env[v].clone(),
+        }
+    }
+
+    /// `o`, read now if it is a global, so it can be used later.
+    fn snapshot(&mut self, o: Operand) -> Operand {
+        match o {
+            Operand::Global(_) => self.def(Operation::Copy(o)),
+            _ => o,
+        }
+    }
+
+    /// The operand control brings to `block` from each of `incoming`: the
+    /// operand itself if they all agree, otherwise a new phi.
+    fn join(&mut self, block: BlockId, incoming: Vec<(BlockId, Operand)>) -> Operand {
+        if incoming.iter().all(|(_, o)| *o == incoming[0].1) {
+            return incoming[0].1.clone();
+        }
+        let dest = self.fresh();
+        self.blocks[block].phis.push(Phi { dest, incoming });
+        Operand::Value(dest)
+    }
+
+    /// Joins each variable of `scope` as it arrives at `block`, and the values.
+    fn join_arrivals(&mut self, block: BlockId, scope: &Env, arrivals: &[Arrival]) -> Operand {
+        for v in scope.keys() {
+            let incoming = arrivals.iter().map(|(from, env, _)| (*from, env[v].clone())).collect();
+            let joined = self.join(block, incoming);
+            self.env.insert(v.clone(), joined);
+        }
+        let values = arrivals.iter().map(|(from, _, value)| (*from, value.clone())).collect();
+        self.join(block, values)
+    }
+
+    fn lower(&mut self, a: &AExpr) -> Operand {
+        match a {
+            AExpr::Let(id, value, body) => {
+                let value = self.lower_cexpr(value);
+                let value = self.snapshot(value);
+                self.env.insert(Var::Local(*id), value);
+                let result = self.lower(body);
+                self.env.remove(&Var::Local(*id));
+                result
+            }
+            AExpr::Seq(first, rest) => {
+                self.lower(first);
+                self.lower(rest)
+            }
+            AExpr::Ret(c) => self.lower_cexpr(c),
+        }
+    }
+
+    fn lower_cexpr(&mut self, c: &CExpr) -> Operand {
+        match c {
+            CExpr::Imm(imm) => self.operand(imm),
+            CExpr::Input => self.def(Operation::Input),
+            CExpr::Argc => self.def(Operation::Argc),
+            CExpr::Read(kind, pos) => self.def(Operation::Read(*kind, *pos)),
+            CExpr::UnOp(op, operand, pos) => {
+                let operand = self.operand(operand);
+                self.def(Operation::UnOp(op.clone(), operand, *pos))
+            }
+            CExpr::BinOp(op, l, r, pos) => {
+                let (l, r) = (self.operand(l), self.operand(r));
+                self.def(Operation::BinOp(op.clone(), l, r, *pos))
+            }
+            CExpr::Call(name, args, pos) => {
+                let args = args.iter().map(|a| self.operand(a)).collect();
+                self.def(Operation::Call(name.clone(), args, *pos))
+            }
+            CExpr::Set(Var::Global(name), value) => {
+                let value = self.operand(value);
+                let set = Inst::SetGlobal(name.clone(), value.clone());
+                self.blocks[self.current].insts.push(set);
+                value
+            }
+            CExpr::Set(v, value) => {
+                let value = self.operand(value);
+                let value = self.snapshot(value);
+                self.env.insert(v.clone(), value.clone());
+                value
+            }
+            CExpr::If(cond, th, el) => self.lower_if(cond, th, el),
+            CExpr::Loop(body) => self.lower_loop(body),
+            CExpr::Break(value) => {
+                let value = self.operand(value);
+                let value = self.snapshot(value);
+                let arrival = (self.current, self.env.clone(), value);
+                let (exit, breaks) = self.loops.last_mut().expect("break outside of loop");
+                breaks.push(arrival);
+                let exit = *exit;
+                self.finish(Terminator::Jump(exit));
+                // Code after a `break` goes in a block no jump reaches.
+                let dead = self.block("dead");
+                self.start(dead);
+                Operand::Num(0)
+            }
+        }
+    }
+
+    fn lower_if(&mut self, cond: &CExpr, th: &AExpr, el: &AExpr) -> Operand {
+        let test = self.lower_cexpr(cond);
+        let guard = match cond {
+            CExpr::UnOp(op @ (UnOp::IsNum | UnOp::IsBool | UnOp::IsFloat), operand, _) => {
+                match self.operand(operand) {
+                    o @ (Operand::Value(_) | Operand::Param(_)) => Some((o, op.clone())),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let then_block = self.block("if_then");
+        let else_block = self.block("if_alt");
+        self.finish(Terminator::Branch(test, then_block, else_block));
+        let scope = self.env.clone();
+        let mut arrivals = Vec::new();
+        for (block, branch, holds) in [(then_block, th, true), (else_block, el, false)] {
+            self.env = scope.clone();
+            if let Some((operand, test)) = &guard {
+                let (operand, test) = (operand.clone(), test.clone());
+                self.guards.push(Guard { operand, test, holds });
+            }
+            self.start(block);
+            let value = self.lower(branch);
+            let value = self.snapshot(value);
+            arrivals.push((self.current, self.env.clone(), value));
+            if guard.is_some() {
+                self.guards.pop();
+            }
+        }
+        let done = self.block("if_done");
+        for (from, _, _) in &arrivals {
+            self.blocks[*from].term = Terminator::Jump(done);
+        }
+        self.start(done);
+        self.join_arrivals(done, &scope, &arrivals)
+    }
+
+    /// Variables the body assigns get a phi at the head of the loop, joining
+    /// their values on entry and at the end of each iteration.
+    fn lower_loop(&mut self, body: &AExpr) -> Operand {
+        let head = self.block("lp_h");
+        let exit = self.block("lp_t");
+        let entry = self.current;
+        self.finish(Terminator::Jump(head));
+        let mut assigned = BTreeSet::new();
+        visit_cexprs(body, &mut |c| {
+            if let CExpr::Set(v, _) = c {
+                assigned.insert(v.clone());
+            }
+        });
+        self.start(head);
+        let carried: Vec<Var> = assigned.into_iter().filter(|v| self.env.contains_key(v)).collect();
+        for v in &carried {
+            let dest = self.fresh();
+            let incoming = vec![(entry, self.env[v].clone())];
+            self.blocks[head].phis.push(Phi { dest, incoming });
+            self.env.insert(v.clone(), Operand::Value(dest));
+        }
+        let scope = self.env.clone();
+        self.loops.push((exit, Vec::new()));
+        self.lower(body);
+        self.finish(Terminator::Jump(head));
+        for (i, v) in carried.iter().enumerate() {
+            let back = (self.current, self.env[v].clone());
+            self.blocks[head].phis[i].incoming.push(back);
+        }
+        let (_, breaks) = self.loops.pop().unwrap();
+        self.start(exit);
+        self.env = scope.clone();
+        if breaks.is_empty() {
+            // Nothing reaches the exit of a loop that never breaks.
+            return Operand::Num(0);
+        }
+        self.join_arrivals(exit, &scope, &breaks)
+    }
+}
+
+/// Keeps the blocks in `order`, renumbered by their position in it. Phi
+/// inputs from blocks left out are dropped.
+fn reorder_blocks(cfg: &mut Cfg, order: &[BlockId]) {
+    let index: HashMap<BlockId, BlockId> =
+        order.iter().enumerate().map(|(new, old)| (*old, new)).collect();
+    let mut old: Vec<Option<Block>> = cfg.blocks.drain(..).map(Some).collect();
+    for id in order {
+        let mut block = old[*id].take().unwrap();
+        block.term = match block.term {
+            Terminator::Jump(t) => Terminator::Jump(index[&t]),
+            Terminator::Branch(o, t, e) => Terminator::Branch(o, index[&t], index[&e]),
+            other => other,
+        };
+        for phi in &mut block.phis {
+            phi.incoming = phi
+                .incoming
+                .drain(..)
+                .filter_map(|(from, o)| index.get(&from).map(|new| (*new, o)))
+                .collect();
+        }
+        cfg.blocks.push(block);
+    }
+}
+
+/// Replaces each phi whose inputs, other than the phi itself, are all the
+/// same operand by that operand.
+fn remove_trivial_phis(cfg: &mut Cfg) {
+    loop {
+        let mut trivial = None;
+        'search: for (b, block) in cfg.blocks.iter().enumerate() {
+            for (i, phi) in block.phis.iter().enumerate() {
+                let own = Operand::Value(phi.dest);
+                let mut inputs = phi.incoming.iter().map(|(_, o)| o).filter(|o| **o != own);
+                if let Some(first) = inputs.next() {
+                    if inputs.all(|o| o == first) {
+                        trivial = Some((b, i, first.clone()));
+                        break 'search;
+                    }
+                }
+            }
+        }
+        let Some((b, i, with)) = trivial else { return };
+        let dest = cfg.blocks[b].phis.remove(i).dest;
+        for_each_operand_mut(cfg, &mut |o| {
+            if *o == Operand::Value(dest) {
+                *o = with.clone();
+            }
+        });
+    }
+}
+
+/// `a`, the A-normal form of a body with `params` parameters, as a
+/// control-flow graph in SSA form. The first `in_regs` parameters are passed
+/// in registers and the rest on the stack.
+fn build_cfg(a: &AExpr, params: usize, in_regs: usize) -> Cfg {
+    let mut builder = CfgBuilder {
+        blocks: Vec::new(),
+        order: Vec::new(),
+        current: 0,
+        values: 0,
+        env: (in_regs..params).map(|i| (Var::Param(i), Operand::Param(i - in_regs))).collect(),
+        guards: Vec::new(),
+        loops: Vec::new(),
+    };
+    let entry = builder.block("entry");
+    builder.start(entry);
+    for i in 0..in_regs {
+        let value = builder.def(Operation::RegParam(i));
+        builder.env.insert(Var::Param(i), value);
+    }
+    let result = builder.lower(a);
+    builder.finish(Terminator::Return(result));
+    let mut cfg = Cfg {
+        blocks: builder.blocks,
+        values: builder.values,
+    };
+    reorder_blocks(&mut cfg, &builder.order);
+    remove_trivial_phis(&mut cfg);
+    cfg
+}
+
+/// The types a tag test `(isnum x)`, `(isbool x)` or `(isfloat x)` gives an
+/// `x` of type `known` where it holds and where it fails.
+fn refine(test: &UnOp, known: Ty) -> Option<(Ty, Ty)> {
+    Some(match (test, known) {
+        (UnOp::IsNum, Ty::Num) => (Ty::Int, Ty::Float),
+        (UnOp::IsNum, _) => (Ty::Int, known),
+        (UnOp::IsBool, Ty::Any) => (Ty::Bool, Ty::Num),
+        (UnOp::IsBool, _) => (Ty::Bool, known),
+        (UnOp::IsFloat, Ty::Num) => (Ty::Float, Ty::Int),
+        (UnOp::IsFloat, _) => (Ty::Float, known),
+        _ => return None,
+    })
+}
+
+/// What is known about `o` in `block`, given the types of the values so far;
+/// `None` for a value not typed yet.
+fn operand_type(o: &Operand, block: &Block, types: &[Option<Ty>]) -> Option<Ty> {
+    let mut ty = match o {
+        Operand::Num(_) => return Some(Ty::Int),
+        Operand::Bool(_) => return Some(Ty::Bool),
+        Operand::Float(_) => return Some(Ty::Float),
+        Operand::Global(_) => return Some(Ty::Any),
+        Operand::Param(_) => Ty::Any,
+        Operand::Value(v) => types[*v]?,
+    };
+    for guard in block.guards.iter().filter(|g| g.operand == *o) {
+        if let Some((holds, fails)) = refine(&guard.test, ty) {
+            ty = if guard.holds { holds } else { fails };
+        }
+    }
+    Some(ty)
+}
+
+/// The type of the result of `op` in `block`, when it does not raise an error.
+fn op_type(op: &Operation, block: &Block, types: &[Option<Ty>]) -> Option<Ty> {
+    let ty = |o: &Operand| operand_type(o, block, types);
+    Some(match op {
+        Operation::Copy(o) => ty(o)?,
+        Operation::Input | Operation::Call(..) | Operation::RegParam(_) => Ty::Any,
+        Operation::Argc => Ty::Int,
+        Operation::Read(ReadKind::Num, _) => Ty::Num,
+        Operation::Read(ReadKind::Bool, _) => Ty::Bool,
+        Operation::Read(ReadKind::Line, _) => Ty::Any,
+        Operation::UnOp(op, o, _) => match op {
+            UnOp::Add1 | UnOp::Sub1 | UnOp::Negate => ty(o)?.arith(Ty::Int),
+            UnOp::IsNum | UnOp::IsBool | UnOp::IsFloat => Ty::Bool,
+            UnOp::ToFloat => Ty::Float,
+            UnOp::Truncate => Ty::Int,
+            UnOp::Arg => Ty::Any,
+            UnOp::Print => ty(o)?,
+        },
+        Operation::BinOp(BinOp::Plus | BinOp::Minus | BinOp::Times | BinOp::Divide, l, r, _) => {
+            ty(l)?.arith(ty(r)?)
+        }
+        Operation::BinOp(..) => Ty::Bool,
+    })
+}
+
+/// What is known about each value's type. Phis join the types arriving from
+/// their predecessors, repeating until nothing changes, so a value carried
+/// around a loop has the join of every iteration; values still untyped at the
+/// end are never computed.
+fn value_types(cfg: &Cfg) -> Vec<Option<Ty>> {
+    let mut types: Vec<Option<Ty>> = vec![None; cfg.values];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &cfg.blocks {
+            let mut found = Vec::new();
+            for phi in &block.phis {
+                let arriving = phi
+                    .incoming
+                    .iter()
+                    .filter_map(|(from, o)| operand_type(o, &cfg.blocks[*from], &types));
+                found.push((phi.dest, arriving.reduce(Ty::join)));
+            }
+            for inst in &block.insts {
+                if let Inst::Def(v, op) = inst {
+                    found.push((*v, op_type(op, block, &types)));
+                }
+            }
+            for (v, ty) in found {
+                let Some(ty) = ty else { continue };
+                let joined = types[v].map_or(ty, |old| old.join(ty));
+                if types[v] != Some(joined) {
+                    types[v] = Some(joined);
+                    changed = true;
+                }
+            }
+        }
+    }
+    types
+}
+
+/// Whether `op` can be dropped when its value is unused: it has no effect
+/// and, given what is known about its operands, cannot fail.
+fn is_pure(op: &Operation, block: &Block, types: &[Option<Ty>]) -> bool {
+    let ty = |o: &Operand| operand_type(o, block, types).unwrap_or(Ty::Any);
+    match op {
+        Operation::Copy(_) | Operation::Input | Operation::Argc | Operation::RegParam(_) => true,
+        Operation::UnOp(UnOp::IsNum | UnOp::IsBool | UnOp::IsFloat, _, _) => true,
+        Operation::UnOp(UnOp::ToFloat, o, _) => ty(o).is_num(),
+        Operation::BinOp(
+            BinOp::Less | BinOp::Greater | BinOp::LessEq | BinOp::GreaterEq,
+            l,
+            r,
+            _,
+        ) => ty(l).is_num() && ty(r).is_num(),
+        Operation::BinOp(BinOp::Equal, l, r, _) => {
+            let (lt, rt) = (ty(l), ty(r));
+            (lt.is_num() && rt.is_num()) || (lt == Ty::Bool && rt == Ty::Bool)
+        }
+        _ => false,
+    }
+}
+
+/// Removes the blocks no path from the entry reaches (`-O1` and above), after
+/// turning branches on literals into jumps: code after a `break`, the exit of
+/// a loop that never breaks, and the branch an `if` on a literal never takes.
+fn remove_unreachable_blocks(cfg: &mut Cfg) {
+    for block in &mut cfg.blocks {
+        if let Terminator::Branch(test, then_block, else_block) = &block.term {
+            let target = match test {
+                Operand::Value(_) | Operand::Param(_) | Operand::Global(_) => continue,
+                Operand::Bool(false) => *else_block,
+                _ => *then_block,
+            };
+            block.term = Terminator::Jump(target);
+        }
+    }
+    let mut reached = vec![false; cfg.blocks.len()];
+    let mut work = vec![0];
+    while let Some(b) = work.pop() {
+        if !std::mem::replace(&mut reached[b], true) {
+            work.extend(successors(&cfg.blocks[b].term));
+        }
+    }
+    let order: Vec<BlockId> = (0..cfg.blocks.len()).filter(|b| reached[*b]).collect();
+    reorder_blocks(cfg, &order);
+    remove_trivial_phis(cfg);
+}
+
+/// Removes phis and pure computations whose values are never used (`-O1` and
+/// above). Values are marked used from the effects and terminators back, so
+/// a whole chain of dead computations goes at once.
+fn eliminate_dead_code(cfg: &mut Cfg) {
+    let types = value_types(cfg);
+    let mut reads: Vec<Vec<Operand>> = vec![Vec::new(); cfg.values];
+    let mut work: Vec<Operand> = Vec::new();
+    for block in &cfg.blocks {
+        for phi in &block.phis {
+            reads[phi.dest] = phi.incoming.iter().map(|(_, o)| o.clone()).collect();
+        }
+        for inst in &block.insts {
+            let operands = inst_operands(inst).into_iter().cloned();
+            match inst {
+                Inst::Def(v, op) if is_pure(op, block, &types) => reads[*v] = operands.collect(),
+                _ => work.extend(operands),
+            }
+        }
+        work.extend(term_operand(&block.term).cloned());
+    }
+    let mut used = vec![false; cfg.values];
+    while let Some(o) = work.pop() {
+        if let Operand::Value(v) = o {
+            if !std::mem::replace(&mut used[v], true) {
+                work.append(&mut reads[v]);
+            }
+        }
+    }
+    for b in 0..cfg.blocks.len() {
+        let block = &cfg.blocks[b];
+        let keep: Vec<bool> = block
+            .insts
+            .iter()
+            .map(|inst| match inst {
+                Inst::Def(v, op) => used[*v] || !is_pure(op, block, &types),
+                Inst::SetGlobal(..) => true,
+            })
+            .collect();
+        let mut keep = keep.into_iter();
+        let block = &mut cfg.blocks[b];
+        block.insts.retain(|_| keep.next().unwrap());
+        block.phis.retain(|phi| used[phi.dest]);
+    }
+}
+
+/// Where a branch to `target` ends up once it skips blocks that only jump on
+/// to a block without phis.
+fn skip_empty_blocks(cfg: &Cfg, mut target: BlockId) -> BlockId {
+    for _ in 0..cfg.blocks.len() {
+        let block = &cfg.blocks[target];
+        match block.term {
+            Terminator::Jump(next)
+                if block.phis.is_empty()
+                    && block.insts.is_empty()
+                    && cfg.blocks[next].phis.is_empty() =>
+            {
+                target = next
+            }
+            _ => break,
+        }
+    }
+    target
+}
+
+/// Threads branches through the joins of `if`s (`-O1` and above). A block
+/// that only branches on a phi, where a nested `if` such as
+/// `(if (if a b false) x y)` joins its booleans, is skipped: each block
+/// jumping to it branches on the value it brings instead, or for a literal
+/// jumps straight on. Branches then skip blocks that only jump on.
+fn thread_branches(cfg: &mut Cfg) {
+    let mut uses = vec![0; cfg.values];
+    for block in &cfg.blocks {
+        let guards = block.guards.iter().map(|g| &g.operand);
+        let phis = block.phis.iter().flat_map(|p| &p.incoming).map(|(_, o)| o);
+        let insts = block.insts.iter().flat_map(inst_operands);
+        for o in guards.chain(phis).chain(insts).chain(term_operand(&block.term)) {
+            if let Operand::Value(v) = o {
+                uses[*v] += 1;
+            }
+        }
+    }
+    for b in 0..cfg.blocks.len() {
+        let block = &cfg.blocks[b];
+        let (then_block, else_block) = match (&block.phis[..], &block.term) {
+            ([phi], Terminator::Branch(Operand::Value(t), then_block, else_block))
+                if *t == phi.dest && uses[*t] == 1 && block.insts.is_empty() =>
+            {
+                (*then_block, *else_block)
+            }
+            _ => continue,
+        };
+        let targets = [then_block, else_block];
+        if targets.iter().any(|t| *t == b || !cfg.blocks[*t].phis.is_empty()) {
+            continue;
+        }
+        let incoming = block.phis[0].incoming.clone();
+        let preds = cfg.blocks.iter().filter(|p| successors(&p.term).contains(&b)).count();
+        let jumps = |from: &BlockId| cfg.blocks[*from].term == Terminator::Jump(b);
+        if preds != incoming.len() || !incoming.iter().map(|(from, _)| from).all(jumps) {
+            continue;
+        }
+        for (from, o) in incoming {
+            cfg.blocks[from].term = match o {
+                Operand::Value(_) | Operand::Param(_) | Operand::Global(_) => {
+                    Terminator::Branch(o, then_block, else_block)
+                }
+                Operand::Bool(false) => Terminator::Jump(else_block),
+                _ => Terminator::Jump(then_block),
+            };
+        }
+    }
+    for b in 0..cfg.blocks.len() {
+        if let Terminator::Branch(test, then_block, else_block) = &cfg.blocks[b].term {
+            let then_block = skip_empty_blocks(cfg, *then_block);
+            let else_block = skip_empty_blocks(cfg, *else_block);
+            cfg.blocks[b].term = Terminator::Branch(test.clone(), then_block, else_block);
+        }
+    }
+}
+
+/// For each block, whether each block dominates it: every path from the
+/// entry to it passes through that block.
+fn dominators(cfg: &Cfg) -> Vec<Vec<bool>> {
+    let n = cfg.blocks.len();
+    let mut preds = vec![Vec::new(); n];
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for s in successors(&block.term) {
+            preds[s].push(b);
+        }
+    }
+    let mut dom = vec![vec![true; n]; n];
+    dom[0] = (0..n).map(|b| b == 0).collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in 1..n {
+            let mut meet: Vec<bool> = (0..n)
+                .map(|d| preds[b].iter().all(|p| dom[*p][d]))
+                .collect();
+            meet[b] = true;
+            if meet != dom[b] {
+                dom[b] = meet;
+                changed = true;
+            }
+        }
+    }
+    dom
+}
+
+/// `op` with its source position cleared, if running it again with the same
+/// operands gives the same result and has no effect of its own. Operations
+/// reading a mutable global are left out, since a `set!` or call in between
+/// may change it; so are `print`, the stdin builtins, and calls to
+/// functions not in `pure`.
+fn cse_key(op: &Operation, pure: &HashSet<String>) -> Option<Operation> {
+    let reads_global = |o: &Operand| matches!(o, Operand::Global(_));
+    let at = Pos::default();
+    match op {
+        Operation::Input | Operation::Argc => Some(op.clone()),
+        Operation::Copy(_) | Operation::Read(..) | Operation::UnOp(UnOp::Print, _, _) => None,
+        // Each is read once, before the registers are reused.
+        Operation::RegParam(_) => None,
+        Operation::UnOp(op, o, _) if !reads_global(o) => {
+            Some(Operation::UnOp(op.clone(), o.clone(), at))
+        }
+        Operation::BinOp(op, l, r, _) if !reads_global(l) && !reads_global(r) => {
+            Some(Operation::BinOp(op.clone(), l.clone(), r.clone(), at))
+        }
+        Operation::Call(name, args, _) if pure.contains(name) && !args.iter().any(reads_global) => {
+            Some(Operation::Call(name.clone(), args.clone(), at))
+        }
+        _ => None,
+    }
+}
+
+/// Replaces each computation that repeats one in a dominating position, with
+/// the same operator and operands, by the earlier value (`-O1` and above).
+/// In SSA form a `set!` defines a new value, so operands that are equal hold
+/// the same values. A repeat of a fallible operation cannot fail either,
+/// since the first one already succeeded on the same operands.
+fn eliminate_common_subexpressions(cfg: &mut Cfg, pure: &HashSet<String>) {
+    let dom = dominators(cfg);
+    // Dominators have fewer dominators of their own, so they come first.
+    let mut order: Vec<BlockId> = (0..cfg.blocks.len()).collect();
+    order.sort_by_key(|b| dom[*b].iter().filter(|d| **d).count());
+    let mut available: Vec<(Operation, BlockId, ValueId)> = Vec::new();
+    let mut replaced = HashMap::new();
+    for b in order {
+        for inst in &cfg.blocks[b].insts {
+            let Inst::Def(v, op) = inst else { continue };
+            let Some(mut key) = cse_key(op, pure) else { continue };
+            // Operands may name values already replaced.
+            for_each_op_operand_mut(&mut key, &mut |o| {
+                if let Operand::Value(v) = o {
+                    *o = Operand::Value(*replaced.get(v).unwrap_or(v));
+                }
+            });
+            let earlier = available.iter().find(|(k, d, _)| *k == key && dom[b][*d]);
+            match earlier {
+                Some((_, _, w)) => {
+                    replaced.insert(*v, *w);
+                }
+                None => available.push((key, b, *v)),
+            }
+        }
+    }
+    if replaced.is_empty() {
+        return;
+    }
+    for block in &mut cfg.blocks {
+        block.insts.retain(|inst| !matches!(inst, Inst::Def(v, _) if replaced.contains_key(v)));
+    }
+    for_each_operand_mut(cfg, &mut |o| {
+        if let Operand::Value(v) = o {
+            if let Some(w) = replaced.get(v) {
+                *o = Operand::Value(*w);
+            }
+        }
+    });
+}
+
+/// The natural loops of `cfg`: each loop header with the blocks of its
+/// body, header included, innermost loops first.
+fn natural_loops(cfg: &Cfg, dom: &[Vec<bool>]) -> Vec<(BlockId, Vec<bool>)> {
+    let n = cfg.blocks.len();
+    let mut preds = vec![Vec::new(); n];
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for s in successors(&block.term) {
+            preds[s].push(b);
+        }
+    }
+    let mut loops: Vec<(BlockId, Vec<bool>)> = Vec::new();
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for head in successors(&block.term).into_iter().filter(|s| dom[b][*s]) {
+            // Everything that reaches the back edge without passing the head.
+            let mut body = vec![false; n];
+            body[head] = true;
+            let mut work = vec![b];
+            while let Some(x) = work.pop() {
+                if !std::mem::replace(&mut body[x], true) {
+                    work.extend(&preds[x]);
+                }
+            }
+            match loops.iter_mut().find(|(h, _)| *h == head) {
+                Some((_, blocks)) => (0..n).for_each(|x| blocks[x] |= body[x]),
+                None => loops.push((head, body)),
+            }
+        }
+    }
+    loops.sort_by_key(|(_, body)| body.iter().filter(|x| **x).count());
+    loops
+}
+
+/// Moves computations that give the same result on every iteration of a
+/// `loop` into the block that enters it (`-O1` and above), innermost loops
+/// first. A computation qualifies when `cse_key` accepts it and its operands
+/// are defined outside the loop; a value carried around the loop by a `set!`
+/// has a phi at the head, so it never is. It must also either be unable to
+/// fail before the loop, given the types known there, or be one of the first
+/// things the loop does: in the head, after only computations that have no
+/// effect and cannot fail. Then an error it raises happens before anything
+/// else observable, just as it would have on the first iteration.
+fn hoist_loop_invariants(cfg: &mut Cfg, pure: &HashSet<String>) {
+    let dom = dominators(cfg);
+    let types = value_types(cfg);
+    for (head, body) in natural_loops(cfg, &dom) {
+        let outside: Vec<BlockId> = (0..cfg.blocks.len())
+            .filter(|b| !body[*b] && successors(&cfg.blocks[*b].term).contains(&head))
+            .collect();
+        let [pre] = outside[..] else { continue };
+        if cfg.blocks[pre].term != Terminator::Jump(head) {
+            continue;
+        }
+        // Values defined in the loop.
+        let mut inside = HashSet::new();
+        for (block, _) in cfg.blocks.iter().zip(&body).filter(|(_, in_loop)| **in_loop) {
+            inside.extend(block.phis.iter().map(|phi| phi.dest));
+            inside.extend(block.insts.iter().filter_map(|inst| match inst {
+                Inst::Def(v, _) => Some(*v),
+                Inst::SetGlobal(..) => None,
+            }));
+        }
+        for b in (0..cfg.blocks.len()).filter(|b| body[*b]) {
+            let mut i = 0;
+            // Whether everything before `i` in the head has no effect and
+            // cannot fail.
+            let mut at_front = b == head;
+            while i < cfg.blocks[b].insts.len() {
+                let inst = &cfg.blocks[b].insts[i];
+                let invariant = inst_operands(inst).into_iter().all(|o| match o {
+                    Operand::Value(v) => !inside.contains(v),
+                    _ => true,
+                });
+                let hoist = match inst {
+                    Inst::Def(_, op) if invariant && cse_key(op, pure).is_some() => {
+                        at_front || is_pure(op, &cfg.blocks[pre], &types)
+                    }
+                    _ => false,
+                };
+                if !hoist {
+                    at_front = at_front
+                        && matches!(inst, Inst::Def(_, op) if is_pure(op, &cfg.blocks[b], &types));
+                    i += 1;
+                    continue;
+                }
+                let inst = cfg.blocks[b].insts.remove(i);
+                if let Inst::Def(v, _) = inst {
+                    inside.remove(&v);
+                }
+                cfg.blocks[pre].insts.push(inst);
+            }
+        }
+    }
+}
+
+/// Frame slots of the values `cfg` keeps in memory, as offsets below `rbp`,
+/// and the values kept only in `rax`. A value used just by the terminator
+/// right after its definition stays in `rax`, and unused values get no slot.
+/// Values whose live ranges do not overlap share a slot: they take the
+/// lowest free one in layout order, trying first a slot holding a value
+/// joined with them by a phi, so the copy into the phi disappears.
+fn assign_slots(cfg: &Cfg) -> (Slots, HashSet<ValueId>) {
+    let mut uses = vec![0; cfg.values];
+    let mut count = |o: &Operand| {
+        if let Operand::Value(v) = o {
+            uses[*v] += 1;
+        }
+    };
+    for block in &cfg.blocks {
+        block.phis.iter().flat_map(|p| &p.incoming).for_each(|(_, o)| count(o));
+        block.insts.iter().flat_map(inst_operands).for_each(&mut count);
+        term_operand(&block.term).into_iter().for_each(&mut count);
+    }
+    let mut in_rax = HashSet::new();
+    for block in &cfg.blocks {
+        if let (Some(Inst::Def(v, _)), Some(Operand::Value(t))) =
+            (block.insts.last(), term_operand(&block.term))
+        {
+            if v == t && uses[*v] == 1 {
+                in_rax.insert(*v);
+            }
+        }
+    }
+    let stored = |v: &ValueId| uses[*v] > 0 && !in_rax.contains(v);
+
+    // Values live on entry to each block, after its phis.
+    let value_of = |o: &Operand| match o {
+        Operand::Value(v) if stored(v) => Some(*v),
+        _ => None,
+    };
+    let live_out = |b: BlockId, live_in: &[HashSet<ValueId>]| {
+        let mut live = HashSet::new();
+        for s in successors(&cfg.blocks[b].term) {
+            live.extend(&live_in[s]);
+            for phi in &cfg.blocks[s].phis {
+                let arriving = phi.incoming.iter().filter(|(from, _)| *from == b);
+                live.extend(arriving.filter_map(|(_, o)| value_of(o)));
+            }
+        }
+        live
+    };
+    let mut live_in: Vec<HashSet<ValueId>> = vec![HashSet::new(); cfg.blocks.len()];
+    let mut interference: HashMap<ValueId, HashSet<ValueId>> = HashMap::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in (0..cfg.blocks.len()).rev() {
+            let block = &cfg.blocks[b];
+            let mut live = live_out(b, &live_in);
+            live.extend(term_operand(&block.term).and_then(value_of));
+            for inst in block.insts.iter().rev() {
+                if let Inst::Def(v, _) = inst {
+                    live.remove(v);
+                    if stored(v) {
+                        interference.entry(*v).or_default().extend(&live);
+                    }
+                }
+                live.extend(inst_operands(inst).into_iter().filter_map(value_of));
+            }
+            let phis: Vec<ValueId> = block.phis.iter().map(|p| p.dest).filter(stored).collect();
+            for p in &phis {
+                live.remove(p);
+            }
+            for p in &phis {
+                let others = phis.iter().filter(|q| *q != p);
+                interference.entry(*p).or_default().extend(live.iter().chain(others));
+            }
+            if live != live_in[b] {
+                live_in[b] = live;
+                changed = true;
+            }
+        }
+    }
+    let mut neighbors: HashMap<ValueId, HashSet<ValueId>> = HashMap::new();
+    for (v, others) in &interference {
+        for w in others {
+            neighbors.entry(*v).or_default().insert(*w);
+            neighbors.entry(*w).or_default().insert(*v);
+        }
+    }
+    let mut related: HashMap<ValueId, Vec<ValueId>> = HashMap::new();
+    for phi in cfg.blocks.iter().flat_map(|b| &b.phis) {
+        for (_, o) in &phi.incoming {
+            if let Operand::Value(v) = o {
+                related.entry(phi.dest).or_default().push(*v);
+                related.entry(*v).or_default().push(phi.dest);
+            }
+        }
+    }
+
+    let mut slots = Slots::new();
+    for block in &cfg.blocks {
+        let phis = block.phis.iter().map(|p| p.dest);
+        let defs = block.insts.iter().filter_map(|inst| match inst {
+            Inst::Def(v, _) => Some(*v),
+            Inst::SetGlobal(..) => None,
+        });
+        for v in phis.chain(defs).filter(stored) {
+            let taken: HashSet<i32> = neighbors
+                .get(&v)
+                .into_iter()
+                .flatten()
+                .filter_map(|w| slots.get(w).copied())
+                .collect();
+            let hinted = related.get(&v).into_iter().flatten().filter_map(|w| slots.get(w));
+            let off = hinted
+                .copied()
+                .find(|off| !taken.contains(off))
+                .unwrap_or_else(|| (1..).map(|i| i * 8).find(|off| !taken.contains(off)).unwrap());
+            slots.insert(v, off);
+        }
+    }
+    (slots, in_rax)
+}
+
+/// Frame slots of the values stored in memory, by value number.
+type Slots = HashMap<ValueId, i32>;
+
+/// Where `o`, which is not a literal, lives.
+fn operand_val(o: &Operand, slots: &Slots) -> Val {
+    match o {
+        Operand::Value(v) => slot(slots[v]),
+        Operand::Param(i) => slot(-(16 + (*i as i32) * 8)),
+        Operand::Global(name) => rel(&global_label(name)),
+        _ => panic!("literal operand has no location: {:?}", o),
+    }
+}
+
+/// Loads the tagged value of `o` into `r`.
+fn append_load(code: &mut Vec<Instr>, r: Reg, o: &Operand, slots: &Slots) {
+    code.push(match o {
+        Operand::Num(n) => Instr::IMov(reg(r), Val::Imm((*n as i64).wrapping_mul(2))),
+        Operand::Bool(b) => Instr::IMov(reg(r), Val::Imm(if *b { 3 } else { 1 })),
+        Operand::Float(f) => Instr::ILea(reg(r), Val::Rel(float_label(*f), 5)),
+        _ => Instr::IMov(reg(r), operand_val(o, slots)),
+    });
+}
+
+/// Memory holding the left operand of a binary operator: the operand itself,
+/// or for a literal the scratch slot `[rbp - scratch]`.
+fn left_operand(code: &mut Vec<Instr>, o: &Operand, slots: &Slots, ctx: &Ctx, scratch: i32) -> Val {
+    match o {
+        Operand::Num(_) | Operand::Bool(_) | Operand::Float(_) => {
+            append_load(code, Reg::Rax, o, slots);
+            code.push(store_slot(scratch));
+            ctx.claim_slot(scratch)
+        }
+        _ => operand_val(o, slots),
+    }
+}
+
+/// Copies what arrives from block `from` into the phis of `to`, as if all at
+/// once. When one copy would overwrite a slot another still reads, every
+/// value goes through the machine stack instead.
+fn append_phi_copies(code: &mut Vec<Instr>, from: BlockId, to: &Block, slots: &Slots) {
+    let copies: Vec<(i32, &Operand)> = to
+        .phis
+        .iter()
+        .filter_map(|phi| {
+            let dst = *slots.get(&phi.dest)?;
+            let (_, src) = phi.incoming.iter().find(|(b, _)| *b == from)?;
+            match src {
+                Operand::Value(v) if slots.get(v) == Some(&dst) => None,
+                _ => Some((dst, src)),
+            }
+        })
+        .collect();
+    let read_slot = |o: &Operand| match o {
+        Operand::Value(v) => slots.get(v).copied(),
+        _ => None,
+    };
+    let overlap = copies
+        .iter()
+        .any(|(dst, _)| copies.iter().any(|(_, src)| read_slot(src) == Some(*dst)));
+    if overlap {
+        for (_, src) in &copies {
+            append_load(code, Reg::Rax, src, slots);
+            code.push(Instr::IPush(reg(Reg::Rax)));
+        }
+        for (dst, _) in copies.iter().rev() {
+            code.push(Instr::IPop(reg(Reg::Rax)));
+            code.push(store_slot(*dst));
+        }
+    } else {
+        for (dst, src) in copies {
+            append_load(code, Reg::Rax, src, slots);
+            code.push(store_slot(dst));
+        }
+    }
+}
+
+/// Compiles `cfg` to code leaving its value in `rax`. Values take the frame
+/// slots `assign_slots` gives them. Blocks some jump targets are labelled
+/// from their stem, and a jump to the block right after is left out.
+fn emit_cfg(cfg: &Cfg, ctx: &Ctx, seq: &mut i32) -> Vec<Instr> {
+    let types = value_types(cfg);
+    let (slots, in_rax) = assign_slots(cfg);
+    let deepest = slots.values().copied().max().unwrap_or(0);
+    if deepest > 0 {
+        ctx.claim_slot(deepest);
+    }
+    let scratch = deepest + 8;
+    let mut labels: Vec<Option<String>> = vec![None; cfg.blocks.len()];
+    let mut label = |id: BlockId, seq: &mut i32| {
+        labels[id].get_or_insert_with(|| mk_label(seq, cfg.blocks[id].stem)).clone()
+    };
+    let targets: HashSet<BlockId> = cfg.blocks.iter().flat_map(|b| successors(&b.term)).collect();
+    let mut end = None;
+    let load = |code: &mut Vec<Instr>, o: &Operand| match o {
+        Operand::Value(v) if in_rax.contains(v) => {}
+        _ => append_load(code, Reg::Rax, o, &slots),
+    };
+    let mut code = Vec::new();
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        if targets.contains(&id) {
+            code.push(Instr::ILabel(label(id, seq)));
+        }
+        let ty = |o: &Operand| operand_type(o, block, &types).unwrap_or(Ty::Any);
+        let next = id + 1;
+        // A comparison or tag test whose only use is the branch right after
+        // it jumps on the flags instead of producing a boolean.
+        let fused = match (block.insts.last(), &block.term) {
+            (Some(Inst::Def(v, op)), Terminator::Branch(Operand::Value(t), ..))
+                if v == t && in_rax.contains(v) && is_condition(op) =>
+            {
+                Some(*v)
+            }
+            _ => None,
+        };
+        for inst in &block.insts {
+            match inst {
+                // Stored straight from its register, if used at all.
+                Inst::Def(v, Operation::RegParam(i)) if !in_rax.contains(v) => {
+                    if let Some(off) = slots.get(v) {
+                        code.push(Instr::IMov(slot(*off), reg(ARG_REGS[*i])));
+                    }
+                }
+                Inst::Def(v, op) => {
+                    let branch = match &block.term {
+                        Terminator::Branch(_, then_block, else_block) if fused == Some(*v) => {
+                            let els = label(*else_block, seq);
+                            let then = label(*then_block, seq);
+                            let follows = match next {
+                                n if n == *then_block => Some(then.clone()),
+                                n if n == *else_block => Some(els.clone()),
+                                _ => None,
+                            };
+                            Some((then, els, follows))
+                        }
+                        _ => None,
+                    };
+                    let dest = match &branch {
+                        Some((then, els, follows)) => {
+                            Dest::Branch { then, els, next: follows.as_deref() }
+                        }
+                        None => Dest::Value,
+                    };
+                    code.extend(emit_op(op, dest, &ty, &slots, ctx, scratch, seq));
+                    if let Some(off) = slots.get(v) {
+                        code.push(store_slot(*off));
+                    }
+                }
+                Inst::SetGlobal(name, value) => {
+                    append_load(&mut code, Reg::Rax, value, &slots);
+                    code.push(Instr::IMov(rel(&global_label(name)), reg(Reg::Rax)));
+                }
+            }
+        }
+        match &block.term {
+            Terminator::Branch(..) if fused.is_some() => {}
+            Terminator::Jump(target) => {
+                append_phi_copies(&mut code, id, &cfg.blocks[*target], &slots);
+                if *target != next {
+                    code.push(Instr::IJmp(label(*target, seq)));
+                }
+            }
+            Terminator::Branch(test, then_block, else_block) => {
+                load(&mut code, test);
+                code.push(Instr::ICmp(reg(Reg::Rax), Val::Imm(1)));
+                let els = label(*else_block, seq);
+                let then = label(*then_block, seq);
+                let follows = match next {
+                    n if n == *then_block => Some(then.as_str()),
+                    n if n == *else_block => Some(els.as_str()),
+                    _ => None,
+                };
+                append_branch(&mut code, Cond::Ne, (&then, &els), follows);
+            }
+            Terminator::Return(value) => {
+                load(&mut code, value);
+                if next != cfg.blocks.len() {
+                    let end = end.get_or_insert_with(|| mk_label(seq, "end"));
+                    code.push(Instr::IJmp(end.clone()));
+                }
+            }
+            Terminator::Pending => unreachable!("block left unfinished"),
+        }
+    }
+    code.extend(end.map(Instr::ILabel));
+    code
+}
+
+/// Whether `op` is a comparison or tag test, which `emit_op` can compile
+/// straight into a branch.
+fn is_condition(op: &Operation) -> bool {
+    matches!(
+        op,
+        Operation::UnOp(UnOp::IsNum | UnOp::IsBool | UnOp::IsFloat, ..)
+            | Operation::BinOp(
+                BinOp::Less | BinOp::Greater | BinOp::LessEq | BinOp::GreaterEq | BinOp::Equal,
+                ..
+            )
+    )
+}
+
+/// Compiles `op` to code leaving its value in `rax`, or for a condition
+/// sending its outcome to `dest`. `ty` gives what is known about each
+/// operand where `op` runs; tag checks it makes redundant are left out.
+fn emit_op(
+    op: &Operation,
+    dest: Dest,
+    ty: &impl Fn(&Operand) -> Ty,
+    slots: &Slots,
+    ctx: &Ctx,
+    scratch: i32,
+    seq: &mut i32,
+) -> Vec<Instr> {
+    match op {
+        Operation::Copy(o) => {
+            let mut code = Vec::new();
+            append_load(&mut code, Reg::Rax, o, slots);
+            code
+        }
+
+        Operation::Input => vec![Instr::IMov(reg(Reg::Rax), rel("INPUT_VAL"))],
+
+        Operation::Argc => vec![Instr::ICall("snek_argc".to_string())],
+
+        Operation::RegParam(i) => vec![Instr::IMov(reg(Reg::Rax), reg(ARG_REGS[*i]))],
+
+        // The runtime raises end-of-input and parse errors itself, so the site
+        // only needs an address in this function.
+        Operation::Read(kind, pos) => {
+            let (name, helper, expected) = match kind {
+                ReadKind::Num => ("read-num", "snek_read_num", "num"),
+                ReadKind::Bool => ("read-bool", "snek_read_bool", "bool"),
+                ReadKind::Line => ("read-line", "snek_read_line", "num or bool"),
+            };
+            let here = mk_label(seq, "read");
+            let site = error_site(ctx, name, *pos, expected, &here);
+            vec![
+                Instr::ILabel(here),
+                Instr::IMov(reg(Reg::Rdi), Val::Imm(site)),
+                Instr::IMov(reg(Reg::Rsi), reg(Reg::Rbp)),
+                Instr::ICall(helper.to_string()),
+            ]
+        }
+
+        Operation::UnOp(op, operand, pos) => {
+            let mut code = Vec::new();
+            append_load(&mut code, Reg::Rax, operand, slots);
+            let t = ctx.known(ty(operand));
+            match op {
+                UnOp::Add1 | UnOp::Sub1 => {
+                    let (name, arith) = match op {
+                        UnOp::Add1 => ("add1", ARITH_PLUS),
+                        _ => ("sub1", ARITH_MINUS),
+                    };
+                    let bad = mk_label(seq, "badarg");
+                    let slow = mk_label(seq, "slow");
+                    let ov = mk_label(seq, "overflow");
+                    let done = mk_label(seq, "u_done");
+                    let site = error_site(ctx, name, *pos, "num", if t.is_num() { &ov } else { &bad });
+                    if t != Ty::Int {
+                        append_tag_check(&mut code, Reg::Rax, 1, 0, Cond::Ne, &slow);
+                    }
+                    code.push(match op {
+                        UnOp::Add1 => Instr::IAdd(reg(Reg::Rax), Val::Imm(2)),
+                        _ => Instr::ISub(reg(Reg::Rax), Val::Imm(2)),
+                    });
+                    code.push(Instr::IJcc(Cond::O, ov.clone()));
+                    if t != Ty::Int {
+                        code.push(Instr::IJmp(done.clone()));
+                        append_unary_slow_path(&mut code, (&slow, &bad), arith, 2, t);
+                    }
+                    if !t.is_num() {
+                        append_snek_invalid_at(ctx, &bad, site, Operands::Unary);
+                    }
+                    append_snek_overflow_at(ctx, &ov, site);
+                    code.push(Instr::ILabel(done));
+                }
+                UnOp::Negate => {
+                    let bad = mk_label(seq, "badarg");
+                    let slow = mk_label(seq, "slow");
+                    let ov = mk_label(seq, "overflow");
+                    let done = mk_label(seq, "u_done");
+                    let here = if t.is_num() { &ov } else { &bad };
+                    let site = error_site(ctx, "negate", *pos, "num", here);
+                    if t != Ty::Int {
+                        append_tag_check(&mut code, Reg::Rax, 1, 0, Cond::Ne, &slow);
+                    }
+                    // Negating the tagged value negates the number; only the
+                    // most negative 63-bit integer overflows.
+                    code.push(Instr::INeg(reg(Reg::Rax)));
+                    code.push(Instr::IJcc(Cond::O, ov.clone()));
+                    if t != Ty::Int {
+                        code.push(Instr::IJmp(done.clone()));
+                        // Multiplying by -1 keeps the sign of a floating-point zero correct.
+                        append_unary_slow_path(&mut code, (&slow, &bad), ARITH_TIMES, -2, t);
+                    }
+                    if !t.is_num() {
+                        append_snek_invalid_at(ctx, &bad, site, Operands::Unary);
+                    }
+                    append_snek_overflow_at(ctx, &ov, site);
+                    code.push(Instr::ILabel(done));
+                }
+                UnOp::IsNum | UnOp::IsBool | UnOp::IsFloat => {
+                    let (mask, bits) = match op {
+                        UnOp::IsNum => (1, 0),
+                        UnOp::IsBool => (5, 1),
+                        _ => (7, 5),
+                    };
+                    append_tag_test(&mut code, Reg::Rax, mask, bits);
+                    append_condition(&mut code, Cond::E, dest);
+                }
+                UnOp::ToFloat | UnOp::Truncate => {
+                    let bad = mk_label(seq, "badarg");
+                    let name = if matches!(op, UnOp::ToFloat) { "float" } else { "truncate" };
+                    let done = mk_label(seq, "u_done");
+                    let here = if t.is_num() { &done } else { &bad };
+                    let site = error_site(ctx, name, *pos, "num", here);
+                    if !t.is_num() {
+                        append_bool_guard(&mut code, Reg::Rax, &bad);
+                    }
+                    code.push(Instr::IMov(reg(Reg::Rdi), reg(Reg::Rax)));
+                    if matches!(op, UnOp::ToFloat) {
+                        code.push(Instr::ICall("snek_to_float".to_string()));
+                    } else {
+                        append_site_args(&mut code, site);
+                        code.push(Instr::ICall("snek_truncate".to_string()));
+                    }
+                    if !t.is_num() {
+                        append_snek_invalid_at(ctx, &bad, site, Operands::Unary);
+                    }
+                    code.push(Instr::ILabel(done));
+                }
+                UnOp::Arg => {
+                    let bad = mk_label(seq, "badarg");
+                    let done = mk_label(seq, "u_done");
+                    let here = if t == Ty::Int { &done } else { &bad };
+                    let site = error_site(ctx, "arg", *pos, "int", here);
+                    if t != Ty::Int {
+                        append_tag_check(&mut code, Reg::Rax, 1, 0, Cond::Ne, &bad);
+                    }
+                    code.push(Instr::IMov(reg(Reg::Rdi), reg(Reg::Rax)));
+                    append_site_args(&mut code, site);
+                    code.push(Instr::ICall("snek_arg".to_string()));
+                    if t != Ty::Int {
+                        append_snek_invalid_at(ctx, &bad, site, Operands::Unary);
+                    }
+                    code.push(Instr::ILabel(done));
+                }
+                UnOp::Print => {
+                    code.push(Instr::IMov(reg(Reg::Rdi), reg(Reg::Rax)));
+                    code.push(Instr::ICall("snek_print".to_string()));
+                }
+            }
+            code
+        }
+
+        Operation::BinOp(op, l, r, pos) => {
+            let mut code = Vec::new();
+            let left = left_operand(&mut code, l, slots, ctx, scratch);
+            append_load(&mut code, Reg::Rax, r, slots);
+            let (lt, rt) = (ctx.known(ty(l)), ctx.known(ty(r)));
+            // Both operands are integers, so the slow path is never taken.
+            let ints = lt == Ty::Int && rt == Ty::Int;
+            // Both are numbers, so no operand can be rejected.
+            let nums = lt.is_num() && rt.is_num();
+            match op {
+                BinOp::Plus | BinOp::Minus | BinOp::Times | BinOp::Divide => {
+                    let (name, arith) = match op {
+                        BinOp::Plus => ("+", ARITH_PLUS),
+                        BinOp::Minus => ("-", ARITH_MINUS),
+                        BinOp::Times => ("*", ARITH_TIMES),
+                        _ => ("/", ARITH_DIVIDE),
+                    };
+                    let bad = mk_label(seq, "badarg");
+                    let slow = mk_label(seq, "slow");
+                    let ov = mk_label(seq, "overflow");
+                    let zero = if matches!(op, BinOp::Divide) {
+                        mk_label(seq, "divzero")
+                    } else {
+                        String::new()
+                    };
+                    let done = mk_label(seq, "bin_done");
+                    let site = error_site(ctx, name, *pos, "num", if nums { &ov } else { &bad });
+                    if rt != Ty::Int {
+                        append_tag_check(&mut code, Reg::Rax, 1, 0, Cond::Ne, &slow);
+                    }
+                    // `*` keeps the left operand in `rcx` for the multiplication.
+                    let scratch = if matches!(op, BinOp::Times) { Reg::Rcx } else { Reg::R11 };
+                    if lt != Ty::Int {
+                        code.push(Instr::IMov(reg(scratch), left.clone()));
+                        code.push(Instr::ITest(reg(scratch), Val::Imm(1)));
+                        code.push(Instr::IJcc(Cond::Ne, slow.clone()));
+                    } else if scratch == Reg::Rcx {
+                        code.push(Instr::IMov(reg(scratch), left.clone()));
+                    }
+                    match op {
+                        BinOp::Plus => {
+                            code.push(Instr::IAdd(reg(Reg::Rax), left.clone()));
+                            code.push(Instr::IJcc(Cond::O, ov.clone()));
+                        }
+                        BinOp::Minus => {
+                            code.push(Instr::IMov(reg(Reg::Rcx), left.clone()));
+                            code.push(Instr::ISub(reg(Reg::Rcx), reg(Reg::Rax)));
+                            code.push(Instr::IJcc(Cond::O, ov.clone()));
+                            code.push(Instr::IMov(reg(Reg::Rax), reg(Reg::Rcx)));
+                        }
+                        BinOp::Times => {
+                            // Only the right operand is untagged, so the product
+                            // comes out tagged, and a 64-bit overflow is one
+                            // outside the 63-bit range.
+                            code.push(Instr::ISar(reg(Reg::Rax), Val::Imm(1)));
+                            code.push(Instr::IMul(reg(Reg::Rax), reg(Reg::Rcx)));
+                            code.push(Instr::IJcc(Cond::O, ov.clone()));
+                        }
+                        _ => {
+                            code.push(Instr::ICmp(reg(Reg::Rax), Val::Imm(0)));
+                            code.push(Instr::IJcc(Cond::E, zero.clone()));
+                            // Both operands carry the same factor of two, so the
+                            // quotient comes out untagged.
+                            code.push(Instr::IMov(reg(Reg::Rcx), reg(Reg::Rax)));
+                            code.push(Instr::IMov(reg(Reg::Rax), left.clone()));
+                            code.push(Instr::ICqo);
+                            code.push(Instr::IDiv(reg(Reg::Rcx)));
+                            code.push(Instr::IAdd(reg(Reg::Rax), reg(Reg::Rax)));
+                            code.push(Instr::IJcc(Cond::O, ov.clone()));
+                        }
+                    }
+                    if !ints {
+                        code.push(Instr::IJmp(done.clone()));
+                        let labels = (slow.as_str(), bad.as_str());
+                        append_binary_slow_path(&mut code, labels, arith, &left, (lt, rt));
+                    }
+                    if !nums {
+                        append_snek_invalid_at(ctx, &bad, site, Operands::Binary);
+                    }
+                    append_snek_overflow_at(ctx, &ov, site);
+                    if matches!(op, BinOp::Divide) {
+                        append_snek_div_zero_at(ctx, &zero, site);
+                    }
+                    code.push(Instr::ILabel(done));
+                }
+                BinOp::Less | BinOp::Greater | BinOp::LessEq | BinOp::GreaterEq => {
+                    let (jcc, arith, name) = match op {
+                        BinOp::Less => (Cond::L, ARITH_LESS, "<"),
+                        BinOp::Greater => (Cond::G, ARITH_GREATER, ">"),
+                        BinOp::LessEq => (Cond::Le, ARITH_LESS_EQ, "<="),
+                        _ => (Cond::Ge, ARITH_GREATER_EQ, ">="),
+                    };
+                    let slow = append_two_num_checks(&left, &mut code, seq, (lt, rt));
+                    let bad = mk_label(seq, "badarg");
+                    let site = if nums {
+                        None
+                    } else {
+                        Some(error_site(ctx, name, *pos, "num", &bad))
+                    };
+                    let done = mk_label(seq, "bin_done");
+                    code.push(Instr::ICmp(reg(Reg::Rdi), reg(Reg::Rsi)));
+                    append_condition(&mut code, jcc, dest);
+                    if !ints {
+                        let mut slow_code = Vec::new();
+                        let labels = (slow.as_str(), bad.as_str());
+                        append_binary_slow_path(&mut slow_code, labels, arith, &left, (lt, rt));
+                        append_slow_condition(&mut code, slow_code, dest, ctx, &done);
+                    }
+                    if let Some(site) = site {
+                        append_snek_invalid_at(ctx, &bad, site, Operands::Binary);
+                    }
+                }
+                // Integers or booleans on both sides compare by their tagged bits.
+                BinOp::Equal if ints || (lt == Ty::Bool && rt == Ty::Bool) => {
+                    code.push(Instr::ICmp(reg(Reg::Rax), left));
+                    append_condition(&mut code, Cond::E, dest);
+                }
+                BinOp::Equal => {
+                    let bad = mk_label(seq, "badarg");
+                    let site = error_site(ctx, "=", *pos, "operands of the same type", &bad);
+                    let slow = mk_label(seq, "slow");
+                    let cmp = mk_label(seq, "eqc");
+                    let nums = mk_label(seq, "eqn");
+                    let done = mk_label(seq, "bin_done");
+                    code.push(Instr::IMov(reg(Reg::Rcx), left.clone()));
+                    code.push(Instr::IMov(reg(Reg::R11), reg(Reg::Rcx)));
+                    code.push(Instr::IOr(reg(Reg::R11), reg(Reg::Rax)));
+                    code.push(Instr::ITest(reg(Reg::R11), Val::Imm(1)));
+                    code.push(Instr::IJcc(Cond::Ne, slow.clone()));
+                    code.push(Instr::ILabel(cmp.clone()));
+                    code.push(Instr::ICmp(reg(Reg::Rax), reg(Reg::Rcx)));
+                    append_condition(&mut code, Cond::E, dest);
+                    // Booleans only compare against booleans; any other mix of
+                    // numbers goes through the runtime so 1 and 1.0 are equal.
+                    let mut slow_code = vec![Instr::ILabel(slow)];
+                    slow_code.push(Instr::IMov(reg(Reg::Rdx), reg(Reg::Rcx)));
+                    slow_code.push(Instr::IAnd(reg(Reg::Rdx), Val::Imm(5)));
+                    append_tag_check(&mut slow_code, Reg::Rax, 5, 1, Cond::Ne, &nums);
+                    slow_code.push(Instr::ICmp(reg(Reg::Rdx), Val::Imm(1)));
+                    slow_code.push(Instr::IJcc(Cond::E, cmp));
+                    slow_code.push(Instr::IJmp(bad.clone()));
+                    slow_code.push(Instr::ILabel(nums));
+                    slow_code.push(Instr::ICmp(reg(Reg::Rdx), Val::Imm(1)));
+                    slow_code.push(Instr::IJcc(Cond::E, bad.clone()));
+                    slow_code.push(Instr::IMov(reg(Reg::Rdi), Val::Imm(ARITH_EQUAL)));
+                    slow_code.push(Instr::IMov(reg(Reg::Rsi), reg(Reg::Rcx)));
+                    slow_code.push(Instr::IMov(reg(Reg::Rdx), reg(Reg::Rax)));
+                    slow_code.push(Instr::ICall("snek_arith".to_string()));
+                    append_slow_condition(&mut code, slow_code, dest, ctx, &done);
+                    append_snek_invalid_at(ctx, &bad, site, Operands::Binary);
+                }
+            }
+            code
+        }
+
+        Operation::Call(name, args, pos) => {
+            let mut code = Vec::new();
+            if ctx.externs.contains(name) {
+                append_extern_call(&mut code, name, args, slots);
+                return code;
+            }
+
+            let in_regs = if ctx.sysv_calls { ARG_REGS.len() } else { 0 };
+            let cleanup = append_call_args(&mut code, args, in_regs, slots);
+            code.push(Instr::ICall(format!("fun_{}", name)));
+            let ret = mk_label(seq, "ret");
+            code.push(Instr::ILabel(ret.clone()));
+            ctx.call_sites.borrow_mut().push((ret, *pos));
+            append_pop_args(&mut code, cleanup);
+            code
+        }
+    }
+}
+
+/// The System V integer argument registers, in order.
+const ARG_REGS: [Reg; 6] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+
+/// System V call to an `extern` function with tagged arguments `args`.
+fn append_extern_call(code: &mut Vec<Instr>, name: &str, args: &[Operand], slots: &Slots) {
+    let cleanup = append_call_args(code, args, ARG_REGS.len(), slots);
+    code.push(Instr::ICall(name.to_string()));
+    append_pop_args(code, cleanup);
+}
+
+/// Passes the first `in_regs` of `args` in `ARG_REGS` and pushes the rest,
+/// last first, returning the bytes to pop after the call. `rsp` is 16-byte
+/// aligned between expressions, so one pad slot keeps it aligned at the call
+/// when an odd number are pushed. The registers are loaded after the pushes,
+/// which go through `rax`.
+fn append_call_args(code: &mut Vec<Instr>, args: &[Operand], in_regs: usize, slots: &Slots) -> i64 {
+    let stack_args = args.len().saturating_sub(in_regs);
+    let needs_pad = stack_args % 2 == 1;
+    if needs_pad {
+        code.push(Instr::ISub(reg(Reg::Rsp), Val::Imm(8)));
+    }
+    for arg in args.iter().skip(in_regs).rev() {
+        append_load(code, Reg::Rax, arg, slots);
+        code.push(Instr::IPush(reg(Reg::Rax)));
+    }
+    for (arg, r) in args.iter().zip(&ARG_REGS[..in_regs]) {
+        append_load(code, *r, arg, slots);
+    }
+    (stack_args * 8 + if needs_pad { 8 } else { 0 }) as i64
+}
+
+fn append_pop_args(code: &mut Vec<Instr>, bytes: i64) {
+    if bytes > 0 {
+        code.push(Instr::IAdd(reg(Reg::Rsp), Val::Imm(bytes)));
+    }
+}
+
+fn align_to_16(bytes: i32) -> i32 {
+    if bytes == 0 {
+        0
+    } else {
+        ((bytes + 15) / 16) * 16
+    }
+}
+
+/// Per-function profiling: the function's row in `snek_profile_counters`
+/// (calls, inclusive cycles, active calls) and the frame slot holding its entry
+/// `rdtsc`. Only the outermost of nested recursive calls adds to the cycles,
+/// so recursion is not counted twice.
+#[derive(Debug, Clone, Copy)]
+struct Profile {
+    index: usize,
+    slot: i32,
+}
+
+impl Profile {
+    /// Reserves the `rdtsc` slot below a frame of `frame_bytes` (16-byte
+    /// aligned), returning the profile and the enlarged frame size.
+    fn below(index: usize, frame_bytes: i32) -> (Profile, i32) {
+        let slot = frame_bytes + 8;
+        (Profile { index, slot }, frame_bytes + 16)
+    }
+
+    fn counter(&self, field: usize) -> Val {
+        Val::Rel("snek_profile_counters".to_string(), (self.index * 24 + field * 8) as i32)
+    }
+
+    /// Reads the time stamp counter into `rax`.
+    fn append_rdtsc(code: &mut Vec<Instr>) {
+        code.push(Instr::IRdtsc);
+        code.push(Instr::IShl(reg(Reg::Rdx), Val::Imm(32)));
+        code.push(Instr::IOr(reg(Reg::Rax), reg(Reg::Rdx)));
+    }
+
+    /// Counts the call and records the entry time stamp. With `keep_rdx`,
+    /// the third argument register, which `rdtsc` overwrites, is preserved.
+    fn append_entry(&self, code: &mut Vec<Instr>, keep_rdx: bool) {
+        code.push(Instr::IAdd(self.counter(0), Val::Imm(1)));
+        code.push(Instr::IAdd(self.counter(2), Val::Imm(1)));
+        if keep_rdx {
+            code.push(Instr::IMov(reg(Reg::R11), reg(Reg::Rdx)));
+        }
+        Profile::append_rdtsc(code);
+        code.push(store_slot(self.slot));
+        if keep_rdx {
+            code.push(Instr::IMov(reg(Reg::Rdx), reg(Reg::R11)));
+        }
+    }
+
+    /// Adds the cycles since entry to the function's total, preserving `rax`.
+    fn append_exit(&self, code: &mut Vec<Instr>, seq: &mut i32) {
+        let nested = mk_label(seq, "prof_nested");
+        code.push(Instr::IMov(reg(Reg::R11), reg(Reg::Rax)));
+        code.push(Instr::ISub(self.counter(2), Val::Imm(1)));
+        code.push(Instr::IJcc(Cond::Ne, nested.clone()));
+        Profile::append_rdtsc(code);
+        code.push(Instr::ISub(reg(Reg::Rax), slot(self.slot)));
+        code.push(Instr::IAdd(self.counter(1), reg(Reg::Rax)));
+        code.push(Instr::ILabel(nested));
+        code.push(Instr::IMov(reg(Reg::Rax), reg(Reg::R11)));
+    }
+}
+
+fn append_prologue(code: &mut Vec<Instr>, frame_bytes: i32) {
+    code.push(Instr::IPush(reg(Reg::Rbp)));
+    code.push(Instr::IMov(reg(Reg::Rbp), reg(Reg::Rsp)));
+    if frame_bytes > 0 {
+        code.push(Instr::ISub(reg(Reg::Rsp), Val::Imm(frame_bytes as i64)));
+    }
+}
+
+/// Stores the callee-saved registers a function uses in their frame slots.
+fn append_saves(code: &mut Vec<Instr>, saves: &[(Reg, i32)]) {
+    for (r, off) in saves {
+        code.push(Instr::IMov(slot(*off), reg(*r)));
+    }
+}
+
+fn append_restores(code: &mut Vec<Instr>, saves: &[(Reg, i32)]) {
+    for (r, off) in saves {
+        code.push(Instr::IMov(reg(*r), slot(*off)));
+    }
+}
+
+fn append_epilogue(code: &mut Vec<Instr>) {
+    code.push(Instr::IMov(reg(Reg::Rsp), reg(Reg::Rbp)));
+    code.push(Instr::IPop(reg(Reg::Rbp)));
+    code.push(Instr::IRet);
+}
+
+/// Lowers `e`, a function body, main or a global initializer, to A-normal form
+/// and then to a control-flow graph, and compiles it. Its values take the
+/// slots from `[rbp - 8]` down.
+fn emit_body(e: &Expr, ctx: &Ctx, seq: &mut i32) -> Vec<Instr> {
+    let in_regs = if ctx.sysv_calls { ctx.params.len().min(ARG_REGS.len()) } else { 0 };
+    let mut cfg = build_cfg(&lower_expr(e, ctx), ctx.params.len(), in_regs);
+    if ctx.prune {
+        remove_unreachable_blocks(&mut cfg);
+        hoist_loop_invariants(&mut cfg, ctx.pure);
+        eliminate_common_subexpressions(&mut cfg, ctx.pure);
+        eliminate_dead_code(&mut cfg);
+        thread_branches(&mut cfg);
+        remove_unreachable_blocks(&mut cfg);
+    }
+    emit_cfg(&cfg, ctx, seq)
+}
+
+/// Compiles `defn` with the program-wide tables in `base`. `profile` is the
+/// function's row in the profile counters when compiling with `--profile`;
+/// `allocate` keeps the body's stack slots in registers where it can.
+fn compile_definition(
+    defn: &Definition,
+    base: &Ctx,
+    profile: Option<usize>,
+    allocate: bool,
+    seq: &mut i32,
+) -> Vec<Instr> {
+    let frame = Cell::new(0);
+    let stubs = RefCell::new(Vec::new());
+    let ctx = Ctx {
+        params: &defn.params,
+        frame: &frame,
+        stubs: &stubs,
+        ..*base
+    };
+    let body = emit_body(&defn.body, &ctx, seq);
+    let depth = frame.get();
+    let (body, stubs, saved) = allocate_with_stubs(body, stubs.into_inner(), depth, allocate);
+    let (saves, mut frame_bytes) = save_slots(&saved, align_to_16(depth));
+    let profile = profile.map(|index| {
+        let (p, bytes) = Profile::below(index, frame_bytes);
+        frame_bytes = bytes;
+        p
+    });
+    let mut code = vec![Instr::ILabel(format!("fun_{}", defn.name))];
+    append_prologue(&mut code, frame_bytes);
+    code.push(Instr::ICmp(reg(Reg::Rsp), rel("STACK_LIMIT")));
+    code.push(Instr::IJcc(Cond::B, "stack_overflow".to_string()));
+    append_saves(&mut code, &saves);
+    if let Some(p) = &profile {
+        p.append_entry(&mut code, base.sysv_calls && defn.params.len() > 2);
+    }
+    code.extend(body);
+    if let Some(p) = &profile {
+        p.append_exit(&mut code, seq);
+    }
+    append_restores(&mut code, &saves);
+    append_epilogue(&mut code);
+    code.extend(stubs);
+    code.push(Instr::ILabel(format!("endfun_{}", defn.name)));
+    code
+}
+
+/// `body` and its error `stubs` with the body's stack slots moved into
+/// registers when `allocate` is set, and the registers used. They are
+/// allocated together so the jumps into the stubs have their targets.
+fn allocate_with_stubs(
+    mut body: Vec<Instr>,
+    stubs: Vec<Instr>,
+    depth: i32,
+    allocate: bool,
+) -> (Vec<Instr>, Vec<Instr>, Vec<Reg>) {
+    if !allocate {
+        return (body, stubs, Vec::new());
+    }
+    let split = body.len();
+    body.extend(stubs);
+    let (mut body, saved) = allocate_registers(&body, depth);
+    let stubs = body.split_off(split);
+    (body, stubs, saved)
+}
+
+/// Names of the functions called in `e`, in order of appearance.
+fn collect_calls(e: &Expr, out: &mut Vec<String>) {
+    match e {
+        Expr::Num(_)
+        | Expr::Float(_)
+        | Expr::Bool(_)
+        | Expr::Input
+        | Expr::Argc
+        | Expr::Read(..)
+        | Expr::Var(_) => {}
+        Expr::Let(bindings, body) => {
+            for (_, rhs) in bindings {
+                collect_calls(rhs, out);
+            }
+            collect_calls(body, out);
+        }
+        Expr::UnOp(_, sub, _) | Expr::Loop(sub) | Expr::Break(sub) | Expr::Set(_, sub) => {
+            collect_calls(sub, out)
+        }
+        Expr::BinOp(_, e1, e2, _) => {
+            collect_calls(e1, out);
+            collect_calls(e2, out);
+        }
+        Expr::If(c, t, f) => {
+            collect_calls(c, out);
+            collect_calls(t, out);
+            collect_calls(f, out);
+        }
+        Expr::Block(items) => {
+            for it in items {
+                collect_calls(it, out);
+            }
+        }
+        Expr::Call(name, args, _) => {
+            out.push(name.clone());
+            for arg in args {
+                collect_calls(arg, out);
+            }
+        }
+    }
+}
+
+fn collect_floats(e: &Expr, out: &mut Vec<u64>) {
+    match e {
+        Expr::Float(f) => {
+            if !out.contains(&f.to_bits()) {
+                out.push(f.to_bits());
+            }
+        }
+        Expr::Num(_)
+        | Expr::Bool(_)
+        | Expr::Input
+        | Expr::Argc
+        | Expr::Read(..)
+        | Expr::Var(_) => {}
+        Expr::Let(bindings, body) => {
+            for (_, rhs) in bindings {
+                collect_floats(rhs, out);
+            }
+            collect_floats(body, out);
+        }
+        Expr::UnOp(_, sub, _) | Expr::Loop(sub) | Expr::Break(sub) | Expr::Set(_, sub) => {
+            collect_floats(sub, out)
+        }
+        Expr::BinOp(_, e1, e2, _) => {
+            collect_floats(e1, out);
+            collect_floats(e2, out);
+        }
+        Expr::If(c, t, f) => {
+            collect_floats(c, out);
+            collect_floats(t, out);
+            collect_floats(f, out);
+        }
+        Expr::Block(items) | Expr::Call(_, items, _) => {
+            for it in items {
+                collect_floats(it, out);
+            }
+        }
+    }
+}
+
+fn global_name(g: &Global) -> &str {
+    match g {
+        Global::Define(name, _) | Global::Const(name, _) => name,
+    }
+}
+
+fn is_literal(e: &Expr) -> bool {
+    matches!(e, Expr::Num(_) | Expr::Bool(_) | Expr::Float(_))
+}
+
+/// Whether `e` contains a `set!` of `name`, in any scope.
+fn assigns(e: &Expr, name: &str) -> bool {
+    match e {
+        Expr::Num(_)
+        | Expr::Float(_)
+        | Expr::Bool(_)
+        | Expr::Input
+        | Expr::Argc
+        | Expr::Read(..)
+        | Expr::Var(_) => false,
+        Expr::Set(target, rhs) => target == name || assigns(rhs, name),
+        Expr::Let(bindings, body) => {
+            bindings.iter().any(|(_, rhs)| assigns(rhs, name)) || assigns(body, name)
+        }
+        Expr::UnOp(_, sub, _) | Expr::Loop(sub) | Expr::Break(sub) => assigns(sub, name),
+        Expr::BinOp(_, e1, e2, _) => assigns(e1, name) || assigns(e2, name),
+        Expr::If(c, t, f) => assigns(c, name) || assigns(t, name) || assigns(f, name),
+        Expr::Block(items) | Expr::Call(_, items, _) => items.iter().any(|it| assigns(it, name)),
+    }
+}
+
+/// The literal an operator applied to literal operands evaluates to, or `None`
+/// when it has to run: it would raise an error, or the integer result does
+/// not fit a literal. Literals are 32-bit and the runtime checks overflow
+/// against the 63-bit range, so a result the checked `i32` arithmetic refuses
+/// may still be fine at runtime; it is just not folded.
+fn fold_unop(op: &UnOp, sub: &Expr) -> Option<Expr> {
+    match (op, sub) {
+        (UnOp::Add1, Expr::Num(n)) => n.checked_add(1).map(Expr::Num),
+        (UnOp::Sub1, Expr::Num(n)) => n.checked_sub(1).map(Expr::Num),
+        (UnOp::Negate, Expr::Num(n)) => n.checked_neg().map(Expr::Num),
+        (UnOp::IsNum, lit) if is_literal(lit) => Some(Expr::Bool(matches!(lit, Expr::Num(_)))),
+        (UnOp::IsBool, lit) if is_literal(lit) => Some(Expr::Bool(matches!(lit, Expr::Bool(_)))),
+        (UnOp::IsFloat, lit) if is_literal(lit) => {
+            Some(Expr::Bool(matches!(lit, Expr::Float(_))))
+        }
+        _ => None,
+    }
+}
+
+fn fold_binop(op: &BinOp, left: &Expr, right: &Expr) -> Option<Expr> {
+    match (left, right) {
+        (Expr::Num(a), Expr::Num(b)) => match op {
+            BinOp::Plus => a.checked_add(*b).map(Expr::Num),
+            BinOp::Minus => a.checked_sub(*b).map(Expr::Num),
+            BinOp::Times => a.checked_mul(*b).map(Expr::Num),
+            // Both round towards zero; `checked_div` also refuses division by zero.
+            BinOp::Divide => a.checked_div(*b).map(Expr::Num),
+            BinOp::Less => Some(Expr::Bool(a < b)),
+            BinOp::Greater => Some(Expr::Bool(a > b)),
+            BinOp::LessEq => Some(Expr::Bool(a <= b)),
+            BinOp::GreaterEq => Some(Expr::Bool(a >= b)),
+            BinOp::Equal => Some(Expr::Bool(a == b)),
+        },
+        (Expr::Bool(a), Expr::Bool(b)) if matches!(op, BinOp::Equal) => Some(Expr::Bool(a == b)),
+        _ => None,
+    }
+}
+
+/// Constant folding and propagation (`-O1` and above). `consts` maps the
+/// names in scope that are known literals. Anything that would fail at
+/// runtime is left in place to fail there.
+fn fold_expr(e: &Expr, consts: &HashMap<String, Expr>) -> Expr {
+    let fold = |sub: &Expr| fold_expr(sub, consts);
+    match e {
+        Expr::Num(_)
+        | Expr::Float(_)
+        | Expr::Bool(_)
+        | Expr::Input
+        | Expr::Argc
+        | Expr::Read(..) => e.clone(),
+        Expr::Var(name) => consts.get(name).cloned().unwrap_or_else(|| e.clone()),
+        Expr::Let(bindings, body) => {
+            let mut scope = consts.clone();
+            let mut kept = Vec::new();
+            for (i, (name, rhs)) in bindings.iter().enumerate() {
+                let rhs = fold_expr(rhs, &scope);
+                let mutated = bindings[i + 1..].iter().any(|(_, later)| assigns(later, name))
+                    || assigns(body, name);
+                if is_literal(&rhs) && !mutated {
+                    scope.insert(name.clone(), rhs);
+                } else {
+                    scope.remove(name);
+                    kept.push((name.clone(), rhs));
+                }
+            }
+            let body = fold_expr(body, &scope);
+            if kept.is_empty() {
+                body
+            } else {
+                Expr::Let(kept, Box::new(body))
+            }
+        }
+        Expr::UnOp(op, sub, pos) => {
+            let sub = fold(sub);
+            fold_unop(op, &sub).unwrap_or_else(|| Expr::UnOp(op.clone(), Box::new(sub), *pos))
+        }
+        Expr::BinOp(op, e1, e2, pos) => {
+            let (left, right) = (fold(e1), fold(e2));
+            fold_binop(op, &left, &right).unwrap_or_else(|| {
+                Expr::BinOp(op.clone(), Box::new(left), Box::new(right), *pos)
+            })
+        }
+        // Every value but `false` selects the `then` branch.
+        Expr::If(c, t, f) => match fold(c) {
+            Expr::Bool(false) => fold(f),
+            cond if is_literal(&cond) => fold(t),
+            cond => Expr::If(Box::new(cond), Box::new(fold(t)), Box::new(fold(f))),
+        },
+        Expr::Block(items) => {
+            let last = items.len().saturating_sub(1);
+            let mut folded: Vec<Expr> = items
+                .iter()
+                .enumerate()
+                .map(|(i, it)| (i, fold(it)))
+                .filter(|(i, it)| *i == last || !is_literal(it))
+                .map(|(_, it)| it)
+                .collect();
+            if folded.len() == 1 {
+                folded.pop().unwrap()
+            } else {
+                Expr::Block(folded)
+            }
+        }
+        Expr::Loop(body) => Expr::Loop(Box::new(fold(body))),
+        Expr::Break(inner) => Expr::Break(Box::new(fold(inner))),
+        Expr::Set(name, rhs) => Expr::Set(name.clone(), Box::new(fold(rhs))),
+        Expr::Call(name, args, pos) => {
+            Expr::Call(name.clone(), args.iter().map(fold).collect(), *pos)
+        }
+    }
+}
+
+/// `prog` with every function body, global initializer and main folded.
+/// Global constants are known everywhere they are not shadowed.
+fn fold_program(prog: &Program) -> Program {
+    let mut consts = HashMap::new();
+    for g in &prog.globals {
+        if let Global::Const(name, lit) = g {
+            consts.insert(name.clone(), lit.clone());
+        }
+    }
+    let mut folded = prog.clone();
+    for defn in &mut folded.defns {
+        let mut visible = consts.clone();
+        visible.retain(|name, _| !defn.params.contains(name));
+        defn.body = fold_expr(&defn.body, &visible);
+    }
+    for g in &mut folded.globals {
+        if let Global::Define(_, init) = g {
+            *init = fold_expr(init, &consts);
+        }
+    }
+    folded.main = fold_expr(&folded.main, &consts);
+    folded
+}
+
+/// Largest body, in expression nodes, that `inline_program` copies into a
+/// call site at optimization level `opt_level`.
+fn inline_limit(opt_level: u32) -> usize {
+    match opt_level {
+        0 => 0,
+        1 => 12,
+        _ => 40,
+    }
+}
+
+fn expr_size(e: &Expr) -> usize {
+    1 + match e {
+        Expr::Num(_)
+        | Expr::Float(_)
+        | Expr::Bool(_)
+        | Expr::Input
+        | Expr::Argc
+        | Expr::Read(..)
+        | Expr::Var(_) => 0,
+        Expr::Let(bindings, body) => {
+            bindings.iter().map(|(_, rhs)| expr_size(rhs)).sum::<usize>() + expr_size(body)
+        }
+        Expr::UnOp(_, sub, _) | Expr::Loop(sub) | Expr::Break(sub) | Expr::Set(_, sub) => {
+            expr_size(sub)
+        }
+        Expr::BinOp(_, e1, e2, _) => expr_size(e1) + expr_size(e2),
+        Expr::If(c, t, f) => expr_size(c) + expr_size(t) + expr_size(f),
+        Expr::Block(items) | Expr::Call(_, items, _) => items.iter().map(expr_size).sum(),
+    }
+}
+
+/// Adds every variable name `e` mentions, bound or not, to `out`.
+fn collect_names(e: &Expr, out: &mut HashSet<String>) {
+    match e {
+        Expr::Num(_)
+        | Expr::Float(_)
+        | Expr::Bool(_)
+        | Expr::Input
+        | Expr::Argc
+        | Expr::Read(..) => {}
+        Expr::Var(name) => {
+            out.insert(name.clone());
+        }
+        Expr::Let(bindings, body) => {
+            for (name, rhs) in bindings {
+                out.insert(name.clone());
+                collect_names(rhs, out);
+            }
+            collect_names(body, out);
+        }
+        Expr::Set(name, sub) => {
+            out.insert(name.clone());
+            collect_names(sub, out);
+        }
+        Expr::UnOp(_, sub, _) | Expr::Loop(sub) | Expr::Break(sub) => collect_names(sub, out),
+        Expr::BinOp(_, e1, e2, _) => {
+            collect_names(e1, out);
+            collect_names(e2, out);
+        }
+        Expr::If(c, t, f) => {
+            collect_names(c, out);
+            collect_names(t, out);
+            collect_names(f, out);
+        }
+        Expr::Block(items) | Expr::Call(_, items, _) => {
+            for it in items {
+                collect_names(it, out);
+            }
+        }
+    }
+}
+
+/// Adds the names `e` uses without binding them, other than those in
+/// `bound`, to `out`: in a function body, the globals it reads or sets.
+fn collect_free_names(e: &Expr, bound: &HashSet<String>, out: &mut HashSet<String>) {
+    let visit = |sub: &Expr, out: &mut HashSet<String>| collect_free_names(sub, bound, out);
+    match e {
+        Expr::Num(_)
+        | Expr::Float(_)
+        | Expr::Bool(_)
+        | Expr::Input
+        | Expr::Argc
+        | Expr::Read(..) => {}
+        Expr::Var(name) => {
+            if !bound.contains(name) {
+                out.insert(name.clone());
+            }
+        }
+        Expr::Let(bindings, body) => {
+            let mut inner = bound.clone();
+            for (name, rhs) in bindings {
+                collect_free_names(rhs, &inner, out);
+                inner.insert(name.clone());
+            }
+            collect_free_names(body, &inner, out);
+        }
+        Expr::Set(name, sub) => {
+            if !bound.contains(name) {
+                out.insert(name.clone());
+            }
+            visit(sub, out);
+        }
+        Expr::UnOp(_, sub, _) | Expr::Loop(sub) | Expr::Break(sub) => visit(sub, out),
+        Expr::BinOp(_, e1, e2, _) => {
+            visit(e1, out);
+            visit(e2, out);
+        }
+        Expr::If(c, t, f) => {
+            visit(c, out);
+            visit(t, out);
+            visit(f, out);
+        }
+        Expr::Block(items) | Expr::Call(_, items, _) => {
+            for it in items {
+                visit(it, out);
+            }
+        }
+    }
+}
+
+/// Whether `e` prints or reads stdin.
+fn performs_io(e: &Expr) -> bool {
+    match e {
+        Expr::Read(..) | Expr::UnOp(UnOp::Print, _, _) => true,
+        Expr::Num(_) | Expr::Float(_) | Expr::Bool(_) | Expr::Input | Expr::Argc | Expr::Var(_) => {
+            false
+        }
+        Expr::Let(bindings, body) => {
+            bindings.iter().any(|(_, rhs)| performs_io(rhs)) || performs_io(body)
+        }
+        Expr::UnOp(_, sub, _) | Expr::Loop(sub) | Expr::Break(sub) | Expr::Set(_, sub) => {
+            performs_io(sub)
+        }
+        Expr::BinOp(_, e1, e2, _) => performs_io(e1) || performs_io(e2),
+        Expr::If(c, t, f) => performs_io(c) || performs_io(t) || performs_io(f),
+        Expr::Block(items) | Expr::Call(_, items, _) => items.iter().any(performs_io),
+    }
+}
+
+/// Functions whose calls have no effect beyond their result, which depends
+/// only on the arguments: they neither print, read stdin nor touch a mutable
+/// global, and call only other such functions. Externs never qualify.
+fn pure_functions(prog: &Program) -> HashSet<String> {
+    let mutable: HashSet<String> = prog
+        .globals
+        .iter()
+        .filter(|g| matches!(g, Global::Define(..)))
+        .map(|g| global_name(g).to_string())
+        .collect();
+    let mut pure: HashSet<String> = prog
+        .defns
+        .iter()
+        .filter(|defn| {
+            let mut free = HashSet::new();
+            let params = defn.params.iter().cloned().collect();
+            collect_free_names(&defn.body, &params, &mut free);
+            !performs_io(&defn.body) && free.is_disjoint(&mutable)
+        })
+        .map(|defn| defn.name.clone())
+        .collect();
+    // Drop functions calling impure ones until none is left.
+    loop {
+        let impure: Vec<&Definition> = prog
+            .defns
+            .iter()
+            .filter(|defn| pure.contains(&defn.name))
+            .filter(|defn| {
+                let mut called = Vec::new();
+                collect_calls(&defn.body, &mut called);
+                called.iter().any(|callee| !pure.contains(callee))
+            })
+            .collect();
+        if impure.is_empty() {
+            return pure;
+        }
+        for defn in impure {
+            pure.remove(&defn.name);
+        }
+    }
+}
+
+/// A function `inline_program` may copy into its callers.
+struct Inlinable {
+    params: Vec<String>,
+    body: Expr,
+    /// Globals the body refers to; a call site where a local hides one of
+    /// them is left alone.
+    free: HashSet<String>,
+}
+
+struct Inliner<'a> {
+    inlinable: HashMap<String, Inlinable>,
+    /// Every name in the program, so renamed locals never collide.
+    taken: &'a mut HashSet<String>,
+}
+
+impl Inliner<'_> {
+    /// An unused name for a copy of the local `name`.
+    fn rename_local(&mut self, name: &str) -> String {
+        let mut n = 0;
+        loop {
+            let candidate = format!("{}%{}", name, n);
+            if self.taken.insert(candidate.clone()) {
+                return candidate;
+            }
+            n += 1;
+        }
+    }
+
+    /// `e` with its `let`-bound names renamed apart; `names` maps the ones in
+    /// scope to their new names. Calls and globals are left alone.
+    fn rename(&mut self, e: &Expr, names: &HashMap<String, String>) -> Expr {
+        let renamed = |name: &String| names.get(name).unwrap_or(name).clone();
+        match e {
+            Expr::Num(_)
+            | Expr::Float(_)
+            | Expr::Bool(_)
+            | Expr::Input
+            | Expr::Argc
+            | Expr::Read(..) => e.clone(),
+            Expr::Var(name) => Expr::Var(renamed(name)),
+            Expr::Let(bindings, body) => {
+                let mut inner = names.clone();
+                let mut renamed_bindings = Vec::new();
+                for (name, rhs) in bindings {
+                    let rhs = self.rename(rhs, &inner);
+                    let fresh = self.rename_local(name);
+                    inner.insert(name.clone(), fresh.clone());
+                    renamed_bindings.push((fresh, rhs));
+                }
+                Expr::Let(renamed_bindings, Box::new(self.rename(body, &inner)))
+            }
+            Expr::UnOp(op, sub, pos) => {
+                Expr::UnOp(op.clone(), Box::new(self.rename(sub, names)), *pos)
+            }
+            Expr::BinOp(op, e1, e2, pos) => Expr::BinOp(
+                op.clone(),
+                Box::new(self.rename(e1, names)),
+                Box::new(self.rename(e2, names)),
+                *pos,
+            ),
+            Expr::If(c, t, f) => Expr::If(
+                Box::new(self.rename(c, names)),
+                Box::new(self.rename(t, names)),
+                Box::new(self.rename(f, names)),
+            ),
+            Expr::Block(items) => {
+                Expr::Block(items.iter().map(|it| self.rename(it, names)).collect())
+            }
+            Expr::Loop(body) => Expr::Loop(Box::new(self.rename(body, names))),
+            Expr::Break(inner) => Expr::Break(Box::new(self.rename(inner, names))),
+            Expr::Set(name, rhs) => Expr::Set(renamed(name), Box::new(self.rename(rhs, names))),
+            Expr::Call(name, args, pos) => Expr::Call(
+                name.clone(),
+                args.iter().map(|arg| self.rename(arg, names)).collect(),
+                *pos,
+            ),
+        }
+    }
+
+    /// `e` with calls to inlinable functions replaced by a `let` binding
+    /// renamed copies of their parameters to the arguments around a renamed
+    /// copy of their body. `locals` are the caller's names in scope.
+    fn inline(&mut self, e: &Expr, locals: &HashSet<String>) -> Expr {
+        match e {
+            Expr::Num(_)
+            | Expr::Float(_)
+            | Expr::Bool(_)
+            | Expr::Input
+            | Expr::Argc
+            | Expr::Read(..)
+            | Expr::Var(_) => e.clone(),
+            Expr::Let(bindings, body) => {
+                let mut inner = locals.clone();
+                let mut kept = Vec::new();
+                for (name, rhs) in bindings {
+                    kept.push((name.clone(), self.inline(rhs, &inner)));
+                    inner.insert(name.clone());
+                }
+                Expr::Let(kept, Box::new(self.inline(body, &inner)))
+            }
+            Expr::UnOp(op, sub, pos) => {
+                Expr::UnOp(op.clone(), Box::new(self.inline(sub, locals)), *pos)
+            }
+            Expr::BinOp(op, e1, e2, pos) => Expr::BinOp(
+                op.clone(),
+                Box::new(self.inline(e1, locals)),
+                Box::new(self.inline(e2, locals)),
+                *pos,
+            ),
+            Expr::If(c, t, f) => Expr::If(
+                Box::new(self.inline(c, locals)),
+                Box::new(self.inline(t, locals)),
+                Box::new(self.inline(f, locals)),
+            ),
+            Expr::Block(items) => {
+                Expr::Block(items.iter().map(|it| self.inline(it, locals)).collect())
+            }
+            Expr::Loop(body) => Expr::Loop(Box::new(self.inline(body, locals))),
+            Expr::Break(inner) => Expr::Break(Box::new(self.inline(inner, locals))),
+            Expr::Set(name, rhs) => Expr::Set(name.clone(), Box::new(self.inline(rhs, locals))),
+            Expr::Call(name, args, pos) => {
+                let args: Vec<Expr> = args.iter().map(|arg| self.inline(arg, locals)).collect();
+                let (params, body) = match self.inlinable.get(name) {
+                    Some(f) if f.free.is_disjoint(locals) => (f.params.clone(), f.body.clone()),
+                    _ => return Expr::Call(name.clone(), args, *pos),
+                };
+                let mut names = HashMap::new();
+                let mut bindings = Vec::new();
+                for (param, arg) in params.iter().zip(args) {
+                    let fresh = self.rename_local(param);
+                    names.insert(param.clone(), fresh.clone());
+                    bindings.push((fresh, arg));
+                }
+                let body = self.rename(&body, &names);
+                if bindings.is_empty() {
+                    body
+                } else {
+                    Expr::Let(bindings, Box::new(body))
+                }
+            }
+        }
+    }
+}
+
+/// Whether `name` can call itself, directly or through other functions.
+fn is_recursive(name: &str, calls: &HashMap<String, Vec<String>>) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = calls.get(name).cloned().unwrap_or_default();
+    while let Some(next) = stack.pop() {
+        if next == name {
+            return true;
+        }
+        if seen.insert(next.clone()) {
+            stack.extend(calls.get(&next).into_iter().flatten().cloned());
+        }
+    }
+    false
+}
+
+/// `prog` with calls to small non-recursive functions (`-O1` and above)
+/// replaced by their bodies. Functions are visited callees first, so a body
+/// is measured against `limit` after its own calls are inlined. Every
+/// definition is kept, since recursive callers still call theirs.
+fn inline_program(prog: &Program, limit: usize) -> Program {
+    let mut calls = HashMap::new();
+    for defn in &prog.defns {
+        let mut called = Vec::new();
+        collect_calls(&defn.body, &mut called);
+        calls.insert(defn.name.clone(), called);
+    }
+    // Post-order over the call graph: a non-recursive callee is finished
+    // before any of its callers.
+    let mut order = Vec::new();
+    let mut seen = HashSet::new();
+    for defn in &prog.defns {
+        if !seen.insert(defn.name.as_str()) {
+            continue;
+        }
+        let mut stack = vec![(defn.name.as_str(), 0)];
+        while let Some((name, i)) = stack.pop() {
+            match calls.get(name).and_then(|called| called.get(i)) {
+                Some(callee) => {
+                    stack.push((name, i + 1));
+                    if calls.contains_key(callee) && seen.insert(callee.as_str()) {
+                        stack.push((callee.as_str(), 0));
+                    }
+                }
+                None => order.push(name),
+            }
+        }
+    }
+
+    let mut taken = HashSet::new();
+    for defn in &prog.defns {
+        taken.extend(defn.params.iter().cloned());
+        collect_names(&defn.body, &mut taken);
+    }
+    for g in &prog.globals {
+        taken.insert(global_name(g).to_string());
+    }
+    collect_names(&prog.main, &mut taken);
+    let mut inliner = Inliner {
+        inlinable: HashMap::new(),
+        taken: &mut taken,
+    };
+
+    let mut inlined = prog.clone();
+    for name in order {
+        let defn = inlined.defns.iter_mut().find(|d| d.name == name).unwrap();
+        let params: HashSet<String> = defn.params.iter().cloned().collect();
+        defn.body = inliner.inline(&defn.body, &params);
+        if expr_size(&defn.body) <= limit && !is_recursive(name, &calls) {
+            let mut free = HashSet::new();
+            collect_free_names(&defn.body, &params, &mut free);
+            let f = Inlinable {
+                params: defn.params.clone(),
+                body: defn.body.clone(),
+                free,
+            };
+            inliner.inlinable.insert(name.to_string(), f);
+        }
+    }
+    inlined.main = inliner.inline(&prog.main, &HashSet::new());
+    inlined
+}
+
+/// Settings for one compilation, mostly from command-line flags.
+#[derive(Debug, Clone)]
+struct CompileOptions {
+    /// Path of the source file, as shown in runtime error messages.
+    source_name: String,
+    /// Merge the definitions in `src/prelude.snek` into the program; on
+    /// unless `--no-prelude` is given.
+    prelude: bool,
+    /// Count calls and inclusive cycles per function (`--profile`).
+    profile: bool,
+    /// Optimization level from `-O<n>`; 0 compiles expressions as written.
+    opt_level: u32,
+    /// Call Snek functions with the System V convention (`--sysv-calls`).
+    sysv_calls: bool,
+    /// Object format and platform the assembly is for (`--target`).
+    target: Target,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            source_name: String::new(),
+            prelude: true,
+            profile: false,
+            opt_level: 0,
+            sysv_calls: false,
+            target: Target::default(),
+        }
+    }
+}
+
+/// A platform the compiler can emit assembly for. The runtime is built for
+/// the same one, so it sees the symbols spelled the way its linker expects.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum Target {
+    /// ELF, assembled with `nasm -f elf64`.
+    #[default]
+    Linux,
+    /// Mach-O, assembled with `nasm -f macho64`.
+    MacOs,
+}
+
+impl Target {
+    fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "linux-x86_64" => Some(Target::Linux),
+            "macos-x86_64" => Some(Target::MacOs),
+            _ => None,
+        }
+    }
+
+    /// The platform the compiler runs on, used without `--target`.
+    fn host() -> Target {
+        if cfg!(target_os = "macos") {
+            Target::MacOs
+        } else {
+            Target::Linux
+        }
+    }
+
+    /// `name` as a symbol shared with Rust or C code: Mach-O prefixes C
+    /// names with `_`, and names NASM reserves are escaped.
+    fn symbol(self, name: &str) -> String {
+        let symbol = match self {
+            Target::Linux => name.to_string(),
+            Target::MacOs => format!("_{}", name),
+        };
+        if nasm_reserved(&symbol) {
+            format!("${}", symbol)
+        } else {
+            symbol
+        }
+    }
+}
+
+/// Whether NASM reads `name`, in any case, as a register or an operand
+/// keyword like `byte` or `rel` rather than as a symbol, unless it is
+/// escaped as `$name`.
+fn nasm_reserved(name: &str) -> bool {
+    const NAMES: &[&str] = &[
+        "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rsp", "rbp", "rip", "eax", "ebx", "ecx", "edx",
+        "esi", "edi", "esp", "ebp", "eip", "ax", "bx", "cx", "dx", "si", "di", "sp", "bp", "ip",
+        "al", "bl", "cl", "dl", "ah", "bh", "ch", "dh", "sil", "dil", "spl", "bpl", "cs", "ds",
+        "es", "fs", "gs", "ss", "st", "byte", "word", "dword", "qword", "tword", "oword", "yword",
+        "zword", "abs", "rel", "far", "near", "short", "strict", "seg", "wrt", "to", "nosplit",
+    ];
+    // Numbered register families and how many registers each has.
+    const NUMBERED: &[(&str, u32)] = &[
+        ("cr", 16),
+        ("dr", 16),
+        ("tr", 8),
+        ("st", 8),
+        ("mm", 8),
+        ("xmm", 32),
+        ("ymm", 32),
+        ("zmm", 32),
+        ("k", 8),
+        ("bnd", 4),
+        ("tmm", 8),
+    ];
+    let name = name.to_ascii_lowercase();
+    let index = |digits: &str| match digits.strip_prefix('0') {
+        Some(rest) if !rest.is_empty() => None,
+        _ => digits.parse::<u32>().ok(),
+    };
+    let wide = name.strip_prefix('r').is_some_and(|rest| {
+        let rest = rest.strip_suffix(['d', 'w', 'b']).unwrap_or(rest);
+        index(rest).is_some_and(|n| (8..16).contains(&n))
+    });
+    NAMES.contains(&name.as_str())
+        || wide
+        || NUMBERED.iter().any(|(family, count)| {
+            name.strip_prefix(family).and_then(index).is_some_and(|n| n < *count)
+        })
+}
+
+/// `i` with every symbol in `shared` spelled for `target`.
+fn link_symbols(i: &Instr, target: Target, shared: &HashSet<String>) -> Instr {
+    let spell = |name: &String| {
+        if shared.contains(name) {
+            target.symbol(name)
+        } else {
+            name.clone()
+        }
+    };
+    match i {
+        Instr::ICall(name) => Instr::ICall(spell(name)),
+        Instr::ILabel(name) => Instr::ILabel(spell(name)),
+        _ => map_operands(i, |v| match v {
+            Val::Rel(name, offset) => Val::Rel(spell(name), *offset),
+            _ => v.clone(),
+        }),
+    }
+}
+
+const PRELUDE: &str = include_str!("prelude.snek");
+
+/// The prelude's definitions. They carry no source positions, since those
+/// would be reported against the user's file.
+fn prelude_definitions() -> Vec<Definition> {
+    let sexp = parse(PRELUDE).unwrap_or_else(|e| panic!("Parse error in prelude: {}", e));
+    match &sexp {
+        Sexp::List(items) => items
+            .iter()
+            .map(|item| parse_definition(item, &Spans::new()))
+            .collect(),
+        _ => panic!("Prelude must be a list of definitions"),
+    }
+}
+
+/// `prog` plus the prelude functions it calls, directly or through other
+/// prelude functions. The program's own functions and externs take priority.
+fn with_prelude(prog: &Program) -> Program {
+    let mut defined: HashSet<&str> = prog.defns.iter().map(|d| d.name.as_str()).collect();
+    defined.extend(prog.externs.iter().map(|e| e.name.as_str()));
+    let prelude: Vec<Definition> = prelude_definitions()
+        .into_iter()
+        .filter(|d| !defined.contains(d.name.as_str()))
+        .collect();
+
+    let mut called = Vec::new();
+    for defn in &prog.defns {
+        collect_calls(&defn.body, &mut called);
+    }
+    for g in &prog.globals {
+        match g {
+            Global::Define(_, e) | Global::Const(_, e) => collect_calls(e, &mut called),
+        }
+    }
+    collect_calls(&prog.main, &mut called);
+
+    let mut used = HashSet::new();
+    while let Some(name) = called.pop() {
+        if let Some(defn) = prelude.iter().find(|d| d.name == name) {
+            if used.insert(name) {
+                collect_calls(&defn.body, &mut called);
+            }
+        }
+    }
+    let mut merged = prog.clone();
+    merged
+        .defns
+        .extend(prelude.into_iter().filter(|d| used.contains(&d.name)));
+    merged
+}
+
+/// Registers mentioned by an operand, including the base of a memory operand.
+fn val_regs(v: &Val) -> Vec<Reg> {
+    match v {
+        Val::Reg(r) | Val::Reg32(r) | Val::Reg8(r) | Val::RegOffset(r, _) => vec![*r],
+        Val::Imm(_) | Val::Rel(..) => vec![],
+    }
+}
+
+/// The register an instruction writes through operand `v`, if any.
+fn val_def(v: &Val) -> Option<Reg> {
+    match v {
+        Val::Reg(r) | Val::Reg32(r) | Val::Reg8(r) => Some(*r),
+        _ => None,
+    }
+}
+
+/// Caller-saved registers; every callee, Snek function or runtime helper,
+/// may overwrite them.
+const CALLER_SAVED: [Reg; 8] =
+    [Reg::Rax, Reg::Rcx, Reg::Rdx, Reg::Rsi, Reg::Rdi, Reg::R8, Reg::R9, Reg::R11];
+
+/// Registers whose value `i` reads. Calls read every argument register.
+fn instr_uses(i: &Instr) -> Vec<Reg> {
+    match i {
+        Instr::IMov(dst, src) | Instr::ILea(dst, src) => {
+            let mut regs = val_regs(src);
+            match dst {
+                // Writing the low byte keeps the rest of the register.
+                Val::Reg8(r) => regs.push(*r),
+                Val::Reg(_) | Val::Reg32(_) => {}
+                _ => regs.extend(val_regs(dst)),
+            }
+            regs
+        }
+        Instr::IAdd(dst, src)
+        | Instr::ISub(dst, src)
+        | Instr::IMul(dst, src)
+        | Instr::IAnd(dst, src)
+        | Instr::IOr(dst, src)
+        | Instr::ITest(dst, src)
+        | Instr::ICmp(dst, src)
+        | Instr::ISar(dst, src)
+        | Instr::IShl(dst, src)
+        // A `cmov` whose condition fails keeps the destination.
+        | Instr::ICmov(_, dst, src) => {
+            let mut regs = val_regs(dst);
+            regs.extend(val_regs(src));
+            regs
+        }
+        Instr::INeg(v) => val_regs(v),
+        Instr::IDiv(v) => {
+            let mut regs = val_regs(v);
+            regs.extend([Reg::Rax, Reg::Rdx]);
+            regs
+        }
+        Instr::ICqo => vec![Reg::Rax],
+        Instr::IPush(v) => {
+            let mut regs = val_regs(v);
+            regs.push(Reg::Rsp);
+            regs
+        }
+        Instr::IPop(v) => {
+            let mut regs = if val_def(v).is_some() { vec![] } else { val_regs(v) };
+            regs.push(Reg::Rsp);
+            regs
+        }
+        Instr::ICall(_) => vec![Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9, Reg::Rsp],
+        Instr::IRet => vec![Reg::Rax, Reg::Rsp],
+        Instr::IRdtsc | Instr::ILabel(_) | Instr::IJmp(_) | Instr::IJcc(..) => vec![],
+    }
+}
+
+/// Registers `i` overwrites.
+fn instr_defs(i: &Instr) -> Vec<Reg> {
+    match i {
+        Instr::IMov(dst, _)
+        | Instr::ILea(dst, _)
+        | Instr::IAdd(dst, _)
+        | Instr::ISub(dst, _)
+        | Instr::IMul(dst, _)
+        | Instr::IAnd(dst, _)
+        | Instr::IOr(dst, _)
+        | Instr::ISar(dst, _)
+        | Instr::IShl(dst, _)
+        | Instr::ICmov(_, dst, _)
+        | Instr::INeg(dst) => val_def(dst).into_iter().collect(),
+        Instr::IDiv(_) | Instr::IRdtsc => vec![Reg::Rax, Reg::Rdx],
+        Instr::ICqo => vec![Reg::Rdx],
+        Instr::IPush(_) => vec![Reg::Rsp],
+        Instr::IPop(v) => {
+            let mut regs: Vec<Reg> = val_def(v).into_iter().collect();
+            regs.push(Reg::Rsp);
+            regs
+        }
+        Instr::ICall(_) => CALLER_SAVED.to_vec(),
+        Instr::ITest(..)
+        | Instr::ICmp(..)
+        | Instr::IRet
+        | Instr::ILabel(_)
+        | Instr::IJmp(_)
+        | Instr::IJcc(..) => vec![],
+    }
+}
+
+/// Positions of the labels in `code`.
+fn label_positions(code: &[Instr]) -> HashMap<&str, usize> {
+    let mut labels = HashMap::new();
+    for (i, instr) in code.iter().enumerate() {
+        if let Instr::ILabel(name) = instr {
+            labels.insert(name.as_str(), i);
+        }
+    }
+    labels
+}
+
+/// Whether `r` is overwritten before being read on every path leaving
+/// `code[at]`. Jumps to labels outside `code` count as reads.
+fn reg_dead_after(code: &[Instr], at: usize, r: Reg, labels: &HashMap<&str, usize>) -> bool {
+    let mut pending = vec![at + 1];
+    let mut seen = HashSet::new();
+    while let Some(pc) = pending.pop() {
+        if pc >= code.len() || !seen.insert(pc) {
+            continue;
+        }
+        let instr = &code[pc];
+        if instr_uses(instr).contains(&r) {
+            return false;
+        }
+        if instr_defs(instr).contains(&r) {
+            continue;
+        }
+        match instr {
+            // The error tails read the operands, the site and the frame, and
+            // never return.
+            Instr::IJmp(target) if is_error_tail(target) => {
+                if [Reg::Rax, Reg::Rcx, Reg::Rsi, Reg::Rbp].contains(&r) {
+                    return false;
+                }
+            }
+            Instr::IJmp(target) | Instr::IJcc(_, target) => {
+                match labels.get(target.as_str()) {
+                    Some(&dest) => pending.push(dest),
+                    None => return false,
+                }
+                if matches!(instr, Instr::IJcc(..)) {
+                    pending.push(pc + 1);
+                }
+            }
+            Instr::IRet => {}
+            _ => pending.push(pc + 1),
+        }
+    }
+    true
+}
+
+/// A peephole rule: given the code and a position, the number of instructions
+/// it replaces there and their replacement.
+type PeepholeRule = fn(&[Instr], usize, &HashMap<&str, usize>) -> Option<(usize, Vec<Instr>)>;
+
+/// `mov x, r` followed by a load of `x`, a memory operand or a register the
+/// allocator gave a slot: the value is still in `r`.
+fn peep_store_load(
+    code: &[Instr],
+    i: usize,
+    _: &HashMap<&str, usize>,
+) -> Option<(usize, Vec<Instr>)> {
+    match (&code[i], code.get(i + 1)?) {
+        (Instr::IMov(mem, Val::Reg(src)), Instr::IMov(Val::Reg(dst), loaded))
+            if (is_memory(mem) || matches!(mem, Val::Reg(_)))
+                && mem == loaded
+                && *mem != reg(*dst) =>
+        {
+            let mut out = vec![code[i].clone()];
+            if dst != src {
+                out.push(Instr::IMov(reg(*dst), reg(*src)));
+            }
+            Some((2, out))
+        }
+        _ => None,
+    }
+}
+
+/// A tag check through the scratch register, `mov r11, x` then either
+/// `and r11, m` / `cmp r11, 0` or `test r11, m`, feeding `je`/`jne` or, past
+/// moves of constants, `cmove`/`cmovne`, becomes `test x, m` when nothing
+/// reads `r11` afterwards. With a register `x` and a mask that fits in a byte
+/// this is `test al, 1`.
+fn peep_tag_test(
+    code: &[Instr],
+    i: usize,
+    labels: &HashMap<&str, usize>,
+) -> Option<(usize, Vec<Instr>)> {
+    let src = match &code[i] {
+        Instr::IMov(Val::Reg(Reg::R11), src @ Val::Reg(r)) if *r != Reg::R11 => src,
+        Instr::IMov(Val::Reg(Reg::R11), src) if is_memory(src) => src,
+        _ => return None,
+    };
+    let (mask, jcc_at) = match (code.get(i + 1)?, code.get(i + 2)?) {
+        (
+            Instr::IAnd(Val::Reg(Reg::R11), Val::Imm(m)),
+            Instr::ICmp(Val::Reg(Reg::R11), Val::Imm(0)),
+        ) => (*m, i + 3),
+        (Instr::ITest(Val::Reg(Reg::R11), Val::Imm(m)), _) => (*m, i + 2),
+        _ => return None,
+    };
+    let mut end = jcc_at;
+    while let Instr::IMov(Val::Reg(_), Val::Imm(_)) = code.get(end)? {
+        end += 1;
+    }
+    if !matches!(
+        code[end],
+        Instr::IJcc(Cond::E | Cond::Ne, _) | Instr::ICmov(Cond::E | Cond::Ne, ..)
+    ) {
+        return None;
+    }
+    let overwritten = code[jcc_at..end].iter().any(|m| instr_defs(m).contains(&Reg::R11));
+    if !overwritten && !reg_dead_after(code, end, Reg::R11, labels) {
+        return None;
+    }
+    let tested = match src {
+        Val::Reg(r) if (0..=255).contains(&mask) => Val::Reg8(*r),
+        _ => src.clone(),
+    };
+    let mut out = vec![Instr::ITest(tested, Val::Imm(mask))];
+    out.extend_from_slice(&code[jcc_at..=end]);
+    Some((end + 1 - i, out))
+}
+
+/// A jump, conditional or not, to a label that directly follows it.
+fn peep_jump_to_next(
+    code: &[Instr],
+    i: usize,
+    _: &HashMap<&str, usize>,
+) -> Option<(usize, Vec<Instr>)> {
+    let target = match &code[i] {
+        Instr::IJmp(target) | Instr::IJcc(_, target) => target,
+        _ => return None,
+    };
+    for next in &code[i + 1..] {
+        match next {
+            Instr::ILabel(name) if name == target => return Some((1, vec![])),
+            Instr::ILabel(_) => {}
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// The rewrites `peephole` applies, by name.
+const PEEPHOLE_RULES: [(&str, PeepholeRule); 3] = [
+    ("store-load", peep_store_load),
+    ("tag-test", peep_tag_test),
+    ("jump-to-next", peep_jump_to_next),
+];
+
+/// One left-to-right sweep applying the first rule that matches at each
+/// position. Returns the new code and whether anything changed.
+fn peephole_pass(code: &[Instr]) -> (Vec<Instr>, bool) {
+    let labels = label_positions(code);
+    let mut out = Vec::with_capacity(code.len());
+    let mut changed = false;
+    let mut i = 0;
+    while i < code.len() {
+        let rewrite = PEEPHOLE_RULES
+            .iter()
+            .find_map(|(_, rule)| rule(code, i, &labels));
+        match rewrite {
+            Some((consumed, replacement)) => {
+                out.extend(replacement);
+                i += consumed;
+                changed = true;
+            }
+            None => {
+                out.push(code[i].clone());
+                i += 1;
+            }
+        }
+    }
+    (out, changed)
+}
+
+/// Rewrites short instruction sequences into cheaper equivalents (`-O1` and
+/// above), repeating since one rewrite can expose another.
+fn peephole(mut code: Vec<Instr>) -> Vec<Instr> {
+    loop {
+        let (next, changed) = peephole_pass(&code);
+        code = next;
+        if !changed {
+            return code;
+        }
+    }
+}
+
+/// Callee-saved registers, in the order the register allocator hands them
+/// out. Snek functions and runtime helpers alike preserve them across calls.
+const CALLEE_SAVED: [Reg; 5] = [Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+/// The offset `off` of a stack slot operand `[rbp - off]`.
+fn frame_slot(v: &Val) -> Option<i32> {
+    match v {
+        Val::RegOffset(Reg::Rbp, o) if *o < 0 => Some(-o),
+        _ => None,
+    }
+}
+
+/// Stack slots `i` reads and writes. A `mov` into a slot only writes it.
+fn slot_accesses(i: &Instr) -> (Vec<i32>, Vec<i32>) {
+    let read = |v: &Val| frame_slot(v).into_iter().collect::<Vec<_>>();
+    match i {
+        Instr::IMov(dst, src) => (read(src), read(dst)),
+        Instr::ILea(_, src) | Instr::ICmov(_, _, src) => {
+            (read(src), vec![])
+        }
+        Instr::ITest(a, b) | Instr::ICmp(a, b) => {
+            let mut uses = read(a);
+            uses.extend(read(b));
+            (uses, vec![])
+        }
+        Instr::IAdd(dst, src)
+        | Instr::ISub(dst, src)
+        | Instr::IMul(dst, src)
+        | Instr::IAnd(dst, src)
+        | Instr::IOr(dst, src)
+        | Instr::ISar(dst, src)
+        | Instr::IShl(dst, src) => {
+            let mut uses = read(dst);
+            uses.extend(read(src));
+            (uses, read(dst))
+        }
+        Instr::INeg(v) => (read(v), read(v)),
+        Instr::IDiv(v) | Instr::IPush(v) => (read(v), vec![]),
+        Instr::IPop(v) => (vec![], read(v)),
+        Instr::ICqo
+        | Instr::IRdtsc
+        | Instr::ICall(_)
+        | Instr::IRet
+        | Instr::ILabel(_)
+        | Instr::IJmp(_)
+        | Instr::IJcc(..) => (vec![], vec![]),
+    }
+}
+
+/// `i` with every operand passed through `f`.
+fn map_operands(i: &Instr, f: impl Fn(&Val) -> Val) -> Instr {
+    match i {
+        Instr::IMov(a, b) => Instr::IMov(f(a), f(b)),
+        Instr::ILea(a, b) => Instr::ILea(f(a), f(b)),
+        Instr::IAdd(a, b) => Instr::IAdd(f(a), f(b)),
+        Instr::ISub(a, b) => Instr::ISub(f(a), f(b)),
+        Instr::IMul(a, b) => Instr::IMul(f(a), f(b)),
+        Instr::IAnd(a, b) => Instr::IAnd(f(a), f(b)),
+        Instr::IOr(a, b) => Instr::IOr(f(a), f(b)),
+        Instr::ITest(a, b) => Instr::ITest(f(a), f(b)),
+        Instr::ICmp(a, b) => Instr::ICmp(f(a), f(b)),
+        Instr::ISar(a, b) => Instr::ISar(f(a), f(b)),
+        Instr::IShl(a, b) => Instr::IShl(f(a), f(b)),
+        Instr::ICmov(c, a, b) => Instr::ICmov(*c, f(a), f(b)),
+        Instr::INeg(v) => Instr::INeg(f(v)),
+        Instr::IDiv(v) => Instr::IDiv(f(v)),
+        Instr::IPush(v) => Instr::IPush(f(v)),
+        Instr::IPop(v) => Instr::IPop(f(v)),
+        other => other.clone(),
+    }
+}
+
+/// The stack slots live on entry to each instruction of `body`. A jump to a
+/// label outside `body`, other than an error tail, is assumed to read every
+/// slot in `candidates`.
+fn live_slots(body: &[Instr], candidates: &HashSet<i32>) -> Vec<HashSet<i32>> {
+    let labels = label_positions(body);
+    let accesses: Vec<(Vec<i32>, Vec<i32>)> = body.iter().map(slot_accesses).collect();
+    let mut live_in: Vec<HashSet<i32>> = vec![HashSet::new(); body.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pc in (0..body.len()).rev() {
+            let mut live: HashSet<i32> = HashSet::new();
+            let flow_to = |target: Option<usize>, live: &mut HashSet<i32>| match target {
+                Some(dest) if dest < body.len() => live.extend(&live_in[dest]),
+                Some(_) => {}
+                None => live.extend(candidates),
+            };
+            match &body[pc] {
+                // The error tails never return.
+                Instr::IJmp(target) if is_error_tail(target) => {}
+                Instr::IJmp(target) => flow_to(labels.get(target.as_str()).copied(), &mut live),
+                Instr::IJcc(_, target) => {
+                    flow_to(labels.get(target.as_str()).copied(), &mut live);
+                    flow_to(Some(pc + 1), &mut live);
+                }
+                Instr::IRet => {}
+                _ => flow_to(Some(pc + 1), &mut live),
+            }
+            let (uses, defs) = &accesses[pc];
+            for d in defs {
+                if !uses.contains(d) {
+                    live.remove(d);
+                }
+            }
+            live.extend(uses);
+            live.retain(|off| candidates.contains(off));
+            if live != live_in[pc] {
+                live_in[pc] = live;
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+/// Linear-scan register allocation (`-O2` and above) of the stack slots
+/// `[rbp - 8]` to `[rbp - max_depth]` that `body`, a function body or main,
+/// keeps its `let` bindings and operator temporaries in. Each slot's live
+/// interval runs from the first to the last instruction that accesses it or
+/// has it live; slots get the registers in `CALLEE_SAVED`, and when more
+/// intervals overlap than there are registers the one ending last stays in its
+/// stack slot. Returns the rewritten body and the registers it uses, which
+/// the caller must save and restore.
+fn allocate_registers(body: &[Instr], max_depth: i32) -> (Vec<Instr>, Vec<Reg>) {
+    let mut candidates: HashSet<i32> = HashSet::new();
+    let mut escaped: HashSet<i32> = HashSet::new();
+    for instr in body {
+        let (uses, defs) = slot_accesses(instr);
+        candidates.extend(uses.into_iter().chain(defs).filter(|off| *off <= max_depth));
+        if let Instr::ILea(_, src) = instr {
+            escaped.extend(frame_slot(src));
+        }
+    }
+    candidates.retain(|off| !escaped.contains(off));
+
+    let live_in = live_slots(body, &candidates);
+    let mut intervals: HashMap<i32, (usize, usize)> = HashMap::new();
+    for (pc, instr) in body.iter().enumerate() {
+        let (uses, defs) = slot_accesses(instr);
+        for off in live_in[pc].iter().chain(&uses).chain(&defs) {
+            if candidates.contains(off) {
+                let span = intervals.entry(*off).or_insert((pc, pc));
+                span.0 = span.0.min(pc);
+                span.1 = span.1.max(pc);
+            }
+        }
+    }
+    let mut order: Vec<(usize, usize, i32)> =
+        intervals.iter().map(|(off, (start, end))| (*start, *end, *off)).collect();
+    order.sort();
+
+    let mut assigned: HashMap<i32, Reg> = HashMap::new();
+    let mut active: Vec<(usize, i32)> = Vec::new();
+    for (start, end, off) in order {
+        active.retain(|(active_end, _)| *active_end >= start);
+        let taken: Vec<Reg> = active.iter().map(|(_, o)| assigned[o]).collect();
+        if let Some(free) = CALLEE_SAVED.iter().find(|r| !taken.contains(r)) {
+            assigned.insert(off, *free);
+            active.push((end, off));
+            continue;
+        }
+        let (furthest, victim) = active.iter().copied().max().unwrap();
+        if furthest > end {
+            let r = assigned.remove(&victim).unwrap();
+            assigned.insert(off, r);
+            active.retain(|(_, o)| *o != victim);
+            active.push((end, off));
+        }
+    }
+
+    let rewritten = body
+        .iter()
+        .map(|instr| {
+            map_operands(instr, |v| match frame_slot(v).and_then(|off| assigned.get(&off)) {
+                Some(r) => reg(*r),
+                None => v.clone(),
+            })
+        })
+        .collect();
+    let used = CALLEE_SAVED
+        .iter()
+        .copied()
+        .filter(|r| assigned.values().any(|a| a == r))
+        .collect();
+    (rewritten, used)
+}
+
+/// Frame slots below a frame of `frame_bytes` (16-byte aligned) for saving
+/// the callee-saved registers in `regs`, and the enlarged frame size.
+fn save_slots(regs: &[Reg], frame_bytes: i32) -> (Vec<(Reg, i32)>, i32) {
+    let slots = regs
+        .iter()
+        .enumerate()
+        .map(|(i, r)| (*r, frame_bytes + 8 * (i as i32 + 1)))
+        .collect();
+    (slots, frame_bytes + align_to_16(8 * regs.len() as i32))
+}
+
+/// NUL-terminated string as a `db` operand list, so any identifier is safe to embed.
+fn c_string_bytes(s: &str) -> String {
+    let mut bytes: Vec<String> = s.bytes().map(|b| b.to_string()).collect();
+    bytes.push("0".to_string());
+    bytes.join(", ")
+}
+
+/// The names a function body, global initializer or main may refer to.
+struct Validator<'a> {
+    arities: HashMap<&'a str, usize>,
+    /// Globals visible so far, and whether each is a `const`.
+    globals: HashMap<&'a str, bool>,
+    params: &'a [String],
+}
+
+impl<'a> Validator<'a> {
+    /// Checks `e` in the order lowering visits it, so the first error
+    /// reported is the same one lowering would find.
+    fn check(&self, e: &'a Expr, scope: &HashSet<&'a str>, in_loop: bool) {
+        let known = |name: &str| scope.contains(name) || self.params.iter().any(|p| p == name);
+        match e {
+            Expr::Num(_)
+            | Expr::Bool(_)
+            | Expr::Float(_)
+            | Expr::Input
+            | Expr::Argc
+            | Expr::Read(..) => {}
+            Expr::Var(name) => {
+                if !known(name) && !self.globals.contains_key(name.as_str()) {
+                    panic!("Unbound variable: {}", name);
+                }
+            }
+            Expr::Let(bindings, body) => {
+                let mut inner = scope.clone();
+                for (name, rhs) in bindings {
+                    if self.params.contains(name) {
+                        panic!("Cannot shadow parameter with let: {}", name);
+                    }
+                    self.check(rhs, &inner, in_loop);
+                    inner.insert(name);
+                }
+                self.check(body, &inner, in_loop);
+            }
+            Expr::UnOp(_, sub, _) => self.check(sub, scope, in_loop),
+            Expr::BinOp(_, e1, e2, _) => {
+                self.check(e1, scope, in_loop);
+                self.check(e2, scope, in_loop);
+            }
+            Expr::If(c, t, f) => {
+                self.check(c, scope, in_loop);
+                self.check(t, scope, in_loop);
+                self.check(f, scope, in_loop);
+            }
+            Expr::Block(items) => items.iter().for_each(|it| self.check(it, scope, in_loop)),
+            Expr::Loop(body) => self.check(body, scope, true),
+            Expr::Break(inner) => {
+                if !in_loop {
+                    panic!("break outside of loop");
+                }
+                self.check(inner, scope, in_loop);
+            }
+            Expr::Set(name, rhs) => {
+                if !known(name) {
+                    match self.globals.get(name.as_str()) {
+                        Some(false) => {}
+                        Some(true) => panic!("Cannot set! constant: {}", name),
+                        None => panic!("set! on unknown binding: {}", name),
+                    }
+                }
+                self.check(rhs, scope, in_loop);
+            }
+            Expr::Call(name, args, _) => {
+                let expected = match self.arities.get(name.as_str()) {
+                    Some(arity) => *arity,
+                    None => panic!("Undefined function: {}", name),
+                };
+                if expected != args.len() {
+                    panic!(
+                        "Wrong number of arguments in call to {}: expected {}, got {}",
+                        name,
+                        expected,
+                        args.len()
+                    );
+                }
+                args.iter().for_each(|arg| self.check(arg, scope, in_loop));
+            }
+        }
+    }
+}
+
+/// Reports the compile errors in `prog` as written, before folding or
+/// inlining can remove or move the code containing them: duplicate
+/// definitions, then the bodies of the functions, the global initializers
+/// and main, in that order.
+fn validate_program(prog: &Program) {
+    let mut arities = HashMap::new();
+    for (name, arity) in prog
+        .defns
+        .iter()
+        .map(|d| (&d.name, d.params.len()))
+        .chain(prog.externs.iter().map(|e| (&e.name, e.params.len())))
+    {
+        if arities.insert(name.as_str(), arity).is_some() {
+            panic!("Duplicate function definition: {}", name);
+        }
+    }
+    let mut globals = HashMap::new();
+    for g in &prog.globals {
+        if globals.insert(global_name(g), matches!(g, Global::Const(..))).is_some() {
+            panic!("Duplicate global definition: {}", global_name(g));
+        }
+    }
+
+    let mut v = Validator {
+        arities,
+        globals,
+        params: &[],
+    };
+    for defn in &prog.defns {
+        v.params = &defn.params;
+        v.check(&defn.body, &HashSet::new(), false);
+    }
+    // Initializers may only see earlier globals.
+    let all = std::mem::take(&mut v.globals);
+    v.params = &[];
+    for g in &prog.globals {
+        if let Global::Define(_, init) = g {
+            v.check(init, &HashSet::new(), false);
+        }
+        v.globals.insert(global_name(g), all[global_name(g)]);
+    }
+    v.check(&prog.main, &HashSet::new(), false);
+}
+
+fn compile_program(prog: &Program, opts: &CompileOptions) -> String {
+    let merged;
+    let prog = if opts.prelude {
+        merged = with_prelude(prog);
+        &merged
+    } else {
+        prog
+    };
+    validate_program(prog);
+    let folded;
+    let prog = if opts.opt_level >= 1 {
+        // Profiles count calls, so functions stay whole when profiling.
+        folded = if opts.profile {
+            fold_program(prog)
+        } else {
+            fold_program(&inline_program(prog, inline_limit(opts.opt_level)))
+        };
+        &folded
+    } else {
+        prog
+    };
+    let mut arities = HashMap::new();
+    for defn in &prog.defns {
+        arities.insert(defn.name.clone(), defn.params.len());
+    }
+    let mut externs = HashSet::new();
+    for ext in &prog.externs {
+        arities.insert(ext.name.clone(), ext.params.len());
+        externs.insert(ext.name.clone());
+    }
+
+    let mut globals = HashMap::new();
+    for g in &prog.globals {
+        let binding = match g {
+            Global::Define(..) => GlobalBinding::Mutable,
+            Global::Const(_, lit) => GlobalBinding::Const(lit.clone()),
+        };
+        globals.insert(global_name(g).to_string(), binding);
+    }
+
+    let call_sites = RefCell::new(Vec::new());
+    let error_sites = RefCell::new(Vec::new());
+    let mut seq = 0i32;
+    // Symbols shared with the runtime and foreign code, which the target may
+    // spell differently from the labels used here.
+    let mut imports: Vec<String> = [
+        "snek_error",
+        "snek_print",
+        "snek_arith",
+        "snek_to_float",
+        "snek_truncate",
+        "snek_arg",
+        "snek_argc",
+        "snek_read_num",
+        "snek_read_bool",
+        "snek_read_line",
+        "INPUT_VAL",
+        "STACK_LIMIT",
+    ]
+    .map(String::from)
+    .to_vec();
+    let mut exports: Vec<String> = [
+        "our_code_starts_here",
+        "snek_function_table",
+        "snek_function_count",
+        "snek_call_site_table",
+        "snek_call_site_count",
+        "snek_error_site_table",
+        "snek_error_site_count",
+        "snek_source_name",
+        "snek_profile_enabled",
+        "snek_profile_counters",
+    ]
+    .map(String::from)
+    .to_vec();
+    for ext in &prog.externs {
+        if !imports.contains(&ext.name) {
+            imports.push(ext.name.clone());
+        }
+    }
+    // With register arguments, Rust and C code can call Snek functions too.
+    if opts.sysv_calls {
+        exports.extend(prog.defns.iter().map(|defn| format!("fun_{}", defn.name)));
+    }
+    let sym = |name: &str| opts.target.symbol(name);
+    // Mach-O has no 32-bit absolute addresses, and Rust links ELF executables
+    // as position independent, so every target addresses memory `rel`.
+    let mut lines = vec!["section .text".to_string(), "default rel".to_string()];
+    lines.extend(imports.iter().map(|name| format!("extern {}", sym(name))));
+    lines.extend(exports.iter().map(|name| format!("global {}", sym(name))));
+
+    let pure = if opts.profile {
+        HashSet::new()
+    } else {
+        pure_functions(prog)
+    };
+    let frame = Cell::new(0);
+    let stubs = RefCell::new(Vec::new());
+    let ctx = Ctx {
+        arities: &arities,
+        externs: &externs,
+        globals: &globals,
+        params: &[],
+        frame: &frame,
+        call_sites: &call_sites,
+        error_sites: &error_sites,
+        stubs: &stubs,
+        use_types: opts.opt_level >= 1,
+        prune: opts.opt_level >= 1,
+        pure: &pure,
+        sysv_calls: opts.sysv_calls,
+    };
+    // Profile rows follow `snek_function_table`: functions, then main.
+    let profile_row = |i: usize| if opts.profile { Some(i) } else { None };
+    let allocate = opts.opt_level >= 2;
+    let mut code = Vec::new();
+    for (i, defn) in prog.defns.iter().enumerate() {
+        code.extend(compile_definition(defn, &ctx, profile_row(i), allocate, &mut seq));
+    }
+
+    let mut body = Vec::new();
+
+    // Initializers run in declaration order and may only see earlier globals.
+    let mut visible = HashMap::new();
+    for g in &prog.globals {
+        if let Global::Define(name, init) = g {
+            let init_ctx = Ctx {
+                globals: &visible,
+                ..ctx
+            };
+            body.extend(emit_body(init, &init_ctx, &mut seq));
+            body.push(Instr::IMov(rel(&global_label(name)), reg(Reg::Rax)));
+        }
+        let name = global_name(g);
+        visible.insert(name.to_string(), globals[name].clone());
+    }
+
+    body.extend(emit_body(&prog.main, &ctx, &mut seq));
+    let main_depth = frame.get();
+    let (body, main_stubs, saved) =
+        allocate_with_stubs(body, stubs.take(), main_depth, allocate);
+    let (saves, mut main_frame) = save_slots(&saved, align_to_16(main_depth));
+    let main_profile = profile_row(prog.defns.len()).map(|index| {
+        let (p, bytes) = Profile::below(index, main_frame);
+        main_frame = bytes;
+        p
+    });
+    code.push(Instr::ILabel("our_code_starts_here".to_string()));
+    append_prologue(&mut code, main_frame);
+    append_saves(&mut code, &saves);
+    if let Some(p) = &main_profile {
+        p.append_entry(&mut code, false);
+    }
+    code.extend(body);
+    if let Some(p) = &main_profile {
+        p.append_exit(&mut code, &mut seq);
+    }
+    append_restores(&mut code, &saves);
+    append_epilogue(&mut code);
+    code.extend(main_stubs);
+    code.push(Instr::ILabel("our_code_ends_here".to_string()));
+
+    // Shared target of the stack check in every function prologue. It has no
+    // error site; the runtime starts the backtrace at the incomplete frame's caller.
+    code.push(Instr::ILabel("stack_overflow".to_string()));
+    code.push(Instr::IMov(reg(Reg::Rdi), Val::Imm(5)));
+    append_site_args(&mut code, -1);
+    code.push(Instr::ICall("snek_error".to_string()));
+    append_error_tails(&mut code);
+    if opts.opt_level >= 1 {
+        code = peephole(code);
+    }
+    let shared: HashSet<String> = imports.iter().chain(&exports).cloned().collect();
+    lines.extend(code.iter().map(|i| instr_to_str(&link_symbols(i, opts.target, &shared))));
+
+    // Tables the runtime uses to turn return addresses into a backtrace:
+    // (start, end, name) per function and (return address, line, column) per call.
+    lines.push("section .data".to_string());
+    lines.push("align 8".to_string());
+    lines.push(format!("{}:", sym("snek_function_table")));
+    for (i, defn) in prog.defns.iter().enumerate() {
+        let start = format!("fun_{}", defn.name);
+        let start = if opts.sysv_calls { sym(&start) } else { start };
+        lines.push(format!("dq {}, endfun_{}, fname_{}", start, defn.name, i));
+    }
+    let main_index = prog.defns.len();
+    lines.push(format!(
+        "dq {}, our_code_ends_here, fname_{}",
+        sym("our_code_starts_here"),
+        main_index
+    ));
+    lines.push(format!("{}: dq {}", sym("snek_function_count"), prog.defns.len() + 1));
+    lines.push(format!("{}:", sym("snek_call_site_table")));
+    let call_sites = call_sites.into_inner();
+    for (ret, pos) in &call_sites {
+        lines.push(format!("dq {}, {}, {}", ret, pos.line, pos.col));
+    }
+    lines.push(format!("{}: dq {}", sym("snek_call_site_count"), call_sites.len()));
+    // (address in function, operator, line, column, expected operand type) per
+    // fallible operation; `snek_error` is passed an index into this table.
+    let error_sites = error_sites.into_inner();
+    let mut site_strings: Vec<&str> = Vec::new();
+    let mut string_label = |text: &'static str| match site_strings.iter().position(|t| *t == text) {
+        Some(i) => format!("sitestr_{}", i),
+        None => {
+            site_strings.push(text);
+            format!("sitestr_{}", site_strings.len() - 1)
+        }
+    };
+    lines.push(format!("{}:", sym("snek_error_site_table")));
+    for site in &error_sites {
+        lines.push(format!(
+            "dq {}, {}, {}, {}, {}",
+            site.here,
+            string_label(site.op),
+            site.pos.line,
+            site.pos.col,
+            string_label(site.expected)
+        ));
+    }
+    lines.push(format!("{}: dq {}", sym("snek_error_site_count"), error_sites.len()));
+    lines.push(format!("{}: db {}", sym("snek_source_name"), c_string_bytes(&opts.source_name)));
+    for (i, defn) in prog.defns.iter().enumerate() {
+        lines.push(format!("fname_{}: db {}", i, c_string_bytes(&defn.name)));
+    }
+    lines.push(format!("fname_{}: db {}", main_index, c_string_bytes("<main>")));
+    for (i, text) in site_strings.iter().enumerate() {
+        lines.push(format!("sitestr_{}: db {}", i, c_string_bytes(text)));
+    }
+
+    // Float literals are boxed once, statically; `lea` adds the float tag.
+    let mut floats = Vec::new();
+    for defn in &prog.defns {
+        collect_floats(&defn.body, &mut floats);
+    }
+    for g in &prog.globals {
+        match g {
+            Global::Define(_, e) | Global::Const(_, e) => collect_floats(e, &mut floats),
+        }
+    }
+    collect_floats(&prog.main, &mut floats);
+    if !floats.is_empty() {
+        lines.push("align 8".to_string());
+        for bits in floats {
+            lines.push(format!("{}: dq 0x{:016x}", float_label(f64::from_bits(bits)), bits));
+        }
+    }
+
+    let mutable: Vec<&str> = prog
+        .globals
+        .iter()
+        .filter(|g| matches!(g, Global::Define(..)))
+        .map(global_name)
+        .collect();
+    // The runtime always links against the profile symbols; without
+    // `--profile` the counters stay zero and are never printed.
+    lines.push(format!("{}: dq {}", sym("snek_profile_enabled"), opts.profile as i32));
+    lines.push("section .bss".to_string());
+    lines.push("align 8".to_string());
+    let counters = 3 * (prog.defns.len() + 1);
+    lines.push(format!("{}: resq {}", sym("snek_profile_counters"), counters));
+    for name in mutable {
+        lines.push(format!("{}: resq 1", global_label(name)));
+    }
+    // ELF linkers assume an object without this note needs an executable stack.
+    if opts.target == Target::Linux {
+        lines.push("section .note.GNU-stack noalloc noexec nowrite progbits".to_string());
+    }
+    format!("{}\n", lines.join("\n"))
+}
+
+/// The options and file names in the command-line arguments `args`, which
+/// exclude the program name, or the message for a bad option.
+fn parse_args(args: &[String]) -> Result<(CompileOptions, Vec<&String>), String> {
+    let mut opts = CompileOptions {
+        target: Target::host(),
+        ..CompileOptions::default()
+    };
+    let mut files = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--no-prelude" => opts.prelude = false,
+            "--profile" => opts.profile = true,
+            "--sysv-calls" => opts.sysv_calls = true,
+            "--target" => {
+                let name = rest.next().map(String::as_str).unwrap_or("");
+                opts.target = Target::from_name(name).ok_or_else(|| {
+                    format!("Unknown target: {} (expected linux-x86_64 or macos-x86_64)", name)
+                })?;
+            }
+            level if level.starts_with("-O") => match level[2..].parse() {
+                Ok(n) => opts.opt_level = n,
+                Err(_) => return Err(format!("Invalid optimization level: {}", level)),
+            },
+            flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+            _ => files.push(arg),
+        }
+    }
+    Ok((opts, files))
+}
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+
+    let (mut opts, files) = parse_args(&args[1..]).unwrap_or_else(|msg| {
+        eprintln!("{}", msg);
+        std::process::exit(1);
+    });
+    if files.len() != 2 {
+        eprintln!(
+            "Usage: {} [-O<n>] [--no-prelude] [--profile] [--sysv-calls] \
+             [--target linux-x86_64|macos-x86_64] <input.snek> <output.s>",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+
+    let in_name = files[0];
+    let out_name = files[1];
+
+    let mut in_file = File::open(in_name)?;
+    let mut in_contents = String::new();
+    in_file.read_to_string(&mut in_contents)?;
+
+    let sexp = parse(&in_contents).unwrap_or_else(|e| panic!("Parse error: {}", e));
+    let spans = source_spans(&in_contents, &sexp);
+    let prog = parse_program(&sexp, &spans);
+    opts.source_name = in_name.clone();
+    let asm = compile_program(&prog, &opts);
+
+    let mut out_file = File::create(out_name)?;
+    out_file.write_all(asm.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_prog(src: &str) -> Program {
+        let sexp = parse(src).unwrap();
+        parse_program(&sexp, &source_spans(src, &sexp))
+    }
+
+    fn compile_src(src: &str) -> String {
+        compile_with(src, CompileOptions::default())
+    }
+
+    fn compile_with(src: &str, opts: CompileOptions) -> String {
+        compile_program(&parse_prog(src), &opts)
+    }
+
+    fn load_slot(off: i32) -> Instr {
+        Instr::IMov(reg(Reg::Rax), slot(off))
+    }
+
+    #[test]
+    fn parse_single_def_and_main() {
+        let p = parse_prog("((fun (id x) x) (id 5))");
+        assert_eq!(p.defns.len(), 1);
+    }
+
+    #[test]
+    fn parse_call_with_zero_args() {
+        let p = parse_prog("((fun (forty_two) 42) (forty_two))");
+        assert!(matches!(p.main, Expr::Call(..)));
+    }
+
+    #[test]
+    fn parse_call_with_five_args() {
+        let p = parse_prog("((fun (sum5 a b c d e) (+ a (+ b (+ c (+ d e))))) (sum5 1 2 3 4 5))");
+        assert!(matches!(p.main, Expr::Call(..)));
+    }
+
+    #[test]
+    fn simple_function_call_emits_call_label() {
+        let asm = compile_src("((fun (inc x) (add1 x)) (inc 4))");
+        assert!(asm.contains("call fun_inc"));
+    }
+
+    #[test]
+    fn zero_arg_call_emits_no_cleanup() {
+        let asm = compile_src("((fun (f) 7) (f))");
+        assert!(!asm.contains("add rsp, 0"));
+    }
+
+    #[test]
+    fn one_arg_call_cleans_up_and_aligns() {
+        let asm = compile_src("((fun (f x) x) (f 1))");
+        assert!(asm.contains("sub rsp, 8"));
+        assert!(asm.contains("add rsp, 16"));
+    }
+
+    #[test]
+    fn two_arg_call_cleans_exactly_sixteen() {
+        let asm = compile_src("((fun (add2 x y) (+ x y)) (add2 1 2))");
+        assert!(asm.contains("add rsp, 16"));
+    }
+
+    #[test]
+    fn five_arg_call_pushes_all_args() {
+        let asm = compile_src(
+            "((fun (f a b c d e) (+ a (+ b (+ c (+ d e))))) (f 1 2 3 4 5))",
+        );
+        assert!(asm.matches("push rax").count() >= 5);
+    }
+
+    #[test]
+    fn recursive_function_compiles_self_call() {
+        let asm = compile_src("((fun (fact n) (if (= n 1) 1 (* n (fact (sub1 n))))) (fact 5))");
+        assert!(asm.contains("fun_fact:"));
+        assert!(asm.contains("call fun_fact"));
+    }
+
+    #[test]
+    fn mutual_recursion_compiles_both_calls() {
+        let asm = compile_src(
+            "((fun (is_even n) (if (= n 0) true (is_odd (sub1 n)))) (fun (is_odd n) (if (= n 0) false (is_even (sub1 n)))) (is_even 8))",
+        );
+        assert!(asm.contains("call fun_is_even"));
+        assert!(asm.contains("call fun_is_odd"));
+    }
+
+    #[test]
+    fn function_calling_function_compiles() {
+        let asm = compile_src("((fun (double x) (+ x x)) (fun (quad x) (double (double x))) (quad 3))");
+        assert!(asm.contains("call fun_double"));
+        assert!(asm.contains("call fun_quad"));
+    }
+
+    #[test]
+    fn local_variables_use_rbp_negative_offsets() {
+        let asm = compile_src("((fun (f x) (let ((y (+ x 1))) (+ y x))) (f 10))");
+        assert!(asm.contains("[rbp - 8]"));
+    }
+
+    #[test]
+    fn parameters_use_rbp_positive_offsets() {
+        let asm = compile_src("((fun (f x y) (+ x y)) (f 2 3))");
+        assert!(asm.contains("[rbp + 16]"));
+        assert!(asm.contains("[rbp + 24]"));
+    }
+
+    #[test]
+    fn mixed_param_local_access_compiles() {
+        let asm = compile_src("((fun (mix x y) (let ((z (+ x y))) (+ z x))) (mix 1 2))");
+        assert!(asm.contains("[rbp + 16]"));
+        assert!(asm.contains("[rbp - 8]"));
+    }
+
+    #[test]
+    fn set_of_parameter_leaves_argument_slot_alone() {
+        let asm = compile_src("((fun (f x) (block (set! x (add1 x)) (+ x x))) (f 1))");
+        let body = &asm[asm.find("fun_f:").unwrap()..asm.find("endfun_f:").unwrap()];
+        // The argument is read once, by `add1`; the `set!` makes a new value
+        // that `(+ x x)` uses instead of writing it back to the caller's slot.
+        assert!(!body.lines().any(|l| l.starts_with("mov [rbp + 16]")));
+        assert_eq!(body.matches("[rbp + 16]").count(), 1);
+        for prune in [false, true] {
+            assert_eq!(run_function_body("(block (set! x (add1 x)) (+ x x))", 1, prune), Ok(4));
+        }
+    }
+
+    #[test]
+    fn print_unop_calls_runtime_print() {
+        let asm = compile_src("(print 5)");
+        assert!(asm.contains("call snek_print"));
+    }
+
+    #[test]
+    fn stack_frame_prologue_and_epilogue_present() {
+        let asm = compile_src("((fun (id x) x) (id 1))");
+        assert!(asm.contains("push rbp"));
+        assert!(asm.contains("mov rbp, rsp"));
+        assert!(asm.contains("mov rsp, rbp"));
+        assert!(asm.contains("pop rbp"));
+    }
+
+    #[test]
+    fn main_uses_stack_frame_too() {
+        let asm = compile_src("(let ((x 1)) x)");
+        assert!(asm.contains("our_code_starts_here:\npush rbp\nmov rbp, rsp"));
+    }
+
+    #[test]
+    fn parse_plain_expression_program_without_defs() {
+        let p = parse_prog("(+ 1 2)");
+        assert_eq!(p.defns.len(), 0);
+    }
+
+    #[test]
+    fn arity_table_rejects_duplicate_definitions() {
+        let sexp = parse("((fun (f x) x) (fun (f y) y) (f 1))").unwrap();
+        let p = parse_program(&sexp, &Spans::new());
+        let result = std::panic::catch_unwind(|| compile_program(&p, &CompileOptions::default()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrong number of arguments")]
+    fn wrong_number_of_arguments_panics() {
+        compile_src("((fun (f x y) (+ x y)) (f 1))");
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined function")]
+    fn undefined_function_call_panics() {
+        compile_src("(missing_fn 1)");
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot shadow parameter with let")]
+    fn shadowing_parameter_with_let_panics() {
+        compile_src("((fun (f x) (let ((x 5)) x)) (f 1))");
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate parameter")]
+    fn duplicate_parameter_rejected() {
+        parse_prog("((fun (f x x) x) (f 1 2))");
+    }
+
+    #[test]
+    fn call_argument_order_is_right_to_left_push() {
+        let asm = compile_src("((fun (f a b c) a) (f 1 2 3))");
+        assert!(asm.contains("call fun_f"));
+        assert!(asm.matches("push rax").count() >= 3);
+    }
+
+    #[test]
+    fn nested_call_uses_temp_slots() {
+        let asm = compile_src("((fun (id x) x) (id (id (id 7))))");
+        assert!(asm.contains("mov [rbp - 8], rax"));
+    }
+
+    #[test]
+    fn recursion_with_locals_uses_frame_space() {
+        let asm = compile_src(
+            "((fun (sum_to n) (if (= n 0) 0 (let ((rest (sum_to (sub1 n)))) (+ n rest)))) (sum_to 4))",
+        );
+        assert!(asm.contains("sub rsp, "));
+        assert!(asm.contains("call fun_sum_to"));
+    }
+
+    #[test]
+    fn comparison_and_equality_still_emit_runtime_checks() {
+        let asm = compile_src("(block (< 1 2) (= 2 2))");
+        assert!(asm.contains("cmp rdi, rsi"));
+        assert!(asm.contains("call snek_error"));
+    }
+
+    #[test]
+    fn loop_break_with_calls_compiles() {
+        let asm = compile_src("((fun (id x) x) (loop (break (id 5))))");
+        assert!(asm.contains("lp_h_"));
+        assert!(asm.contains("call fun_id"));
+    }
+
+    #[test]
+    fn function_definitions_must_precede_main() {
+        let sexp = parse("((+ 1 2) (fun (f x) x))").unwrap();
+        let result = std::panic::catch_unwind(|| parse_program(&sexp, &Spans::new()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_define_and_const_globals() {
+        let p = parse_prog("((define counter 0) (const limit 10) (fun (f x) x) (f limit))");
+        assert_eq!(p.globals.len(), 2);
+        assert_eq!(p.defns.len(), 1);
+    }
+
+    #[test]
+    fn define_global_gets_bss_slot_and_initializer() {
+        let asm = compile_src("((define counter 5) counter)");
+        assert!(asm.contains("section .bss"));
+        assert!(asm.contains("glob_counter: resq 1"));
+        assert!(asm.contains("mov [rel glob_counter], rax"));
+        assert!(asm.contains("mov rax, [rel glob_counter]"));
+    }
+
+    #[test]
+    fn global_initializer_runs_before_main() {
+        let asm = compile_src("((define x 1) (add1 x))");
+        let init = asm.find("mov [rel glob_x], rax").unwrap();
+        let read = asm.find("mov rax, [rel glob_x]").unwrap();
+        assert!(init < read);
+    }
+
+    #[test]
+    fn functions_can_set_globals() {
+        let asm = compile_src("((define total 0) (fun (bump n) (set! total (+ total n))) (bump 3))");
+        assert!(asm.contains("fun_bump:"));
+        assert!(asm.contains("mov [rel glob_total], rax"));
+    }
+
+    #[test]
+    fn constants_are_inlined_without_storage() {
+        let asm = compile_src("((const limit 21) (+ limit limit))");
+        assert!(!asm.contains("glob_limit"));
+        assert!(asm.matches("mov rax, 42").count() == 2);
+    }
+
+    #[test]
+    fn locals_shadow_globals() {
+        let asm = compile_src("((define x 1) (let ((x 2)) x))");
+        assert!(!asm.contains("mov rax, [rel glob_x]"));
+        assert!(asm.contains("mov rax, 4"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot set! constant")]
+    fn set_on_constant_panics() {
+        compile_src("((const k 1) (set! k 2))");
+    }
+
+    #[test]
+    #[should_panic(expected = "must be a literal")]
+    fn non_literal_constant_rejected() {
+        parse_prog("((const k (+ 1 2)) k)");
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate global definition")]
+    fn duplicate_global_rejected() {
+        compile_src("((define x 1) (const x 2) x)");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unbound variable: y")]
+    fn global_initializer_cannot_see_later_globals() {
+        compile_src("((define x y) (define y 1) x)");
+    }
+
+    #[test]
+    fn parse_float_literal() {
+        let p = parse_prog("2.5");
+        assert!(matches!(p.main, Expr::Float(f) if f == 2.5));
+    }
+
+    #[test]
+    fn float_literal_is_boxed_in_data_section() {
+        let asm = compile_src("(+ 1.5 1.5)");
+        assert!(asm.contains("section .data"));
+        assert_eq!(asm.matches("flt_3ff8000000000000: dq 0x3ff8000000000000").count(), 1);
+        assert!(asm.contains("lea rax, [rel flt_3ff8000000000000 + 5]"));
+    }
+
+    #[test]
+    fn arithmetic_falls_back_to_runtime_for_floats() {
+        let asm = compile_src("(* 2 0.5)");
+        assert!(asm.contains("mov rdi, 2\nmov rdx, rax\ncall snek_arith"));
+    }
+
+    #[test]
+    fn integer_division_checks_for_zero() {
+        let asm = compile_src("(/ 7 2)");
+        assert!(asm.contains("idiv rcx"));
+        assert!(asm.contains("mov esi, 0\njmp fail_div_zero"));
+        assert!(asm.contains("fail_div_zero:\nmov rdi, 3\nmov rdx, rbp\ncall snek_error"));
+    }
+
+    #[test]
+    fn isbool_distinguishes_floats_from_booleans() {
+        let asm = compile_src("(isbool 1.0)");
+        assert!(asm.contains("and r11, 5\ncmp r11, 1"));
+    }
+
+    #[test]
+    fn conversions_call_runtime() {
+        let asm = compile_src("(truncate (float 3))");
+        assert!(asm.contains("call snek_to_float"));
+        assert!(asm.contains("call snek_truncate"));
+    }
+
+    #[test]
+    fn float_constants_are_allowed() {
+        let p = parse_prog("((const pi 3.14) pi)");
+        assert!(matches!(p.globals[0], Global::Const(_, Expr::Float(_))));
+    }
+
+    #[test]
+    fn parse_argc_and_arg() {
+        let p = parse_prog("(arg (sub1 (argc)))");
+        assert!(matches!(p.main, Expr::UnOp(UnOp::Arg, ..)));
+    }
+
+    #[test]
+    fn arg_checks_index_is_number_before_runtime_call() {
+        let asm = compile_src("(arg 0)");
+        let check = asm.find("and r11, 1").unwrap();
+        let call = asm.find("call snek_arg").unwrap();
+        assert!(check < call);
+    }
+
+    #[test]
+    fn parse_read_builtins() {
+        let p = parse_prog("(block (read-num) (read-bool)\n  (read-line))");
+        match p.main {
+            Expr::Block(es) => {
+                assert!(matches!(es[0], Expr::Read(ReadKind::Num, _)));
+                assert!(matches!(es[1], Expr::Read(ReadKind::Bool, _)));
+                assert!(matches!(es[2], Expr::Read(ReadKind::Line, Pos { line: 2, col: 3 })));
+            }
+            other => panic!("unexpected parse: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_builtins_pass_error_site_to_runtime() {
+        let asm = compile_src("(+ 1 (read-num))");
+        assert!(asm.contains("read_1:\nmov rdi, 0\nmov rsi, rbp\ncall snek_read_num"));
+        assert!(asm.contains("snek_error_site_table:\ndq read_1, sitestr_0, 1, 6, sitestr_1\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot use keyword as binding name: read-line")]
+    fn read_builtins_are_reserved() {
+        parse_prog("(let ((read-line 1)) read-line)");
+    }
+
+    #[test]
+    fn argc_calls_runtime() {
+        let asm = compile_src("(argc)");
+        assert!(asm.contains("call snek_argc"));
+    }
+
+    #[test]
+    fn function_prologue_checks_stack_limit() {
+        let asm = compile_src("((fun (f x) (f (+ x 1))) (f 1))");
+        assert!(asm.contains("fun_f:\npush rbp\nmov rbp, rsp\nsub rsp, 16\ncmp rsp, [rel STACK_LIMIT]\njb stack_overflow"));
+    }
+
+    #[test]
+    fn stack_overflow_stub_reports_error_code_five() {
         let asm = compile_src("((fun (f x) x) (f 1))");
-        assert!(asm.contains("sub rsp, 8"));
+        assert!(asm.contains("stack_overflow:\nmov rdi, 5\nmov rsi, -1\nmov rdx, rbp\ncall snek_error"));
+    }
+
+    #[test]
+    fn list_positions_skip_comments_and_strings() {
+        let src = "; (not a list\n(f \"(\" (g 1))";
+        assert_eq!(
+            list_positions(src),
+            vec![Pos { line: 2, col: 1 }, Pos { line: 2, col: 8 }]
+        );
+    }
+
+    #[test]
+    fn calls_record_source_position() {
+        let p = parse_prog("((fun (f x) x)\n  (f 1))");
+        assert!(matches!(p.main, Expr::Call(_, _, Pos { line: 2, col: 3 })));
+    }
+
+    #[test]
+    fn call_sites_are_labelled_and_tabled() {
+        let asm = compile_src("((fun (f x) x)\n  (f 1))");
+        assert!(asm.contains("call fun_f\nret_1:"));
+        assert!(asm.contains("snek_call_site_table:\ndq ret_1, 2, 3"));
+        assert!(asm.contains("snek_call_site_count: dq 1"));
+    }
+
+    #[test]
+    fn function_table_covers_every_function_and_main() {
+        let asm = compile_src("((fun (f x) x) (f 1))");
+        assert!(asm.contains("endfun_f:"));
+        assert!(asm.contains("dq fun_f, endfun_f, fname_0"));
+        assert!(asm.contains("dq our_code_starts_here, our_code_ends_here, fname_1"));
+        assert!(asm.contains("fname_0: db 102, 0"));
+    }
+
+    #[test]
+    fn error_stubs_pass_site_frame_pointer_and_operand() {
+        let asm = compile_src("(add1 true)");
+        assert!(asm.contains("mov esi, 0\njmp fail_invalid_unary"));
+        assert!(asm.contains("fail_invalid_unary:\nmov rdi, 1\nmov rdx, rbp\nmov rcx, rax\ncall snek_error"));
+    }
+
+    #[test]
+    fn binary_error_stubs_pass_both_operands() {
+        let asm = compile_src("(+ 1 true)");
+        // The slow path leaves the left operand in `rcx` for the stub.
+        assert!(asm.contains("slow_2:\nmov rcx, [rbp - 8]\n"));
+        assert!(asm.contains("mov esi, 0\njmp fail_invalid_binary"));
+        assert!(asm.contains("fail_invalid_binary:\nmov rdi, 1\nmov rdx, rbp\nmov r8, rax\ncall snek_error"));
+    }
+
+    #[test]
+    fn error_stubs_follow_the_epilogue_and_share_tails() {
+        let asm = compile_src("((fun (f x) (+ (* x 2) (add1 x))) (f 1))");
+        let f = &asm[asm.find("fun_f:").unwrap()..asm.find("endfun_f:").unwrap()];
+        // Nothing on the path that does not fail jumps over a stub.
+        let ret = f.find("ret\n").unwrap();
+        for stub in ["badarg_", "overflow_"] {
+            assert!(f.find(&format!("\n{}", stub)).unwrap() > ret, "{}", f);
+        }
+        let stub = |l: &str| l.ends_with(':') || l.starts_with("mov esi") || l.starts_with("jmp");
+        assert!(f[ret + 4..].lines().all(stub), "{}", f);
+        // Only the stack-overflow stub and the tails in use call `snek_error`.
+        assert_eq!(asm.matches("call snek_error").count(), 4);
+        assert!(!asm.contains("fail_div_zero"));
+    }
+
+    #[test]
+    fn error_sites_record_operator_position_and_expected_type() {
+        let asm = compile_src("(let ((x 1))\n  (+ x (arg 0)))");
+        assert!(asm.contains("snek_error_site_table:\ndq badarg_1, sitestr_0, 2, 8, sitestr_1\n"));
+        assert!(asm.contains("dq badarg_3, sitestr_2, 2, 3, sitestr_3\n"));
+        assert!(asm.contains("snek_error_site_count: dq 2"));
+        assert!(asm.contains("sitestr_0: db 97, 114, 103, 0"));
+        assert!(asm.contains("sitestr_3: db 110, 117, 109, 0"));
+    }
+
+    #[test]
+    fn operators_record_source_position() {
+        let p = parse_prog("(add1\n  (+ 1 2))");
+        match p.main {
+            Expr::UnOp(UnOp::Add1, sub, Pos { line: 1, col: 1 }) => {
+                assert!(matches!(*sub, Expr::BinOp(BinOp::Plus, _, _, Pos { line: 2, col: 3 })));
+            }
+            other => panic!("unexpected parse: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_extern_declaration() {
+        let p = parse_prog("((extern (clamp v lo hi)) (clamp 5 0 3))");
+        assert_eq!(p.externs.len(), 1);
+        assert_eq!(p.externs[0].name, "clamp");
+        assert_eq!(p.externs[0].params, vec!["v", "lo", "hi"]);
+    }
+
+    #[test]
+    fn extern_call_passes_args_in_registers() {
+        let asm = compile_src("((extern (clamp v lo hi)) (clamp 5 0 3))");
+        assert!(asm.contains("extern clamp\n"));
+        assert!(asm.contains(
+            "mov rdi, 10\nmov rsi, 0\nmov rdx, 6\ncall clamp"
+        ));
+        assert!(!asm.contains("call fun_clamp"));
+    }
+
+    #[test]
+    fn extern_call_spills_extra_args_with_alignment_pad() {
+        let asm = compile_src("((extern (f a b c d e g h)) (f 1 2 3 4 5 6 7))");
+        assert!(asm.contains("sub rsp, 8\nmov rax, 14\npush rax\nmov rdi, 2\nmov rsi, 4"));
+        assert!(asm.contains("call f\nadd rsp, 16"));
+    }
+
+    #[test]
+    fn runtime_functions_are_declared_once() {
+        let asm = compile_src("((extern (snek_print v)) (snek_print 1))");
+        assert_eq!(asm.matches("extern snek_print\n").count(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrong number of arguments")]
+    fn extern_arity_is_checked() {
+        compile_src("((extern (clamp v lo hi)) (clamp 5 0))");
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate function definition: f")]
+    fn extern_cannot_redefine_function() {
+        compile_src("((fun (f x) x) (extern (f x)) (f 1))");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid extern name: is-even")]
+    fn extern_name_must_be_a_symbol() {
+        parse_prog("((extern (is-even n)) (is-even 2))");
+    }
+
+    #[test]
+    fn extern_names_nasm_reserves_are_escaped() {
+        let src = "((extern (rdi v)) (extern (abs v)) (extern (clamp v)) (rdi (abs (clamp 1))))";
+        let asm = compile_src(src);
+        assert!(asm.contains("extern $rdi\nextern $abs\nextern clamp\n"));
+        assert!(asm.contains("call $rdi\n"));
+        assert!(asm.contains("call $abs\n"));
+        let opts = CompileOptions {
+            target: Target::MacOs,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with(src, opts);
+        assert!(asm.contains("call _rdi\n"));
+        for name in ["rdi", "RAX", "r8d", "xmm15", "st0", "byte", "rel", "wrt"] {
+            assert!(nasm_reserved(name), "{}", name);
+        }
+        for name in ["clamp", "r16", "r08", "xmm32", "bytes", "k8", "_rdi"] {
+            assert!(!nasm_reserved(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn stack_calls_are_the_default() {
+        let asm = compile_src("((fun (f a b) (+ a b)) (f 1 2))");
+        assert!(asm.contains("mov rax, 4\npush rax\nmov rax, 2\npush rax\ncall fun_f"));
+        assert!(!asm.contains("global fun_f"));
+    }
+
+    #[test]
+    fn sysv_calls_pass_args_in_registers() {
+        let opts = CompileOptions {
+            sysv_calls: true,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("((fun (f a b) (+ a b)) (f 1 2))", opts);
+        assert!(asm.contains("mov rdi, 2\nmov rsi, 4\ncall fun_f\nret_"));
+        assert!(asm.contains("global fun_f\n"));
+    }
+
+    #[test]
+    fn sysv_callee_stores_register_params() {
+        let src = "((fun (f a b c) (+ a (+ b c))) (f 1 2 3))";
+        let opts = CompileOptions {
+            sysv_calls: true,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with(src, opts.clone());
+        assert!(asm.contains(
+            "jb stack_overflow\nmov [rbp - 8], rdi\nmov [rbp - 16], rsi\nmov [rbp - 24], rdx\n"
+        ));
+        let opts = CompileOptions {
+            opt_level: 2,
+            ..opts
+        };
+        let asm = compile_with(src, opts);
+        assert!(asm.contains("mov rbx, rdi\nmov r12, rsi\nmov r13, rdx\n"));
+    }
+
+    #[test]
+    fn sysv_calls_pass_extra_args_on_the_stack() {
+        let opts = CompileOptions {
+            sysv_calls: true,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("((fun (f a b c d e g h) (+ a h)) (f 1 2 3 4 5 6 7))", opts);
+        assert!(asm.contains("sub rsp, 8\nmov rax, 14\npush rax\nmov rdi, 2\nmov rsi, 4"));
+        assert!(asm.contains("mov r9, 12\ncall fun_f\n"));
         assert!(asm.contains("add rsp, 16"));
+        // The seventh argument is the first stack parameter.
+        assert!(asm.contains("mov rax, [rbp + 16]"));
+        assert!(!asm.contains("[rbp + 24]"));
+    }
+
+    #[test]
+    fn sysv_profiled_entry_keeps_third_arg() {
+        let opts = CompileOptions {
+            sysv_calls: true,
+            profile: true,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("((fun (f a b c) c) (f 1 2 3))", opts);
+        assert!(asm.contains("mov r11, rdx\nrdtsc\nshl rdx, 32\nor rax, rdx\n"));
+        assert!(asm.contains("mov rdx, r11\nmov rax, rdx\n"));
+    }
+
+    #[test]
+    fn target_names_are_parsed() {
+        assert_eq!(Target::from_name("linux-x86_64"), Some(Target::Linux));
+        assert_eq!(Target::from_name("macos-x86_64"), Some(Target::MacOs));
+        assert_eq!(Target::from_name("macos"), None);
+    }
+
+    #[test]
+    fn linux_target_uses_plain_symbols_and_marks_the_stack() {
+        let opts = CompileOptions {
+            target: Target::Linux,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("((fun (f x) x) (f input))", opts);
+        assert!(asm.starts_with("section .text\ndefault rel\n"));
+        assert!(asm.contains("global our_code_starts_here\n"));
+        assert!(asm.contains("mov rax, [rel INPUT_VAL]"));
+        assert!(asm.ends_with("section .note.GNU-stack noalloc noexec nowrite progbits\n"));
+    }
+
+    #[test]
+    fn macos_target_prefixes_shared_symbols() {
+        let src = "((extern (clamp v lo hi)) (fun (f x) x) (print (clamp (f input) 0 3)))";
+        let opts = CompileOptions {
+            target: Target::MacOs,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with(src, opts);
+        assert!(asm.starts_with("section .text\ndefault rel\n"));
+        assert!(asm.contains("extern _snek_print\n"));
+        assert!(asm.contains("extern _clamp\n"));
+        assert!(asm.contains("global _our_code_starts_here\n"));
+        assert!(asm.contains("\n_our_code_starts_here:\n"));
+        assert!(asm.contains("cmp rsp, [rel _STACK_LIMIT]"));
+        assert!(asm.contains("mov rax, [rel _INPUT_VAL]"));
+        assert!(asm.contains("call _clamp\n"));
+        assert!(asm.contains("call _snek_print\n"));
+        assert!(asm.contains("\n_snek_function_table:\n"));
+        assert!(asm.contains("\n_snek_profile_counters: resq 6\n"));
+        assert!(!asm.contains("GNU-stack"));
+        // Labels the runtime does not see keep their names.
+        assert!(asm.contains("\nfun_f:\n"));
+        assert!(asm.contains("call fun_f\n"));
+        assert!(asm.contains("dq fun_f, endfun_f, fname_0"));
+    }
+
+    #[test]
+    fn macos_target_prefixes_exported_functions() {
+        let opts = CompileOptions {
+            target: Target::MacOs,
+            sysv_calls: true,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("((fun (f x) x) (f 1))", opts);
+        assert!(asm.contains("global _fun_f\n"));
+        assert!(asm.contains("\n_fun_f:\n"));
+        assert!(asm.contains("call _fun_f\n"));
+        assert!(asm.contains("dq _fun_f, endfun_f, fname_0"));
+    }
+
+    #[test]
+    fn prelude_defines_standard_functions() {
+        let names: Vec<String> = prelude_definitions().into_iter().map(|d| d.name).collect();
+        assert_eq!(names, vec!["abs", "max", "min", "pow", "gcd", "even?", "odd?"]);
+    }
+
+    #[test]
+    fn prelude_keeps_only_called_functions() {
+        let asm = compile_src("(odd? 3)");
+        assert!(asm.contains("fun_odd?:"));
+        assert!(asm.contains("fun_even?:"));
+        assert!(!asm.contains("fun_abs:"));
+        assert!(!asm.contains("fun_pow:"));
+    }
+
+    #[test]
+    fn prelude_is_reachable_from_functions_and_globals() {
+        let asm = compile_src("((define m (min 1 2)) (fun (f x) (gcd x 4)) (f m))");
+        assert!(asm.contains("fun_min:"));
+        assert!(asm.contains("fun_gcd:"));
+        assert!(asm.contains("fun_abs:"));
+    }
+
+    #[test]
+    fn user_definitions_shadow_prelude() {
+        let asm = compile_src("((fun (abs x) 7) (abs 1))");
+        assert_eq!(asm.matches("\nfun_abs:").count(), 1);
+        let asm = compile_src("((extern (abs x)) (abs 1))");
+        assert!(!asm.contains("fun_abs:"));
+    }
+
+    fn cli_options(args: &[&str]) -> CompileOptions {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let (opts, files) = parse_args(&args).unwrap();
+        assert_eq!(files, ["prog.snek", "prog.s"]);
+        opts
+    }
+
+    #[test]
+    fn prelude_is_on_by_default() {
+        assert!(cli_options(&["prog.snek", "prog.s"]).prelude);
+        assert!(compile_src("(abs 1)").contains("call fun_abs"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined function: abs")]
+    fn prelude_can_be_disabled() {
+        let opts = cli_options(&["--no-prelude", "prog.snek", "prog.s"]);
+        compile_with("(abs 1)", opts);
+    }
+
+    #[test]
+    fn profiling_is_off_by_default() {
+        let asm = compile_src("((fun (f x) x) (f 1))");
+        assert!(!asm.contains("rdtsc"));
+        assert!(asm.contains("snek_profile_enabled: dq 0"));
+        assert!(asm.contains("snek_profile_counters: resq 6"));
+    }
+
+    #[test]
+    fn profiled_prologue_counts_calls_and_reads_tsc() {
+        let opts = CompileOptions {
+            profile: true,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("((fun (f x) (add1 x)) (f 1))", opts);
+        assert!(asm.contains("snek_profile_enabled: dq 1"));
+        assert!(asm.contains(
+            "jb stack_overflow\nadd qword [rel snek_profile_counters], 1\n\
+             add qword [rel snek_profile_counters + 16], 1\nrdtsc"
+        ));
+        // Main is row 1, after the one function.
+        assert!(asm.contains("add qword [rel snek_profile_counters + 24], 1"));
+    }
+
+    #[test]
+    fn profiled_frame_reserves_tsc_slot() {
+        let opts = CompileOptions {
+            profile: true,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("((fun (f x) (let ((y (+ x 1))) (+ y y))) (f 1))", opts);
+        assert!(asm.contains("fun_f:\npush rbp\nmov rbp, rsp\nsub rsp, 32\n"));
+        assert!(asm.contains("mov [rbp - 24], rax"));
+        assert!(asm.contains("sub rax, [rbp - 24]\nadd [rel snek_profile_counters + 8], rax"));
+    }
+
+    #[test]
+    fn profiled_epilogue_preserves_result() {
+        let opts = CompileOptions {
+            profile: true,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("((fun (f x) x) (f 1))", opts);
+        assert!(asm.contains("mov r11, rax\nsub qword [rel snek_profile_counters + 16], 1\njne"));
+        assert!(asm.contains("mov rax, r11\nmov rsp, rbp"));
+    }
+
+    #[test]
+    fn operands_render_as_nasm() {
+        assert_eq!(val_to_str(&slot(8)), "[rbp - 8]");
+        assert_eq!(val_to_str(&slot(-16)), "[rbp + 16]");
+        assert_eq!(val_to_str(&Val::RegOffset(Reg::Rsp, 0)), "[rsp]");
+        assert_eq!(val_to_str(&rel("INPUT_VAL")), "[rel INPUT_VAL]");
+        assert_eq!(val_to_str(&Val::Rel("flt_0".to_string(), 5)), "[rel flt_0 + 5]");
+        assert_eq!(val_to_str(&Val::Reg32(Reg::R8)), "r8d");
+    }
+
+    #[test]
+    fn instructions_render_as_nasm() {
+        assert_eq!(instr_to_str(&Instr::ILabel("lp_h_1".to_string())), "lp_h_1:");
+        assert_eq!(instr_to_str(&store_slot(8)), "mov [rbp - 8], rax");
+        let imul = Instr::IMul(Val::Reg32(Reg::Rax), Val::Reg32(Reg::Rsi));
+        assert_eq!(instr_to_str(&imul), "imul eax, esi");
+        assert_eq!(instr_to_str(&Instr::IJcc(Cond::Ge, "t".to_string())), "jge t");
+        assert_eq!(instr_to_str(&Instr::IDiv(reg(Reg::Rcx))), "idiv rcx");
+    }
+
+    #[test]
+    fn memory_immediate_operations_are_sized() {
+        let add = Instr::IAdd(Val::Rel("glob_n".to_string(), 8), Val::Imm(1));
+        assert_eq!(instr_to_str(&add), "add qword [rel glob_n + 8], 1");
+        let cmp = Instr::ICmp(reg(Reg::Rsp), rel("STACK_LIMIT"));
+        assert_eq!(instr_to_str(&cmp), "cmp rsp, [rel STACK_LIMIT]");
+    }
+
+    fn jump(cond: Cond, target: &str) -> Instr {
+        Instr::IJcc(cond, target.to_string())
+    }
+
+    fn label(name: &str) -> Instr {
+        Instr::ILabel(name.to_string())
+    }
+
+    fn read_val(v: &Val, regs: &HashMap<Reg, i64>, mem: &HashMap<i32, i64>) -> i64 {
+        match v {
+            Val::Reg(r) => regs.get(r).copied().unwrap_or(0),
+            Val::Reg8(r) => regs.get(r).copied().unwrap_or(0) & 0xff,
+            Val::Imm(n) => *n,
+            Val::RegOffset(Reg::Rbp, off) => mem.get(off).copied().unwrap_or(0),
+            _ => panic!("operand not simulated: {:?}", v),
+        }
+    }
+
+    /// Registers without `r11`, stack slots, and the label execution left through.
+    type SimState = (Vec<(Reg, i64)>, Vec<(i32, i64)>, Option<String>);
+
+    /// Runs IR made of moves, tag arithmetic and jumps with `rax` preset.
+    fn simulate(code: &[Instr], rax: i64) -> SimState {
+        simulate_from(code, &[(Reg::Rax, rax)])
+    }
+
+    /// Like `simulate`, with the registers in `preset` set instead.
+    fn simulate_from(code: &[Instr], preset: &[(Reg, i64)]) -> SimState {
+        let labels = label_positions(code);
+        let mut regs: HashMap<Reg, i64> = preset.iter().copied().collect();
+        let mut mem = HashMap::new();
+        let mut zf = false;
+        let mut of = false;
+        let mut pc = 0;
+        let mut exit = None;
+        while pc < code.len() {
+            let mut next = pc + 1;
+            match &code[pc] {
+                Instr::ILabel(_) => {}
+                Instr::IMov(dst, src) => {
+                    let v = read_val(src, &regs, &mem);
+                    match dst {
+                        Val::Reg(r) => {
+                            regs.insert(*r, v);
+                        }
+                        Val::RegOffset(Reg::Rbp, off) => {
+                            mem.insert(*off, v);
+                        }
+                        _ => panic!("destination not simulated: {:?}", dst),
+                    }
+                }
+                Instr::IAnd(Val::Reg(r), src) => {
+                    let v = read_val(&reg(*r), &regs, &mem) & read_val(src, &regs, &mem);
+                    regs.insert(*r, v);
+                    zf = v == 0;
+                }
+                Instr::ISar(Val::Reg(r), Val::Imm(n)) => {
+                    regs.insert(*r, read_val(&reg(*r), &regs, &mem) >> n);
+                }
+                Instr::IAdd(Val::Reg(r), src) => {
+                    let sum =
+                        read_val(&reg(*r), &regs, &mem).checked_add(read_val(src, &regs, &mem));
+                    of = sum.is_none();
+                    regs.insert(*r, sum.unwrap_or(0));
+                }
+                Instr::IMul(Val::Reg(r), src) => {
+                    let product =
+                        read_val(&reg(*r), &regs, &mem).checked_mul(read_val(src, &regs, &mem));
+                    of = product.is_none();
+                    regs.insert(*r, product.unwrap_or(0));
+                }
+                Instr::INeg(Val::Reg(r)) => {
+                    let negated = read_val(&reg(*r), &regs, &mem).checked_neg();
+                    of = negated.is_none();
+                    regs.insert(*r, negated.unwrap_or(0));
+                }
+                Instr::ICmp(a, b) => zf = read_val(a, &regs, &mem) == read_val(b, &regs, &mem),
+                Instr::ITest(a, b) => zf = read_val(a, &regs, &mem) & read_val(b, &regs, &mem) == 0,
+                Instr::ICmov(c, Val::Reg(r), src) => {
+                    let holds = match c {
+                        Cond::E => zf,
+                        Cond::Ne => !zf,
+                        other => panic!("condition not simulated: {:?}", other),
+                    };
+                    if holds {
+                        regs.insert(*r, read_val(src, &regs, &mem));
+                    }
+                }
+                Instr::IJmp(target) | Instr::IJcc(_, target) => {
+                    let taken = match &code[pc] {
+                        Instr::IJcc(Cond::E, _) => zf,
+                        Instr::IJcc(Cond::Ne, _) => !zf,
+                        Instr::IJcc(Cond::O, _) => of,
+                        Instr::IJmp(_) => true,
+                        other => panic!("jump not simulated: {:?}", other),
+                    };
+                    if taken {
+                        match labels.get(target.as_str()) {
+                            Some(&dest) => next = dest,
+                            None => {
+                                exit = Some(target.clone());
+                                break;
+                            }
+                        }
+                    }
+                }
+                other => panic!("instruction not simulated: {:?}", other),
+            }
+            pc = next;
+        }
+        regs.remove(&Reg::R11);
+        let mut regs: Vec<(Reg, i64)> = regs.into_iter().collect();
+        regs.sort_by_key(|(r, _)| *r as usize);
+        let mut mem: Vec<(i32, i64)> = mem.into_iter().collect();
+        mem.sort();
+        (regs, mem, exit)
+    }
+
+    /// Runs the code `emit_body` gives `(fun (f x) body)`, passing the number
+    /// `x` in `rdi`. Returns its result in `rax`, or the label of the error stub
+    /// it jumped to. `prune` optimizes the body as at `-O1`.
+    fn run_function_body(body: &str, x: i64, prune: bool) -> Result<i64, String> {
+        let prog = parse_prog(&format!("((fun (f x) {}) 0)", body));
+        let params = ["x".to_string()];
+        let (frame, sites) = (Cell::new(0), RefCell::new(Vec::new()));
+        let ctx = Ctx {
+            arities: &HashMap::new(),
+            externs: &HashSet::new(),
+            globals: &HashMap::new(),
+            params: &params,
+            frame: &frame,
+            call_sites: &sites,
+            error_sites: &RefCell::new(Vec::new()),
+            stubs: &RefCell::new(Vec::new()),
+            use_types: prune,
+            prune,
+            pure: &HashSet::new(),
+            sysv_calls: true,
+        };
+        let code = emit_body(&prog.defns[0].body, &ctx, &mut 0);
+        let (regs, _, exit) = simulate_from(&code, &[(Reg::Rdi, x << 1)]);
+        match exit {
+            Some(stub) => Err(stub),
+            None => Ok(regs.iter().find(|(r, _)| *r == Reg::Rax).unwrap().1 >> 1),
+        }
     }
 
     #[test]
-    fn two_arg_call_cleans_exactly_sixteen() {
-        let asm = compile_src("((fun (add2 x y) (+ x y)) (add2 1 2))");
-        assert!(asm.contains("add rsp, 16"));
+    fn multiplication_and_negation_use_the_63_bit_range() {
+        let max = (1i64 << 62) - 1;
+        let overflows =
+            |r: Result<i64, String>| matches!(r, Err(stub) if stub.starts_with("overflow"));
+        for prune in [false, true] {
+            let run = |body: &str, x: i64| run_function_body(body, x, prune);
+            assert_eq!(run("(* x 1)", 5_000_000_000), Ok(5_000_000_000));
+            assert_eq!(run("(* 3 x)", -5_000_000_000), Ok(-15_000_000_000));
+            assert_eq!(run("(negate x)", 5_000_000_000), Ok(-5_000_000_000));
+            assert_eq!(run("(* x 2)", max / 2), Ok(max - 1));
+            assert_eq!(run("(negate x)", max), Ok(-max));
+            assert!(overflows(run("(* x 2)", max)));
+            assert!(overflows(run("(* x 2)", max / 2 + 1)));
+            assert!(overflows(run("(* x x)", 3_037_000_500)));
+            assert!(overflows(run("(* x -1)", -max - 1)));
+            assert!(overflows(run("(negate x)", -max - 1)));
+        }
+    }
+
+    /// Tagged values of every kind, including the edges of the bit tests.
+    const SAMPLE_VALUES: [i64; 9] = [0, 1, 2, 3, 5, 6, -1, -2, 0x105];
+
+    fn assert_same_behavior(before: &[Instr], after: &[Instr]) {
+        for v in SAMPLE_VALUES {
+            assert_eq!(simulate(before, v), simulate(after, v), "rax = {}", v);
+        }
     }
 
     #[test]
-    fn five_arg_call_pushes_all_args() {
-        let asm = compile_src(
-            "((fun (f a b c d e) (+ a (+ b (+ c (+ d e))))) (f 1 2 3 4 5))",
+    fn peephole_forwards_stored_register_to_load() {
+        let code = vec![store_slot(8), load_slot(8), Instr::IMov(reg(Reg::Rcx), slot(8))];
+        let optimized = peephole(code.clone());
+        assert_eq!(optimized, vec![store_slot(8), Instr::IMov(reg(Reg::Rcx), reg(Reg::Rax))]);
+        assert_same_behavior(&code, &optimized);
+    }
+
+    #[test]
+    fn peephole_forwards_copied_register() {
+        let code = vec![
+            Instr::IMov(reg(Reg::Rbx), reg(Reg::Rax)),
+            Instr::IMov(reg(Reg::Rcx), reg(Reg::Rbx)),
+        ];
+        let optimized = peephole(code.clone());
+        assert_eq!(optimized[1], Instr::IMov(reg(Reg::Rcx), reg(Reg::Rax)));
+        assert_same_behavior(&code, &optimized);
+    }
+
+    #[test]
+    fn peephole_keeps_load_of_other_slot() {
+        let code = vec![store_slot(8), load_slot(16)];
+        assert_eq!(peephole(code.clone()), code);
+    }
+
+    #[test]
+    fn peephole_turns_tag_check_into_test() {
+        let mut code = Vec::new();
+        append_tag_check(&mut code, Reg::Rax, 1, 0, Cond::Ne, "slow");
+        code.push(Instr::IMov(reg(Reg::R11), slot(8)));
+        code.push(Instr::ITest(reg(Reg::R11), Val::Imm(1)));
+        code.push(jump(Cond::E, "fast"));
+        let optimized = peephole(code.clone());
+        assert_eq!(
+            optimized,
+            vec![
+                Instr::ITest(Val::Reg8(Reg::Rax), Val::Imm(1)),
+                jump(Cond::Ne, "slow"),
+                Instr::ITest(slot(8), Val::Imm(1)),
+                jump(Cond::E, "fast"),
+            ]
         );
-        assert!(asm.matches("push rax").count() >= 5);
+        assert_eq!(instr_to_str(&optimized[0]), "test al, 1");
+        assert_same_behavior(&code, &optimized);
+        for v in SAMPLE_VALUES {
+            let with_slot = |c: &[Instr]| {
+                let mut full = vec![Instr::IMov(slot(8), Val::Imm(v))];
+                full.extend_from_slice(c);
+                full
+            };
+            assert_eq!(simulate(&with_slot(&code), 4), simulate(&with_slot(&optimized), 4));
+        }
     }
 
     #[test]
-    fn recursive_function_compiles_self_call() {
-        let asm = compile_src("((fun (fact n) (if (= n 1) 1 (* n (fact (sub1 n))))) (fact 5))");
-        assert!(asm.contains("fun_fact:"));
-        assert!(asm.contains("call fun_fact"));
+    fn peephole_turns_tag_test_before_cmov_into_test() {
+        let mut code = Vec::new();
+        append_tag_test(&mut code, Reg::Rax, 1, 0);
+        append_condition(&mut code, Cond::E, Dest::Value);
+        let optimized = peephole(code.clone());
+        assert_eq!(optimized[0], Instr::ITest(Val::Reg8(Reg::Rax), Val::Imm(1)));
+        assert_eq!(optimized[1..], code[3..]);
+        assert_same_behavior(&code, &optimized);
     }
 
     #[test]
-    fn mutual_recursion_compiles_both_calls() {
-        let asm = compile_src(
-            "((fun (is_even n) (if (= n 0) true (is_odd (sub1 n)))) (fun (is_odd n) (if (= n 0) false (is_even (sub1 n)))) (is_even 8))",
+    fn peephole_keeps_tag_check_when_scratch_is_read() {
+        let mut code = Vec::new();
+        append_tag_check(&mut code, Reg::Rax, 1, 0, Cond::Ne, "odd");
+        code.push(Instr::IMov(reg(Reg::Rax), reg(Reg::R11)));
+        code.push(label("odd"));
+        assert_eq!(peephole(code.clone()), code);
+    }
+
+    #[test]
+    fn peephole_keeps_tag_check_with_other_conditions() {
+        let mut code = Vec::new();
+        append_tag_check(&mut code, Reg::Rax, 5, 1, Cond::E, "bad");
+        append_tag_check(&mut code, Reg::Rax, 1, 0, Cond::L, "bad");
+        assert_eq!(peephole(code.clone()), code);
+    }
+
+    #[test]
+    fn peephole_drops_jump_to_following_label() {
+        let code = vec![
+            jump(Cond::E, "a"),
+            label("a"),
+            Instr::IJmp("c".to_string()),
+            label("b"),
+            label("c"),
+            Instr::IMov(reg(Reg::Rax), Val::Imm(2)),
+            Instr::IJmp("d".to_string()),
+            Instr::IMov(reg(Reg::Rax), Val::Imm(4)),
+            label("d"),
+        ];
+        let optimized = peephole(code.clone());
+        assert_eq!(optimized.len(), code.len() - 2);
+        assert!(optimized.contains(&Instr::IJmp("d".to_string())));
+        assert_same_behavior(&code, &optimized);
+    }
+
+    #[test]
+    fn peephole_rules_are_catalogued() {
+        let names: Vec<&str> = PEEPHOLE_RULES.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["store-load", "tag-test", "jump-to-next"]);
+    }
+
+    #[test]
+    fn peephole_runs_only_when_optimizing() {
+        let src = "(let ((x input) (y input)) (+ x y))";
+        let plain = compile_src(src);
+        assert!(plain.contains("mov r11, rax\nand r11, 1\ncmp r11, 0\njne"));
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let optimized = compile_with(src, opts);
+        assert!(optimized.contains("test al, 1\njne slow_"));
+        assert!(optimized.contains("test qword [rbp - 8], 1"));
+        assert!(!optimized.contains("mov [rbp - 16], rax\nmov rax, [rbp - 16]"));
+    }
+
+    fn fold_main(src: &str) -> Expr {
+        fold_program(&parse_prog(src)).main
+    }
+
+    #[test]
+    fn folding_evaluates_literal_arithmetic() {
+        assert!(matches!(fold_main("(+ (* 2 3) (add1 4))"), Expr::Num(11)));
+        assert!(matches!(fold_main("(/ -7 2)"), Expr::Num(-3)));
+        assert!(matches!(fold_main("(<= 3 3)"), Expr::Bool(true)));
+        assert!(matches!(fold_main("(= true false)"), Expr::Bool(false)));
+        assert!(matches!(fold_main("(isfloat 2.5)"), Expr::Bool(true)));
+        assert!(matches!(fold_main("(isnum 2.5)"), Expr::Bool(false)));
+    }
+
+    #[test]
+    fn folding_leaves_runtime_errors_in_place() {
+        // Does not fit a literal; at runtime it fits in 63 bits.
+        assert!(matches!(fold_main("(* 100000 100000)"), Expr::BinOp(BinOp::Times, ..)));
+        assert!(matches!(fold_main("(/ 1 (- 2 2))"), Expr::BinOp(BinOp::Divide, ..)));
+        assert!(matches!(fold_main("(negate -2147483648)"), Expr::UnOp(UnOp::Negate, ..)));
+        match fold_main("(+ (+ 1 2) true)") {
+            Expr::BinOp(BinOp::Plus, left, right, Pos { line: 1, col: 1 }) => {
+                assert!(matches!(*left, Expr::Num(3)));
+                assert!(matches!(*right, Expr::Bool(true)));
+            }
+            other => panic!("expected + to remain, got {:?}", other),
+        }
+        assert!(matches!(fold_main("(= 1 true)"), Expr::BinOp(BinOp::Equal, ..)));
+    }
+
+    #[test]
+    fn folding_keeps_results_too_wide_for_literals() {
+        assert!(matches!(fold_main("(add1 2147483647)"), Expr::UnOp(UnOp::Add1, ..)));
+    }
+
+    #[test]
+    fn immutable_lets_are_propagated() {
+        assert!(matches!(fold_main("(let ((x 5) (y (+ x 1))) (* x y))"), Expr::Num(30)));
+        match fold_main("((fun (f x) x) (let ((k 2)) (f k)))") {
+            Expr::Call(_, args, _) => assert!(matches!(args[0], Expr::Num(2))),
+            other => panic!("expected call, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mutated_lets_are_not_propagated() {
+        let folded = fold_main("(let ((x 5)) (block (set! x 6) x))");
+        assert!(matches!(&folded, Expr::Let(bindings, _) if bindings.len() == 1));
+    }
+
+    #[test]
+    fn propagation_respects_shadowing() {
+        match fold_main("(let ((x 1)) (let ((x input)) x))") {
+            Expr::Let(_, body) => assert!(matches!(*body, Expr::Var(_))),
+            other => panic!("expected inner let, got {:?}", other),
+        }
+        let p = parse_prog("((const k 3) (fun (f k) (+ k 1)) (f k))");
+        let folded = fold_program(&p);
+        assert!(matches!(folded.defns[0].body, Expr::BinOp(..)));
+        assert!(matches!(&folded.main, Expr::Call(_, args, _) if matches!(args[0], Expr::Num(3))));
+    }
+
+    #[test]
+    fn folding_selects_literal_if_branches() {
+        assert!(matches!(fold_main("(if (< 1 2) 10 (+ 1 true))"), Expr::Num(10)));
+        assert!(matches!(fold_main("(if false 10 20)"), Expr::Num(20)));
+        assert!(matches!(fold_main("(if 0 10 20)"), Expr::Num(10)));
+        let block = fold_main("(block 1 2 (print 3) 4)");
+        assert!(matches!(block, Expr::Block(items) if items.len() == 2));
+    }
+
+    #[test]
+    fn folding_runs_only_when_optimizing() {
+        assert!(compile_src("(+ 2 3)").contains("call snek_error"));
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("(+ 2 3)", opts);
+        assert!(asm.contains("our_code_starts_here:\npush rbp\nmov rbp, rsp\nmov rax, 10\n"));
+        assert!(!asm.contains("badarg"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined function: nope")]
+    fn folded_away_code_is_still_checked() {
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        compile_with("(if false (nope 1) 2)", opts);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unbound variable: y")]
+    fn validation_reports_the_first_error_in_source_order() {
+        validate_program(&parse_prog("(let ((x y)) (block (break x) (nope 1)))"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unbound variable: b")]
+    fn validation_shows_initializers_only_earlier_globals() {
+        validate_program(&parse_prog("((define a b) (define b 1) a)"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot set! constant: limit")]
+    fn validation_checks_set_targets_inside_inlined_functions() {
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        compile_with("((const limit 3) (fun (f) (set! limit 4)) (f))", opts);
+    }
+
+    #[test]
+    fn validation_accepts_valid_programs() {
+        let src = "((define total 0) (fun (bump n) (let ((m n)) (set! total (+ total m)))) \
+                   (loop (if (> total 5) (break total) (bump 2))))";
+        validate_program(&parse_prog(src));
+        validate_program(&with_prelude(&parse_prog("(abs -1)")));
+    }
+
+    fn inline_main(src: &str, limit: usize) -> Expr {
+        inline_program(&parse_prog(src), limit).main
+    }
+
+    #[test]
+    fn small_functions_are_inlined_when_optimizing() {
+        let src = "((fun (double x) (+ x x)) (fun (quad x) (double (double x))) (quad input))";
+        assert!(compile_src(src).contains("call fun_quad"));
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with(src, opts);
+        assert!(!asm.contains("call fun_"));
+        // The definitions are still emitted.
+        assert!(asm.contains("fun_quad:"));
+    }
+
+    #[test]
+    fn recursive_functions_are_not_inlined() {
+        let src = "((fun (even n) (if (= n 0) true (odd (sub1 n))))
+                    (fun (odd n) (if (= n 0) false (even (sub1 n))))
+                    (fun (fact n) (if (= n 0) 1 (* n (fact (sub1 n)))))
+                    (block (even 4) (fact 3)))";
+        match inline_main(src, 100) {
+            Expr::Block(items) => assert!(items.iter().all(|it| matches!(it, Expr::Call(..)))),
+            other => panic!("expected block, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn inlining_respects_the_size_limit() {
+        let src = "((fun (f x) (+ x (* x 2))) (f 3))";
+        assert!(matches!(inline_main(src, 4), Expr::Call(..)));
+        assert!(matches!(inline_main(src, 5), Expr::Let(..)));
+        assert_eq!(inline_limit(0), 0);
+        assert!(inline_limit(1) < inline_limit(2));
+    }
+
+    #[test]
+    fn inlined_locals_are_renamed_apart() {
+        let src = "((fun (f x) (let ((y (+ x 1))) y)) (let ((y 5)) (+ (f y) y)))";
+        let Expr::Let(_, body) = inline_main(src, 100) else { panic!() };
+        let Expr::BinOp(_, call, right, _) = *body else { panic!() };
+        assert!(matches!(*right, Expr::Var(ref y) if y == "y"));
+        let Expr::Let(params, inner) = *call else { panic!() };
+        assert!(matches!(&params[..], [(x, Expr::Var(y))] if x == "x%0" && y == "y"));
+        let Expr::Let(locals, result) = *inner else { panic!() };
+        assert_eq!(locals[0].0, "y%0");
+        assert!(matches!(*result, Expr::Var(ref y) if y == "y%0"));
+        // The copy of `f`'s parameter does not shadow `g`'s.
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        compile_with("((fun (f x) (add1 x)) (fun (g x) (f x)) (g 1))", opts);
+    }
+
+    #[test]
+    fn inlining_does_not_capture_shadowed_globals() {
+        let src = "((define k 1) (fun (f x) (+ x k)) (block (f 1) (let ((k 2)) (f k))))";
+        let Expr::Block(items) = inline_main(src, 100) else { panic!() };
+        assert!(matches!(items[0], Expr::Let(..)));
+        assert!(matches!(&items[1], Expr::Let(_, body) if matches!(**body, Expr::Call(..))));
+    }
+
+    fn input() -> Val {
+        rel("INPUT_VAL")
+    }
+
+    #[test]
+    fn allocation_moves_slots_into_callee_saved_registers() {
+        let body = vec![
+            store_slot(8),
+            Instr::IMov(reg(Reg::Rax), input()),
+            Instr::IAdd(reg(Reg::Rax), slot(8)),
+        ];
+        let (allocated, used) = allocate_registers(&body, 8);
+        assert_eq!(
+            allocated,
+            vec![
+                Instr::IMov(reg(Reg::Rbx), reg(Reg::Rax)),
+                Instr::IMov(reg(Reg::Rax), input()),
+                Instr::IAdd(reg(Reg::Rax), reg(Reg::Rbx)),
+            ]
         );
-        assert!(asm.contains("call fun_is_even"));
-        assert!(asm.contains("call fun_is_odd"));
+        assert_eq!(used, vec![Reg::Rbx]);
     }
 
     #[test]
-    fn function_calling_function_compiles() {
-        let asm = compile_src("((fun (double x) (+ x x)) (fun (quad x) (double (double x))) (quad 3))");
-        assert!(asm.contains("call fun_double"));
-        assert!(asm.contains("call fun_quad"));
+    fn allocation_reuses_registers_after_last_use() {
+        let body = vec![store_slot(8), load_slot(8), store_slot(16), load_slot(16)];
+        let (allocated, used) = allocate_registers(&body, 16);
+        assert_eq!(allocated[2], Instr::IMov(reg(Reg::Rbx), reg(Reg::Rax)));
+        assert_eq!(used, vec![Reg::Rbx]);
     }
 
     #[test]
-    fn local_variables_use_rbp_negative_offsets() {
-        let asm = compile_src("((fun (f x) (let ((y (+ x 1))) (+ y x))) (f 10))");
-        assert!(asm.contains("[rbp - 8]"));
+    fn allocation_spills_when_registers_run_out() {
+        let offsets = [8, 16, 24, 32, 40, 48];
+        let mut body: Vec<Instr> = offsets.iter().map(|off| store_slot(*off)).collect();
+        body.extend(offsets.iter().map(|off| Instr::IAdd(reg(Reg::Rax), slot(*off))));
+        let (allocated, used) = allocate_registers(&body, 48);
+        assert_eq!(used, CALLEE_SAVED.to_vec());
+        // The interval that ends last stays in its stack slot.
+        assert_eq!(allocated[5], store_slot(48));
+        assert_eq!(allocated[11], Instr::IAdd(reg(Reg::Rax), slot(48)));
+        assert_eq!(allocated[0], Instr::IMov(reg(Reg::Rbx), reg(Reg::Rax)));
     }
 
     #[test]
-    fn parameters_use_rbp_positive_offsets() {
-        let asm = compile_src("((fun (f x y) (+ x y)) (f 2 3))");
-        assert!(asm.contains("[rbp + 16]"));
-        assert!(asm.contains("[rbp + 24]"));
+    fn allocation_keeps_loop_carried_slots_apart() {
+        let body = vec![
+            store_slot(8),
+            label("top"),
+            load_slot(8),
+            Instr::IMov(slot(16), reg(Reg::Rcx)),
+            Instr::IMov(reg(Reg::Rcx), slot(16)),
+            Instr::IJmp("top".to_string()),
+        ];
+        let (allocated, used) = allocate_registers(&body, 16);
+        assert_eq!(used, vec![Reg::Rbx, Reg::R12]);
+        assert_eq!(allocated[3], Instr::IMov(reg(Reg::R12), reg(Reg::Rcx)));
     }
 
     #[test]
-    fn mixed_param_local_access_compiles() {
-        let asm = compile_src("((fun (mix x y) (let ((z (+ x y))) (+ z x))) (mix 1 2))");
-        assert!(asm.contains("[rbp + 16]"));
-        assert!(asm.contains("[rbp - 8]"));
+    fn allocation_leaves_other_frame_slots_alone() {
+        let body = vec![store_slot(24), load_slot(24), Instr::IMov(reg(Reg::Rax), slot(-16))];
+        assert_eq!(allocate_registers(&body, 16), (body, vec![]));
     }
 
     #[test]
-    fn set_can_update_parameter_slot() {
-        let asm = compile_src("((fun (f x) (block (set! x (add1 x)) x)) (f 1))");
-        assert!(asm.contains("mov [rbp + 16], rax"));
+    fn allocated_registers_are_saved_and_restored() {
+        let src = "((fun (f n) (let ((x (+ n 1)) (y (+ x 1))) (* x y))) (f input))";
+        let opts = CompileOptions {
+            opt_level: 2,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with(src, opts.clone());
+        let fun = &asm[asm.find("fun_f:").unwrap()..asm.find("endfun_f:").unwrap()];
+        assert!(fun.contains("cmp rsp, [rel STACK_LIMIT]\njb stack_overflow\nmov [rbp - 24], rbx"));
+        assert!(fun.contains("mov [rbp - 24], rbx\nmov [rbp - 32], r12\n"));
+        assert!(fun.contains("mov rbx, [rbp - 24]\nmov r12, [rbp - 32]\nmov rsp, rbp\npop rbp"));
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..opts
+        };
+        assert!(!compile_with(src, opts).contains("rbx"));
     }
 
     #[test]
-    fn print_unop_calls_runtime_print() {
-        let asm = compile_src("(print 5)");
-        assert!(asm.contains("call snek_print"));
+    fn isnum_guard_removes_tag_checks() {
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("(let ((x input)) (if (isnum x) (+ x 1) 0))", opts);
+        assert!(!asm.contains("badarg"));
+        assert!(!asm.contains("call snek_arith"));
     }
 
     #[test]
-    fn stack_frame_prologue_and_epilogue_present() {
-        let asm = compile_src("((fun (id x) x) (id 1))");
-        assert!(asm.contains("push rbp"));
-        assert!(asm.contains("mov rbp, rsp"));
-        assert!(asm.contains("mov rsp, rbp"));
-        assert!(asm.contains("pop rbp"));
+    fn known_numbers_skip_the_bool_check() {
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("(let ((x (read-num))) (+ x 1))", opts);
+        assert!(asm.contains("call snek_arith"));
+        assert!(!asm.contains("badarg"));
     }
 
     #[test]
-    fn main_uses_stack_frame_too() {
-        let asm = compile_src("(let ((x 1)) x)");
-        assert!(asm.contains("our_code_starts_here:\npush rbp\nmov rbp, rsp"));
+    fn isbool_guard_leaves_numbers_in_the_else_branch() {
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("(let ((x input)) (if (isbool x) 0 (+ x 1)))", opts);
+        assert!(!asm.contains("badarg"));
     }
 
     #[test]
-    fn parse_plain_expression_program_without_defs() {
-        let p = parse_prog("(+ 1 2)");
-        assert_eq!(p.defns.len(), 0);
+    fn unknown_values_keep_their_tag_checks() {
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        assert!(compile_with("(let ((x input)) (+ x 1))", opts.clone()).contains("badarg"));
+        let looped = "(let ((x 1)) (loop (block (+ x 1) (set! x true))))";
+        assert!(compile_with(looped, opts).contains("badarg"));
     }
 
     #[test]
-    fn arity_table_rejects_duplicate_definitions() {
-        let sexp = parse("((fun (f x) x) (fun (f y) y) (f 1))").unwrap();
-        let p = parse_program(&sexp);
-        let result = std::panic::catch_unwind(|| compile_program(&p));
-        assert!(result.is_err());
+    fn equality_of_known_ints_compares_directly() {
+        let src = "(let ((x input) (y input)) (if (isnum x) (if (isnum y) (= x y) false) false))";
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        assert!(!compile_with(src, opts.clone()).contains("eqc"));
+        assert!(compile_with("(let ((x input) (y input)) (= x y))", opts).contains("eqc"));
     }
 
     #[test]
-    #[should_panic(expected = "Wrong number of arguments")]
-    fn wrong_number_of_arguments_panics() {
-        compile_src("((fun (f x y) (+ x y)) (f 1))");
+    fn type_analysis_runs_only_when_optimizing() {
+        let asm = compile_src("(let ((x input)) (if (isnum x) (+ x 1) 0))");
+        assert!(asm.contains("badarg"));
+        assert!(asm.contains("call snek_arith"));
+    }
+
+    fn lower_main(src: &str) -> AExpr {
+        let (frame, sites) = (Cell::new(0), RefCell::new(Vec::new()));
+        let ctx = Ctx {
+            arities: &HashMap::new(),
+            externs: &HashSet::new(),
+            globals: &HashMap::new(),
+            params: &[],
+            frame: &frame,
+            call_sites: &sites,
+            error_sites: &RefCell::new(Vec::new()),
+            stubs: &RefCell::new(Vec::new()),
+            use_types: false,
+            prune: false,
+            pure: &HashSet::new(),
+            sysv_calls: false,
+        };
+        lower_expr(&parse_prog(src).main, &ctx)
+    }
+
+    fn local(id: usize) -> Imm {
+        Imm::Var(Var::Local(id))
     }
 
     #[test]
-    #[should_panic(expected = "Undefined function")]
-    fn undefined_function_call_panics() {
-        compile_src("(missing_fn 1)");
+    fn lowering_binds_nested_computations() {
+        let a = lower_main("(+ (add1 input) 2)");
+        let AExpr::Let(0, CExpr::Input, body) = a else { panic!("{:?}", a) };
+        let AExpr::Let(1, CExpr::UnOp(UnOp::Add1, operand, _), body) = *body else {
+            panic!("{:?}", body)
+        };
+        assert_eq!(operand, local(0));
+        assert!(matches!(
+            *body,
+            AExpr::Ret(CExpr::BinOp(BinOp::Plus, ref l, Imm::Num(2), _)) if *l == local(1)
+        ));
     }
 
     #[test]
-    #[should_panic(expected = "Cannot shadow parameter with let")]
-    fn shadowing_parameter_with_let_panics() {
-        compile_src("((fun (f x) (let ((x 5)) x)) (f 1))");
+    fn lowering_renames_shadowed_lets() {
+        let a = lower_main("(let ((x 1)) (let ((x 2)) x))");
+        let AExpr::Let(0, _, body) = a else { panic!("{:?}", a) };
+        let AExpr::Let(1, _, body) = *body else { panic!("{:?}", body) };
+        assert_eq!(*body, AExpr::Ret(CExpr::Imm(local(1))));
     }
 
     #[test]
-    #[should_panic(expected = "Duplicate parameter")]
-    fn duplicate_parameter_rejected() {
-        parse_prog("((fun (f x x) x) (f 1 2))");
+    fn lowering_snapshots_operands_a_later_operand_sets() {
+        let mut binops = Vec::new();
+        let a = lower_main("(let ((x 1)) (+ x (block (set! x 5) 1)))");
+        visit_cexprs(&a, &mut |c| {
+            if let CExpr::BinOp(_, l, _, _) = c {
+                binops.push(l.clone());
+            }
+        });
+        assert_eq!(binops.len(), 1);
+        assert_ne!(binops[0], local(0));
+        let untouched = lower_main("(let ((x 1)) (+ x (block x 1)))");
+        assert!(matches!(
+            untouched,
+            AExpr::Let(0, _, ref body) if matches!(**body, AExpr::Seq(..))
+        ));
     }
 
     #[test]
-    fn call_argument_order_is_right_to_left_push() {
-        let asm = compile_src("((fun (f a b c) a) (f 1 2 3))");
-        assert!(asm.contains("call fun_f"));
-        assert!(asm.matches("push rax").count() >= 3);
+    fn frame_covers_only_the_slots_emitted() {
+        let asm = compile_src("(let ((x input)) (+ x x))");
+        assert!(asm.contains("sub rsp, 16"));
+        assert!(!asm.contains("[rbp - 16]"));
+    }
+
+    fn cfg_of(src: &str) -> Cfg {
+        build_cfg(&lower_main(src), 0, 0)
     }
 
     #[test]
-    fn nested_call_uses_temp_slots() {
-        let asm = compile_src("((fun (id x) x) (id (id (id 7))))");
-        assert!(asm.contains("mov [rbp - 8], rax"));
+    fn branches_that_set_a_variable_join_in_a_phi() {
+        let cfg = cfg_of("(let ((x input)) (block (if (isnum x) (set! x 1) (set! x 2)) x))");
+        let done = cfg.blocks.iter().position(|b| b.stem == "if_done").unwrap();
+        let phi = &cfg.blocks[done].phis[0];
+        assert_eq!(phi.incoming.len(), 2);
+        assert_eq!(cfg.blocks[done].term, Terminator::Return(Operand::Value(phi.dest)));
+        let same = cfg_of("(let ((x input)) (block (if (isnum x) 1 2) x))");
+        // Only the value of the `if` differs between the branches.
+        assert_eq!(same.blocks.iter().map(|b| b.phis.len()).sum::<usize>(), 1);
     }
 
     #[test]
-    fn recursion_with_locals_uses_frame_space() {
-        let asm = compile_src(
-            "((fun (sum_to n) (if (= n 0) 0 (let ((rest (sum_to (sub1 n)))) (+ n rest)))) (sum_to 4))",
+    fn loop_carried_variables_get_phis_at_the_head() {
+        let cfg = cfg_of("(let ((i 0)) (loop (if (> i 3) (break i) (set! i (add1 i)))))");
+        let head = cfg.blocks.iter().find(|b| b.stem == "lp_h").unwrap();
+        assert_eq!(head.phis.len(), 1);
+        assert_eq!(head.phis[0].incoming[0], (0, Operand::Num(0)));
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm =
+            compile_with("(let ((i 0)) (loop (if (> i 3) (break i) (set! i (add1 i)))))", opts);
+        assert!(!asm.contains("badarg"));
+        assert!(asm.contains("overflow"));
+    }
+
+    #[test]
+    fn unreachable_blocks_are_removed_when_optimizing() {
+        let src = "(loop (block (break 1) (print 2)))";
+        assert!(compile_src(src).contains("call snek_print"));
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        assert!(!compile_with(src, opts).contains("call snek_print"));
+        let mut cfg = cfg_of("(block (loop (print 1)) (print 2))");
+        remove_unreachable_blocks(&mut cfg);
+        assert!(cfg.blocks.iter().all(|b| b.stem != "lp_t"));
+    }
+
+    #[test]
+    fn dead_pure_computations_are_removed_when_optimizing() {
+        let src = "(let ((x input) (y (isbool x)) (z (< (add1 x) 2))) x)";
+        let mut cfg = cfg_of(src);
+        eliminate_dead_code(&mut cfg);
+        let ops: Vec<&Inst> = cfg.blocks.iter().flat_map(|b| &b.insts).collect();
+        assert_eq!(ops.len(), 2, "{:?}", ops);
+        assert!(matches!(ops[1], Inst::Def(_, Operation::UnOp(UnOp::Add1, _, _))));
+        assert!(compile_src(src).contains("cmove rax, r11"));
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        assert!(!compile_with(src, opts).contains("cmove rax, r11"));
+    }
+
+    fn count_ops(cfg: &Cfg, is: impl Fn(&Operation) -> bool) -> usize {
+        let ops = cfg.blocks.iter().flat_map(|b| &b.insts);
+        ops.filter(|inst| matches!(inst, Inst::Def(_, op) if is(op))).count()
+    }
+
+    fn times(op: &Operation) -> bool {
+        matches!(op, Operation::BinOp(BinOp::Times, ..))
+    }
+
+    #[test]
+    fn repeated_computations_are_shared_when_optimizing() {
+        let src = "(let ((x input) (y (add1 input))) (+ (+ (* x y) (* y x)) (* x y)))";
+        let mut cfg = cfg_of(src);
+        eliminate_common_subexpressions(&mut cfg, &HashSet::new());
+        // Both `input`s are the same value; operands are not reordered.
+        assert_eq!(count_ops(&cfg, |op| matches!(op, Operation::Input)), 1);
+        assert_eq!(count_ops(&cfg, times), 2);
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with(src, opts);
+        assert_eq!(asm.matches("imul").count(), 2);
+        assert_eq!(compile_src(src).matches("imul").count(), 3);
+    }
+
+    #[test]
+    fn sharing_respects_set_and_effects() {
+        let mut cfg = cfg_of("(let ((x input)) (+ (* x x) (block (set! x (add1 x)) (* x x))))");
+        eliminate_common_subexpressions(&mut cfg, &HashSet::new());
+        assert_eq!(count_ops(&cfg, times), 2);
+        let mut cfg = cfg_of("(+ (print 1) (print 1))");
+        eliminate_common_subexpressions(&mut cfg, &HashSet::new());
+        assert_eq!(count_ops(&cfg, |op| matches!(op, Operation::UnOp(UnOp::Print, ..))), 2);
+        let global = "((define g 3) (+ (* g g) (* g g)))";
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        assert_eq!(compile_with(global, opts.clone()).matches("imul").count(), 2);
+        let local = "(let ((g input)) (+ (* g g) (* g g)))";
+        assert_eq!(compile_with(local, opts).matches("imul").count(), 1);
+    }
+
+    #[test]
+    fn sharing_needs_a_dominating_computation() {
+        let src = "(let ((x input)) (+ (if (isnum x) (* x x) (* x x)) (* x x)))";
+        let mut cfg = cfg_of(src);
+        eliminate_common_subexpressions(&mut cfg, &HashSet::new());
+        assert_eq!(count_ops(&cfg, times), 3);
+        let mut cfg = cfg_of("(let ((x input) (y (* x x))) (if (isnum x) (* x x) 1))");
+        eliminate_common_subexpressions(&mut cfg, &HashSet::new());
+        assert_eq!(count_ops(&cfg, times), 1);
+    }
+
+    #[test]
+    fn only_calls_to_pure_functions_are_shared() {
+        let p = parse_prog(
+            "((define g 0)
+              (fun (sq x) (* x x))
+              (fun (fact n) (if (= n 0) 1 (* n (fact (sub1 n)))))
+              (fun (even n) (if (= n 0) true (odd (sub1 n))))
+              (fun (odd n) (if (= n 0) false (even (sub1 n))))
+              (fun (shout x) (print x))
+              (fun (get) g)
+              (fun (local n) (let ((g n)) (block (set! g (add1 g)) g)))
+              (fun (wraps x) (sq (shout x)))
+              (extern (clamp x lo hi))
+              (sq (clamp 1 2 3)))",
         );
-        assert!(asm.contains("sub rsp, "));
-        assert!(asm.contains("call fun_sum_to"));
+        let mut pure: Vec<String> = pure_functions(&p).into_iter().collect();
+        pure.sort();
+        assert_eq!(pure, vec!["even", "fact", "local", "odd", "sq"]);
+        let fact = "((fun (fact n) (if (= n 0) 1 (* n (fact (sub1 n)))))
+                     (let ((x input)) (+ (fact x) (fact x))))";
+        assert_eq!(compile_src(fact).matches("call fun_fact").count(), 3);
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        assert_eq!(compile_with(fact, opts).matches("call fun_fact").count(), 2);
+    }
+
+    /// The blocks of `cfg` holding an operation `is` accepts, by stem.
+    fn stems_with(cfg: &Cfg, is: impl Fn(&Operation) -> bool) -> Vec<&'static str> {
+        let holds = |b: &&Block| b.insts.iter().any(|i| matches!(i, Inst::Def(_, op) if is(op)));
+        cfg.blocks.iter().filter(holds).map(|b| b.stem).collect()
     }
 
     #[test]
-    fn comparison_and_equality_still_emit_runtime_checks() {
-        let asm = compile_src("(block (< 1 2) (= 2 2))");
-        assert!(asm.contains("cmp rdi, rsi"));
-        assert!(asm.contains("call snek_error"));
+    fn loop_invariants_move_before_the_loop() {
+        let src = "(let ((x input) (i 0)) (loop (if (= i (* x x)) (break i) (set! i (add1 i)))))";
+        let mut cfg = cfg_of(src);
+        assert_eq!(stems_with(&cfg, times), vec!["lp_h"]);
+        hoist_loop_invariants(&mut cfg, &HashSet::new());
+        assert_eq!(stems_with(&cfg, times), vec!["entry"]);
+        // `i` changes every iteration.
+        let add1 = |op: &Operation| matches!(op, Operation::UnOp(UnOp::Add1, ..));
+        assert_eq!(stems_with(&cfg, add1), vec!["if_alt"]);
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with(src, opts);
+        assert!(asm.find("imul").unwrap() < asm.find("lp_h").unwrap());
     }
 
     #[test]
-    fn loop_break_with_calls_compiles() {
-        let asm = compile_src("((fun (id x) x) (loop (break (id 5))))");
-        assert!(asm.contains("lp_h_"));
-        assert!(asm.contains("call fun_id"));
+    fn hoisting_keeps_the_order_of_errors() {
+        // After a `print`, or only on some iterations, `+` could fail too early.
+        let src = "(let ((x input) (i 0))
+                     (loop (block (print i) (set! i (add1 i))
+                                  (if (> i 2) (break (+ x 1)) (add1 x)))))";
+        let mut cfg = cfg_of(src);
+        hoist_loop_invariants(&mut cfg, &HashSet::new());
+        let plus = |op: &Operation| matches!(op, Operation::BinOp(BinOp::Plus, ..));
+        assert_eq!(stems_with(&cfg, plus), vec!["if_then"]);
+        let add1 = |op: &Operation| matches!(op, Operation::UnOp(UnOp::Add1, ..));
+        assert_eq!(stems_with(&cfg, add1), vec!["lp_h", "if_alt"]);
+        // A test that cannot fail moves from anywhere in the loop.
+        let src = "(let ((x input) (i 0))
+                     (loop (block (print i) (set! i (add1 i))
+                                  (if (> i 2) (break i) (isnum x)))))";
+        let mut cfg = cfg_of(src);
+        hoist_loop_invariants(&mut cfg, &HashSet::new());
+        let isnum = |op: &Operation| matches!(op, Operation::UnOp(UnOp::IsNum, ..));
+        assert_eq!(stems_with(&cfg, isnum), vec!["entry"]);
     }
 
     #[test]
-    fn function_definitions_must_precede_main() {
-        let sexp = parse("((+ 1 2) (fun (f x) x))").unwrap();
-        let result = std::panic::catch_unwind(|| parse_program(&sexp));
-        assert!(result.is_err());
+    fn conditions_branch_on_the_flags() {
+        let src = "(let ((x input) (i 0)) (loop (if (>= i x) (break i) (set! i (add1 i)))))";
+        let asm = compile_src(src);
+        let (body, stubs) = asm.split_at(asm.find("ret\n").unwrap());
+        assert!(body.contains("cmp rdi, rsi\njl if_alt_"), "{}", asm);
+        assert!(!body.contains("cmov") && !body.contains("cmp rax, 1"), "{}", asm);
+        // The slow path for floats goes out of line and branches on its result.
+        assert!(stubs.contains("call snek_arith\ncmp rax, 1\nje if_alt_"), "{}", asm);
+        let asm = compile_src("(let ((x input)) (if (isbool x) 1 2))");
+        assert!(asm.contains("cmp r11, 1\njne if_alt_"), "{}", asm);
+    }
+
+    #[test]
+    fn condition_values_use_cmov() {
+        let asm = compile_src("(print (< input 3))");
+        assert!(asm.contains("cmp rdi, rsi\nmov rax, 1\nmov r11, 3\ncmovl rax, r11"), "{}", asm);
+        assert!(!asm.contains("jl "), "{}", asm);
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("(print (isnum input))", opts);
+        assert!(asm.contains("test al, 1\nmov rax, 1\nmov r11, 3\ncmove rax, r11"), "{}", asm);
+    }
+
+    #[test]
+    fn nested_ifs_branch_straight_to_their_targets() {
+        let branches = |cfg: &Cfg| {
+            let terms = cfg.blocks.iter().map(|b| &b.term);
+            terms.filter(|t| matches!(t, Terminator::Branch(..))).count()
+        };
+        let src = "(let ((a input) (b (add1 input))) (if (if (< a 10) (< b 20) false) 1 2))";
+        let mut cfg = cfg_of(src);
+        thread_branches(&mut cfg);
+        remove_unreachable_blocks(&mut cfg);
+        assert_eq!(branches(&cfg), 2);
+        assert_eq!(cfg.blocks.iter().map(|b| b.phis.len()).sum::<usize>(), 1);
+        // A negated test branches to the other side.
+        let mut cfg = cfg_of("(let ((a input)) (if (if (< a 1) false true) 5 6))");
+        thread_branches(&mut cfg);
+        remove_unreachable_blocks(&mut cfg);
+        assert_eq!(branches(&cfg), 1);
+        let Terminator::Branch(_, then_block, _) = cfg.blocks[0].term else { panic!() };
+        let done = cfg.blocks.last().unwrap();
+        let arrives = |from| done.phis[0].incoming.iter().find(|(b, _)| *b == from).unwrap();
+        let Terminator::Jump(to_done) = cfg.blocks[then_block].term else { panic!() };
+        assert_eq!(to_done, cfg.blocks.len() - 1);
+        assert_eq!(arrives(then_block).1, Operand::Num(6));
+        let opts = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with("(let ((a input)) (if (if (< a 1) false true) 5 6))", opts);
+        let body = &asm[..asm.find("ret\n").unwrap()];
+        assert!(!body.contains("cmov") && !body.contains("cmp rax, 1"), "{}", asm);
+    }
+
+    #[test]
+    fn phi_copies_that_overlap_go_through_the_stack() {
+        let phi = |dest, src| Phi { dest, incoming: vec![(0, Operand::Value(src))] };
+        let block = Block {
+            stem: "lp_h",
+            guards: Vec::new(),
+            phis: vec![phi(0, 1), phi(1, 0)],
+            insts: Vec::new(),
+            term: Terminator::Pending,
+        };
+        let slots: Slots = [(0, 8), (1, 16)].into_iter().collect();
+        let mut code = Vec::new();
+        append_phi_copies(&mut code, 0, &block, &slots);
+        let before: HashMap<i32, i64> = [(8, 10), (16, 20)].into_iter().collect();
+        let mut mem = before.clone();
+        let mut stack = Vec::new();
+        let mut rax = 0;
+        for instr in &code {
+            match instr {
+                Instr::IMov(dst, src) if *dst == reg(Reg::Rax) => {
+                    rax = mem[&frame_slot(src).unwrap()];
+                }
+                Instr::IMov(dst, _) => {
+                    mem.insert(frame_slot(dst).unwrap(), rax);
+                }
+                Instr::IPush(_) => stack.push(rax),
+                Instr::IPop(_) => rax = stack.pop().unwrap(),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!((mem[&8], mem[&16]), (before[&16], before[&8]));
+    }
+
+    const RUNTIME: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/runtime/start.rs");
+
+    /// A program linked by `build_program`; its directory is removed on drop.
+    struct BuiltProgram {
+        dir: std::path::PathBuf,
+    }
+
+    impl Drop for BuiltProgram {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Assembles and links `src` the way the Makefile does, in a fresh
+    /// directory named after `name`. Panics if nasm, ar or rustc fails.
+    fn build_program(name: &str, src: &str) -> BuiltProgram {
+        let dir = std::env::temp_dir().join(format!("snek-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let built = BuiltProgram { dir };
+        let sexp = parse(src).unwrap();
+        let opts = CompileOptions {
+            source_name: "prog.snek".to_string(),
+            target: Target::host(),
+            ..CompileOptions::default()
+        };
+        let asm = compile_program(&parse_program(&sexp, &source_spans(src, &sexp)), &opts);
+        std::fs::write(built.dir.join("prog.s"), asm).unwrap();
+        let (format, rust_target): (&str, &[&str]) = match Target::host() {
+            Target::Linux => ("elf64", &[]),
+            Target::MacOs => ("macho64", &["--target", "x86_64-apple-darwin"]),
+        };
+        let steps: [(&str, Vec<&str>); 3] = [
+            ("nasm", vec!["-f", format, "prog.s", "-o", "our_code.o"]),
+            ("ar", vec!["rcs", "libour_code.a", "our_code.o"]),
+            ("rustc", [rust_target, &["-L", ".", RUNTIME, "-o", "prog.run"]].concat()),
+        ];
+        for (tool, args) in steps {
+            let out = std::process::Command::new(tool)
+                .args(args)
+                .current_dir(&built.dir)
+                .output()
+                .unwrap_or_else(|e| panic!("cannot run {tool}: {e}"));
+            assert!(out.status.success(), "{tool}: {}", String::from_utf8_lossy(&out.stderr));
+        }
+        built
+    }
+
+    /// Runs a program from `build_program`, returning its stdout and exit code.
+    fn run_program(prog: &BuiltProgram, args: &[&str], env: &[(&str, &str)]) -> (String, i32) {
+        let out = std::process::Command::new(prog.dir.join("prog.run"))
+            .args(args)
+            .env_remove("SNEK_OUTPUT")
+            .envs(env.iter().copied())
+            .output()
+            .unwrap();
+        (String::from_utf8(out.stdout).unwrap(), out.status.code().unwrap())
+    }
+
+    /// Runs `prog` in JSON mode both ways, with a leading `--json` and with
+    /// `SNEK_OUTPUT=json`, checking that they agree and returning the result.
+    fn run_json(prog: &BuiltProgram, args: &[&str]) -> (String, i32) {
+        let flagged = run_program(prog, &[&["--json"], args].concat(), &[]);
+        let from_env = run_program(prog, args, &[("SNEK_OUTPUT", "json")]);
+        assert_eq!(flagged, from_env);
+        flagged
+    }
+
+    #[test]
+    #[ignore = "needs nasm"]
+    fn json_mode_reports_value_and_output() {
+        let prog = build_program("json_value", "(block (print 1) (print true) (+ input 2))");
+        let record = concat!(
+            r#"{"value": {"type": "int", "value": 7}, "#,
+            r#""output": [{"type": "int", "value": 1}, {"type": "bool", "value": true}], "#,
+            r#""error": null}"#,
+            "\n"
+        );
+        assert_eq!(run_json(&prog, &["5"]), (record.to_string(), 0));
+        assert_eq!(run_program(&prog, &["5"], &[]), ("1\ntrue\n7\n".to_string(), 0));
+    }
+
+    #[test]
+    #[ignore = "needs nasm"]
+    fn json_mode_exits_with_error_code_on_runtime_errors() {
+        let prog = build_program("json_error", "(block (print 1) (/ 10 input))");
+        let (record, status) = run_json(&prog, &["0"]);
+        assert_eq!(status, 10 + 3);
+        assert!(record.starts_with(concat!(
+            r#"{"value": null, "output": [{"type": "int", "value": 1}], "#,
+            r#""error": {"code": 3, "kind": "division by zero", "message": "#
+        )));
+        assert!(record.ends_with("}}\n"));
+        assert_eq!(run_program(&prog, &["0"], &[]), ("1\n".to_string(), 1));
+
+        let prog = build_program("json_invalid", "(+ input true)");
+        let (record, status) = run_json(&prog, &["4"]);
+        assert_eq!(status, 10 + 1);
+        assert!(record.contains(r#""error": {"code": 1, "kind": "invalid argument", "#));
+    }
+
+    #[test]
+    #[ignore = "needs nasm"]
+    fn json_mode_exits_with_2_on_malformed_input() {
+        let prog = build_program("json_bad_input", "(+ input 1)");
+        let (record, status) = run_json(&prog, &["abc"]);
+        assert_eq!(status, 2);
+        assert!(record.starts_with(concat!(
+            r#"{"value": null, "output": [], "#,
+            r#""error": {"code": null, "kind": "bad argument", "message": "#
+        )));
+        assert!(record.ends_with(concat!(r#""backtrace": []}}"#, "\n")));
+        assert_eq!(run_program(&prog, &["abc"], &[]), (String::new(), 1));
     }
 }